@@ -0,0 +1,222 @@
+//! One line per served request, in Common Log Format, Combined Log Format
+//! or JSON lines. Requests answered with an error before they could be
+//! parsed, and connections turned away, are logged too.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use serde_json::json;
+
+use crate::{
+    http::request::HttpRequest,
+    utils::{signal, time::{clf_date, rfc3339}},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `host ident authuser [date] "request" status bytes`
+    Common,
+    /// Common plus `"referer" "user-agent"`.
+    Combined,
+    /// One JSON object per line, including the latency.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "common" | "clf" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown access log format '{}'", other)),
+        }
+    }
+}
+
+/// What is known about a request once its response has been written.
+pub struct LogEntry<'a> {
+    pub peer: Option<SocketAddr>,
+    /// `None` when the request could not be parsed, or the connection was
+    /// turned away before it was read.
+    pub request: Option<&'a HttpRequest>,
+    /// The request line of a request that could not be parsed, if it was
+    /// read; logged as `-` otherwise.
+    pub request_line: Option<&'a str>,
+    pub status: u16,
+    /// Body bytes sent.
+    pub bytes: u64,
+    pub latency: Duration,
+}
+
+enum Target {
+    Stdout,
+    File { path: PathBuf, file: File },
+}
+
+pub struct AccessLog {
+    format: LogFormat,
+    target: Mutex<Target>,
+}
+
+impl AccessLog {
+    /// Opens `path` for appending; `-` logs to stdout.
+    pub fn open(path: &str, format: LogFormat) -> io::Result<Self> {
+        let target = if path == "-" {
+            Target::Stdout
+        } else {
+            let path = PathBuf::from(path);
+            Target::File { file: open_append(&path)?, path }
+        };
+        Ok(Self { format, target: Mutex::new(target) })
+    }
+
+    pub fn record(&self, entry: &LogEntry<'_>) {
+        let mut line = self.format_line(entry, SystemTime::now());
+        line.push('\n');
+
+        let mut target = self.target.lock().expect("access log mutex");
+        if let Target::File { path, file } = &mut *target {
+            // logrotate moved the file away: start a new one at the same path
+            if signal::take_reopen_request() {
+                match open_append(path) {
+                    Ok(f) => *file = f,
+                    Err(e) => eprintln!("Cannot reopen access log {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        let _ = match &mut *target {
+            Target::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Target::File { file, .. } => file.write_all(line.as_bytes()),
+        };
+    }
+
+    fn format_line(&self, entry: &LogEntry<'_>, now: SystemTime) -> String {
+        let req = entry.request;
+        let host = entry.peer.map(|a| a.ip().to_string()).unwrap_or_else(|| "-".into());
+        let target = req.map(|req| if req.query.is_empty() { req.path.clone() } else { format!("{}?{}", req.path, req.query) });
+        let header = |name: &str| req.and_then(|req| req.header(name));
+
+        match self.format {
+            LogFormat::Json => json!({
+                "time": rfc3339(now),
+                "remote_addr": host,
+                "method": req.map(|req| req.method.as_str()),
+                "path": target,
+                "protocol": req.map(|req| req.version.as_str()),
+                "request": if req.is_none() { Some(entry.request_line.unwrap_or("-")) } else { None },
+                "status": entry.status,
+                "bytes": entry.bytes,
+                "duration_ms": entry.latency.as_secs_f64() * 1000.0,
+                "referer": header("Referer"),
+                "user_agent": header("User-Agent"),
+            })
+            .to_string(),
+
+            LogFormat::Common | LogFormat::Combined => {
+                let request = match (req, target) {
+                    (Some(req), Some(target)) => format!("{} {} {}", req.method.as_str(), escape(&target), req.version),
+                    _ => entry.request_line.map(escape).unwrap_or_else(|| "-".into()),
+                };
+                let mut line = format!(
+                    "{} - - {} \"{}\" {} {}",
+                    host,
+                    clf_date(now),
+                    request,
+                    entry.status,
+                    if entry.bytes == 0 { "-".to_string() } else { entry.bytes.to_string() }
+                );
+                if self.format == LogFormat::Combined {
+                    let quoted = |v: Option<&str>| v.map(escape).unwrap_or_else(|| "-".into());
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        quoted(header("Referer")),
+                        quoted(header("User-Agent"))
+                    ));
+                }
+                line
+            }
+        }
+    }
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Keeps quotes and control characters from breaking the line format.
+fn escape(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| match c {
+            '"' => vec!['\\', '"'],
+            '\\' => vec!['\\', '\\'],
+            c if c.is_control() => format!("\\x{:02x}", c as u32).chars().collect(),
+            c => vec![c],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::UNIX_EPOCH;
+
+    fn entry_line(format: LogFormat) -> String {
+        let raw = "GET /toupper?text=a HTTP/1.1\r\nHost: x\r\nUser-Agent: curl/8 \"q\"\r\n\r\n";
+        let mut req = HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap();
+        req.peer_addr = Some("192.0.2.7:5000".parse().unwrap());
+
+        let log = AccessLog { format, target: Mutex::new(Target::Stdout) };
+        let entry = LogEntry { peer: req.peer_addr, request: Some(&req), request_line: None, status: 200, bytes: 42, latency: Duration::from_millis(3) };
+        log.format_line(&entry, UNIX_EPOCH + Duration::from_secs(971_186_136))
+    }
+
+    #[test]
+    fn formats_common_and_combined() {
+        assert_eq!(
+            entry_line(LogFormat::Common),
+            "192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /toupper?text=a HTTP/1.1\" 200 42"
+        );
+        assert!(entry_line(LogFormat::Combined).ends_with("200 42 \"-\" \"curl/8 \\\"q\\\"\""));
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let v: serde_json::Value = serde_json::from_str(&entry_line(LogFormat::Json)).unwrap();
+        assert_eq!(v["remote_addr"], "192.0.2.7");
+        assert_eq!(v["status"], 200);
+        assert_eq!(v["path"], "/toupper?text=a");
+        assert_eq!(v["time"], "2000-10-10T13:55:36Z");
+    }
+
+    #[test]
+    fn formats_requests_that_were_never_parsed() {
+        let log = AccessLog { format: LogFormat::Combined, target: Mutex::new(Target::Stdout) };
+        let now = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        let rejected = LogEntry { peer: None, request: None, request_line: None, status: 503, bytes: 9, latency: Duration::ZERO };
+        assert_eq!(log.format_line(&rejected, now), "- - - [10/Oct/2000:13:55:36 +0000] \"-\" 503 9 \"-\" \"-\"");
+
+        let peer = Some("192.0.2.7:5000".parse().unwrap());
+        let unparsed = LogEntry { peer, request: None, request_line: Some("GET /a\"b HTTP/9"), status: 400, bytes: 0, latency: Duration::ZERO };
+        assert!(log.format_line(&unparsed, now).starts_with("192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /a\\\"b HTTP/9\" 400 -"));
+
+        let log = AccessLog { format: LogFormat::Json, target: Mutex::new(Target::Stdout) };
+        let v: serde_json::Value = serde_json::from_str(&log.format_line(&unparsed, now)).unwrap();
+        assert_eq!(v["request"], "GET /a\"b HTTP/9");
+        assert!(v["method"].is_null());
+    }
+}
@@ -0,0 +1,243 @@
+use std::io::{self, BufRead, Read, Write, ErrorKind};
+
+use crate::http::{
+    errors::ServerError,
+    request::{read_line_limited, RequestLimits},
+};
+
+/// Decodes a `Transfer-Encoding: chunked` body from `inner`, yielding the
+/// payload bytes. Trailer fields after the last chunk are read and discarded.
+///
+/// Chunk-size lines are bounded like a request line and the trailers like a
+/// header block. Going over either fails with an `InvalidData` error that
+/// wraps the `ServerError` to answer with.
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    remaining: u64,
+    done: bool,
+    max_line: usize,
+    max_trailer_bytes: usize,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_limits(inner, &RequestLimits::default())
+    }
+
+    pub fn with_limits(inner: R, limits: &RequestLimits) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+            max_line: limits.max_request_line,
+            max_trailer_bytes: limits.max_header_bytes,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_size_line(&mut self) -> io::Result<u64> {
+        let line = read_line_limited(&mut self.inner, self.max_line)?
+            .ok_or_else(|| too_large(ServerError::BadRequest("Chunk size line too long".into())))?;
+        if line.is_empty() {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Truncated chunked body"));
+        }
+
+        let line = line.strip_suffix("\r\n")
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Chunk size line not terminated by CRLF"))?;
+        parse_chunk_size(line)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("Invalid chunk size: '{}'", line)))
+    }
+
+    fn expect_crlf(&mut self) -> io::Result<()> {
+        match read_line_limited(&mut self.inner, 2)? {
+            Some(line) if line == "\r\n" => Ok(()),
+            _ => Err(io::Error::new(ErrorKind::InvalidData, "Missing CRLF after chunk data")),
+        }
+    }
+
+    fn skip_trailers(&mut self) -> io::Result<()> {
+        let mut total = 0;
+        loop {
+            let line = read_line_limited(&mut self.inner, self.max_trailer_bytes - total)?
+                .ok_or_else(|| too_large(ServerError::HeaderFieldsTooLarge))?;
+            total += line.len();
+            if line.is_empty() || line == "\r\n" {
+                return Ok(());
+            }
+            if !line.ends_with("\r\n") {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Trailer line not terminated by CRLF"));
+            }
+        }
+    }
+}
+
+/// The size from a chunk-size line without its CRLF, ignoring chunk
+/// extensions (";name=value"). Only hex digits are accepted: no sign, no
+/// `0x`, nothing empty, so every peer on the path reads the same size.
+pub(crate) fn parse_chunk_size(line: &str) -> Option<u64> {
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
+}
+
+fn too_large(err: ServerError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Unwraps the `ServerError` for a limit the decoder enforced, or gives the
+/// error back if it is anything else.
+pub(crate) fn limit_error(e: io::Error) -> Result<ServerError, io::Error> {
+    if !e.get_ref().is_some_and(|inner| inner.is::<ServerError>()) {
+        return Err(e);
+    }
+    let inner = e.into_inner().expect("checked above");
+    Ok(*inner.downcast::<ServerError>().expect("checked above"))
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            let size = self.read_size_line()?;
+            if size == 0 {
+                self.skip_trailers()?;
+                self.done = true;
+                return Ok(0);
+            }
+            self.remaining = size;
+        }
+
+        let max = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Truncated chunk data"));
+        }
+
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            self.expect_crlf()?;
+        }
+        Ok(n)
+    }
+}
+
+/// Frames everything written to it as HTTP/1.1 chunks. Every `write` call
+/// becomes one chunk and is flushed straight away, so streamed bodies reach
+/// the client incrementally. Call `finish` to emit the terminating chunk.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn decodes_chunks_and_trailers() {
+        let raw = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: yes\r\n\r\nNEXT";
+        let mut reader = ChunkedReader::new(Cursor::new(&raw[..]));
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"Wikipedia");
+
+        let mut rest = String::new();
+        reader.into_inner().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn rejects_bad_chunk_size() {
+        let mut reader = ChunkedReader::new(Cursor::new(&b"zz\r\nabc\r\n0\r\n\r\n"[..]));
+        let mut body = Vec::new();
+        assert!(reader.read_to_end(&mut body).is_err());
+    }
+
+    #[test]
+    fn accepts_only_hex_digits_and_crlf() {
+        assert_eq!(parse_chunk_size("1f;name=value"), Some(31));
+        for bad in ["+5", "-0", "0x5", "", " ", "5 5"] {
+            assert_eq!(parse_chunk_size(bad), None, "{:?}", bad);
+        }
+
+        let read = |raw: &[u8]| ChunkedReader::new(Cursor::new(raw.to_vec())).read_to_end(&mut Vec::new());
+        assert!(read(b"+5\r\nhello\r\n0\r\n\r\n").is_err());
+        assert!(read(b"5\nhello\r\n0\r\n\r\n").is_err());
+        assert!(read(b"5\r\nhello\n0\r\n\r\n").is_err());
+        assert!(read(b"5\r\nhello\r\n0\r\nX-T: 1\n\r\n").is_err());
+        assert!(read(b"5\r\nhello\r\n0\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_truncated_body() {
+        let mut reader = ChunkedReader::new(Cursor::new(&b"a\r\nabc"[..]));
+        let mut body = Vec::new();
+        assert!(reader.read_to_end(&mut body).is_err());
+    }
+
+    #[test]
+    fn bounds_size_lines_and_trailers() {
+        let limits = RequestLimits { max_request_line: 16, max_header_bytes: 32, ..RequestLimits::default() };
+        let read = |raw: Vec<u8>| ChunkedReader::with_limits(Cursor::new(raw), &limits).read_to_end(&mut Vec::new());
+        let wrapped = |e: io::Error| limit_error(e).ok();
+
+        let endless_size = format!("1;{}", "x".repeat(100)).into_bytes();
+        assert!(matches!(wrapped(read(endless_size).unwrap_err()), Some(ServerError::BadRequest(_))));
+
+        let endless_trailers = format!("0\r\n{}", "X-T: 1\r\n".repeat(10)).into_bytes();
+        assert!(matches!(wrapped(read(endless_trailers).unwrap_err()), Some(ServerError::HeaderFieldsTooLarge)));
+
+        assert!(read(b"1\r\na\r\n0\r\nX-T: 1\r\n\r\n".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn writer_round_trips() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        let encoded = writer.finish().unwrap();
+        assert_eq!(encoded, b"6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n");
+
+        let mut decoded = Vec::new();
+        ChunkedReader::new(Cursor::new(encoded)).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, b"hello world");
+    }
+}
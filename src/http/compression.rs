@@ -0,0 +1,273 @@
+//! Response compression negotiated through `Accept-Encoding`.
+//!
+//! Bodies of at least `min_size` bytes are sent gzip- or deflate-encoded
+//! when the client accepts either, picking the higher q-value (gzip on a
+//! tie). Streamed bodies are compressed as they are produced and go out
+//! chunked. Responses that already have a `Content-Encoding`, partial
+//! content, event streams and media types that are compressed by nature are
+//! left alone.
+
+use std::{env, io::Write};
+
+use flate2::{
+    read::{GzEncoder, ZlibEncoder},
+    write, Compression as Level,
+};
+
+use crate::http::{
+    errors::ServerError,
+    middleware::{Middleware, Next},
+    request::HttpRequest,
+    response::Response,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`.
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the coding to use for an `Accept-Encoding` value, or `None` to
+/// send the body as is. Codings the client did not list are only used
+/// through `*`; `q=0` rules a coding out.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut entries = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = parts
+            .filter_map(|p| p.trim().split_once('='))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case("q"))
+            .map(|(_, v)| v.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        entries.push((coding, q));
+    }
+
+    let q_of = |names: &[&str]| {
+        entries
+            .iter()
+            .find(|(c, _)| names.contains(&c.as_str()))
+            .or_else(|| entries.iter().find(|(c, _)| c == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    let gzip = q_of(&["gzip", "x-gzip"]);
+    let deflate = q_of(&["deflate"]);
+    match (gzip, deflate) {
+        (g, d) if g > 0.0 && g >= d => Some(Encoding::Gzip),
+        (_, d) if d > 0.0 => Some(Encoding::Deflate),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// zlib level from 1 (fastest) to 9 (smallest); 0 disables compression.
+    pub level: u32,
+    /// Smaller bodies are not worth the CPU and the extra headers.
+    pub min_size: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self { level: 6, min_size: 1024 }
+    }
+}
+
+pub struct Compression {
+    cfg: CompressionConfig,
+}
+
+impl Compression {
+    pub fn new(cfg: CompressionConfig) -> Self {
+        Self { cfg }
+    }
+
+    /// Reads `COMPRESSION_LEVEL` and `COMPRESSION_MIN_BYTES`.
+    pub fn from_env() -> Self {
+        let defaults = CompressionConfig::default();
+        let level = env::var("COMPRESSION_LEVEL").ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .map(|l| l.min(9))
+            .unwrap_or(defaults.level);
+        let min_size = env::var("COMPRESSION_MIN_BYTES").ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(defaults.min_size);
+        Self::new(CompressionConfig { level, min_size })
+    }
+
+    fn large_enough(&self, resp: &Response) -> bool {
+        match &resp.stream {
+            None => resp.body.len() as u64 >= self.cfg.min_size.max(1),
+            Some(stream) => stream.len.map(|len| len >= self.cfg.min_size).unwrap_or(true),
+        }
+    }
+
+    fn encode(&self, mut resp: Response, encoding: Encoding) -> Response {
+        let level = Level::new(self.cfg.level);
+        resp = match resp.stream.take() {
+            None => {
+                let body = std::mem::take(&mut resp.body);
+                match compress(&body, encoding, level) {
+                    Some(compressed) if compressed.len() < body.len() => resp.with_body(compressed),
+                    _ => return resp.with_body(body),
+                }
+            }
+            Some(stream) => match encoding {
+                Encoding::Gzip => resp.with_stream(GzEncoder::new(stream.reader, level)),
+                Encoding::Deflate => resp.with_stream(ZlibEncoder::new(stream.reader, level)),
+            },
+        };
+
+        // Byte ranges and strong validators describe the unencoded body
+        resp.headers.remove("Accept-Ranges");
+        if let Some(weak) = resp.headers.get("ETag").filter(|e| !e.starts_with("W/")).map(|e| format!("W/{}", e)) {
+            resp = resp.set_header("ETag", &weak);
+        }
+        resp.set_header("Content-Encoding", encoding.as_str())
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError> {
+        let resp = next.run(req)?;
+        if self.cfg.level == 0 || !compressible(&resp) {
+            return Ok(resp);
+        }
+
+        // Whether or not this client gets it compressed, caches must key on it
+        let resp = resp.vary("Accept-Encoding");
+        let accept = req.headers.get_all("Accept-Encoding").collect::<Vec<_>>().join(",");
+        match negotiate(&accept) {
+            Some(encoding) if self.large_enough(&resp) => Ok(self.encode(resp, encoding)),
+            _ => Ok(resp),
+        }
+    }
+}
+
+fn compressible(resp: &Response) -> bool {
+    let code = resp.status.code;
+    if code < 200 || code == 204 || code == 206 || code == 304 || resp.headers.contains("Content-Encoding") {
+        return false;
+    }
+
+    let content_type = resp.headers.get("Content-Type").unwrap_or("").to_ascii_lowercase();
+    let media = content_type.split(';').next().unwrap_or("").trim();
+    let precompressed = matches!(
+        media,
+        "application/gzip" | "application/x-gzip" | "application/x-xz" | "application/zip"
+            | "application/zstd" | "application/x-bzip2" | "application/x-7z-compressed" | "application/pdf"
+            | "font/woff" | "font/woff2"
+    );
+    let media_file = (media.starts_with("image/") && media != "image/svg+xml")
+        || media.starts_with("audio/")
+        || media.starts_with("video/");
+    // Compressing an event stream would hold events back in the encoder
+    !precompressed && !media_file && media != "text/event-stream"
+}
+
+fn compress(body: &[u8], encoding: Encoding, level: Level) -> Option<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut enc = write::GzEncoder::new(Vec::new(), level);
+            enc.write_all(body).ok()?;
+            enc.finish().ok()
+        }
+        Encoding::Deflate => {
+            let mut enc = write::ZlibEncoder::new(Vec::new(), level);
+            enc.write_all(body).ok()?;
+            enc.finish().ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use std::sync::Arc;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use crate::http::handler::Dispatcher;
+    use crate::http::router::router::SimpleHandler;
+    use crate::http::response::OK;
+
+    fn request(raw: &str) -> HttpRequest {
+        HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap()
+    }
+
+    fn dispatcher() -> Dispatcher {
+        let json = "{\"value\": 42}".repeat(200);
+        Dispatcher::builder()
+            .get("/big", Arc::new(SimpleHandler(move |_: &HttpRequest| {
+                Ok(Response::new(OK).set_header("Content-Type", "application/json").with_body(json.clone()))
+            })))
+            .get("/small", Arc::new(SimpleHandler(|_: &HttpRequest| Ok(Response::new(OK).with_body("tiny")))))
+            .get("/png", Arc::new(SimpleHandler(|_: &HttpRequest| {
+                Ok(Response::new(OK).set_header("Content-Type", "image/png").with_body(vec![0u8; 4096]))
+            })))
+            .get("/stream", Arc::new(SimpleHandler(|_: &HttpRequest| {
+                Ok(Response::new(OK).with_stream(Cursor::new(b"line\n".repeat(1000))))
+            })))
+            .wrap(Arc::new(Compression::new(CompressionConfig::default())))
+            .build()
+    }
+
+    #[test]
+    fn negotiates_with_q_values() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("deflate;q=0.2, *;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.3, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br, identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn compresses_large_bodies_and_streams() {
+        let d = dispatcher();
+
+        let resp = d.dispatch(&mut request("GET /big HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n")).unwrap();
+        assert_eq!(resp.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(resp.headers.get("Vary"), Some("Accept-Encoding"));
+        let mut body = String::new();
+        GzDecoder::new(&resp.body[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "{\"value\": 42}".repeat(200));
+
+        let resp = d.dispatch(&mut request("GET /stream HTTP/1.1\r\nHost: x\r\nAccept-Encoding: deflate\r\n\r\n")).unwrap();
+        assert_eq!(resp.headers.get("Content-Encoding"), Some("deflate"));
+        let mut body = Vec::new();
+        ZlibDecoder::new(resp.stream.unwrap().reader).read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"line\n".repeat(1000));
+    }
+
+    #[test]
+    fn leaves_small_precompressed_and_unrequested_bodies_alone() {
+        let d = dispatcher();
+
+        let small = d.dispatch(&mut request("GET /small HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n")).unwrap();
+        assert_eq!(small.headers.get("Content-Encoding"), None);
+        assert_eq!(small.body, b"tiny");
+
+        let png = d.dispatch(&mut request("GET /png HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n")).unwrap();
+        assert_eq!(png.headers.get("Content-Encoding"), None);
+        assert_eq!(png.headers.get("Vary"), None);
+
+        let plain = d.dispatch(&mut request("GET /big HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+        assert_eq!(plain.headers.get("Content-Encoding"), None);
+        assert_eq!(plain.headers.get("Vary"), Some("Accept-Encoding"));
+    }
+}
@@ -0,0 +1,363 @@
+//! The threaded I/O model's connection handling: a fixed pool of threads
+//! fed by a bounded queue of accepted connections.
+//!
+//! A connection accepted while every thread is busy waits in the queue, in
+//! arrival order, for up to `max_wait`. One that finds the queue full, or
+//! waits longer than that, gets a 503 and is closed. The figures behind
+//! `/metrics` cover every pool in the process and are read with `stats`.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    os::fd::RawFd,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::http::{access_log::AccessLog, response::SERVICE_UNAVAILABLE, server::HttpServer};
+
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static SHED: AtomicU64 = AtomicU64::new(0);
+static WAITS: AtomicU64 = AtomicU64::new(0);
+static WAIT_TOTAL_US: AtomicU64 = AtomicU64::new(0);
+static WAIT_MAX_US: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub threads: usize,
+    /// Threads serving a connection right now.
+    pub busy: usize,
+    pub queued: usize,
+    pub queue_capacity: usize,
+    /// Connections handed to a pool.
+    pub accepted: u64,
+    /// Turned away because the queue was full.
+    pub rejected: u64,
+    /// Closed after waiting in the queue for longer than allowed.
+    pub shed: u64,
+    /// Time served connections spent in the queue.
+    pub avg_wait: Duration,
+    pub max_wait: Duration,
+}
+
+/// Current figures for every connection pool in the process.
+pub fn stats() -> ConnectionStats {
+    let waits = WAITS.load(Ordering::Relaxed);
+    let total = WAIT_TOTAL_US.load(Ordering::Relaxed);
+    ConnectionStats {
+        threads: THREADS.load(Ordering::Relaxed),
+        busy: BUSY.load(Ordering::Relaxed),
+        queued: QUEUED.load(Ordering::Relaxed),
+        queue_capacity: CAPACITY.load(Ordering::Relaxed),
+        accepted: ACCEPTED.load(Ordering::Relaxed),
+        rejected: REJECTED.load(Ordering::Relaxed),
+        shed: SHED.load(Ordering::Relaxed),
+        avg_wait: Duration::from_micros(total.checked_div(waits).unwrap_or(0)),
+        max_wait: Duration::from_micros(WAIT_MAX_US.load(Ordering::Relaxed)),
+    }
+}
+
+struct Pending {
+    fd: RawFd,
+    peer: Option<SocketAddr>,
+    queued_at: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Pending>,
+    /// Threads waiting for a connection.
+    idle: usize,
+    /// Threads serving a connection.
+    busy: usize,
+    closed: bool,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    available: Condvar,
+    threads: usize,
+    capacity: usize,
+    max_wait: Duration,
+    /// Where connections turned away are logged.
+    access_log: Option<Arc<AccessLog>>,
+}
+
+pub(crate) struct ConnectionPool {
+    inner: Arc<Inner>,
+}
+
+impl ConnectionPool {
+    /// Starts `threads` threads that each pass one connection at a time to
+    /// `serve`, which must close it. Up to `capacity` connections wait for a
+    /// free thread, each for at most `max_wait`; zero means no limit. A
+    /// panic in `serve` drops that connection; the thread carries on.
+    pub(crate) fn new<F>(threads: usize, capacity: usize, max_wait: Duration, access_log: Option<Arc<AccessLog>>, serve: F) -> Self
+    where
+        F: Fn(RawFd, Option<SocketAddr>) + Send + Sync + 'static,
+    {
+        let threads = threads.max(1);
+        let inner = Arc::new(Inner {
+            state: Mutex::new(State::default()),
+            available: Condvar::new(),
+            threads,
+            capacity,
+            max_wait,
+            access_log,
+        });
+        THREADS.fetch_add(threads, Ordering::Relaxed);
+        CAPACITY.fetch_add(capacity, Ordering::Relaxed);
+
+        let serve = Arc::new(serve);
+        for i in 0..threads {
+            let inner = Arc::clone(&inner);
+            let serve = Arc::clone(&serve);
+            thread::Builder::new()
+                .name(format!("conn-{}", i))
+                .spawn(move || work(&inner, serve.as_ref()))
+                .expect("failed to spawn connection thread");
+        }
+        Self { inner }
+    }
+
+    /// Hands a connection to the next free thread, queueing it if there is
+    /// none, or turns it away when the queue is full too.
+    pub(crate) fn submit(&self, fd: RawFd, peer: Option<SocketAddr>) {
+        let mut state = self.inner.state.lock().unwrap();
+        // Idle threads take queued connections as soon as they wake up
+        if state.closed || state.queue.len() >= state.idle + self.inner.capacity {
+            drop(state);
+            REJECTED.fetch_add(1, Ordering::Relaxed);
+            HttpServer::reject_client(fd, SERVICE_UNAVAILABLE, "Service Unavailable: too many connections", peer, self.inner.access_log.as_deref());
+            return;
+        }
+
+        state.queue.push_back(Pending { fd, peer, queued_at: Instant::now() });
+        QUEUED.fetch_add(1, Ordering::Relaxed);
+        ACCEPTED.fetch_add(1, Ordering::Relaxed);
+        self.inner.available.notify_one();
+    }
+
+    /// Turns away the connections that have waited too long, so their
+    /// clients hear back without waiting for a thread to free up.
+    pub(crate) fn shed_expired(&self) {
+        if self.inner.max_wait.is_zero() {
+            return;
+        }
+        let mut expired = Vec::new();
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            // Oldest first, so the expired ones are all at the front
+            while state.queue.front().is_some_and(|p| p.queued_at.elapsed() > self.inner.max_wait) {
+                expired.extend(state.queue.pop_front());
+            }
+        }
+        QUEUED.fetch_sub(expired.len(), Ordering::Relaxed);
+        for pending in expired {
+            shed(&self.inner, pending);
+        }
+    }
+
+    /// Connections queued or being served.
+    pub(crate) fn open_connections(&self) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        state.queue.len() + state.busy
+    }
+
+    /// Lets each thread exit once it is done with its current connection;
+    /// anything still queued is turned away.
+    pub(crate) fn close(&self) {
+        let queued: Vec<Pending> = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+            state.queue.drain(..).collect()
+        };
+        self.inner.available.notify_all();
+        QUEUED.fetch_sub(queued.len(), Ordering::Relaxed);
+        for pending in queued {
+            shed(&self.inner, pending);
+        }
+        THREADS.fetch_sub(self.inner.threads, Ordering::Relaxed);
+        CAPACITY.fetch_sub(self.inner.capacity, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ConnectionPool {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn work(inner: &Inner, serve: &(dyn Fn(RawFd, Option<SocketAddr>) + Send + Sync)) {
+    loop {
+        let pending = {
+            let mut state = inner.state.lock().unwrap();
+            loop {
+                if let Some(pending) = state.queue.pop_front() {
+                    state.busy += 1;
+                    break pending;
+                }
+                if state.closed {
+                    return;
+                }
+                state.idle += 1;
+                state = inner.available.wait(state).unwrap();
+                state.idle -= 1;
+            }
+        };
+        let _taken = Taken(inner);
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+
+        let waited = pending.queued_at.elapsed();
+        if !inner.max_wait.is_zero() && waited > inner.max_wait {
+            shed(inner, pending);
+        } else {
+            record_wait(waited);
+            BUSY.fetch_add(1, Ordering::Relaxed);
+            let _busy = Busy;
+            if panic::catch_unwind(AssertUnwindSafe(|| serve(pending.fd, pending.peer))).is_err() {
+                eprintln!("[conn] panic while serving a connection; dropping it");
+            }
+        }
+    }
+}
+
+/// A connection taken off the queue; counted in `State::busy` until dropped.
+struct Taken<'a>(&'a Inner);
+
+impl Drop for Taken<'_> {
+    fn drop(&mut self) {
+        // A panicking handler must not leave the count behind
+        self.0.state.lock().unwrap_or_else(PoisonError::into_inner).busy -= 1;
+    }
+}
+
+/// Counted in `BUSY` while alive.
+struct Busy;
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn shed(inner: &Inner, pending: Pending) {
+    SHED.fetch_add(1, Ordering::Relaxed);
+    HttpServer::reject_client(
+        pending.fd,
+        SERVICE_UNAVAILABLE,
+        "Service Unavailable: timed out waiting for a free connection slot",
+        pending.peer,
+        inner.access_log.as_deref(),
+    );
+}
+
+fn record_wait(waited: Duration) {
+    let us = waited.as_micros() as u64;
+    WAITS.fetch_add(1, Ordering::Relaxed);
+    WAIT_TOTAL_US.fetch_add(us, Ordering::Relaxed);
+    WAIT_MAX_US.fetch_max(us, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::fs::File;
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+
+    fn response(mut client: UnixStream) -> String {
+        let mut text = String::new();
+        client.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn queues_while_busy_and_sheds_what_does_not_fit() {
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let pool = ConnectionPool::new(1, 1, Duration::ZERO, None, move |fd, _| {
+            release_rx.lock().unwrap().recv().unwrap();
+            unsafe { libc::close(fd) };
+        });
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (client, server) = UnixStream::pair().unwrap();
+            pool.submit(server.into_raw_fd(), None);
+            clients.push(client);
+            // Let the thread pick up the first one before the next arrives
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // One served, one queued, the third turned away
+        assert_eq!(pool.open_connections(), 2);
+        assert!(response(clients.pop().unwrap()).contains(" 503 Service Unavailable"));
+
+        release_tx.send(()).unwrap();
+        release_tx.send(()).unwrap();
+        for client in clients {
+            assert_eq!(response(client), "");
+        }
+    }
+
+    #[test]
+    fn sheds_connections_that_wait_too_long() {
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let pool = ConnectionPool::new(1, 4, Duration::from_millis(20), None, move |fd, _| {
+            release_rx.lock().unwrap().recv().unwrap();
+            unsafe { libc::close(fd) };
+        });
+
+        let (busy, server) = UnixStream::pair().unwrap();
+        pool.submit(server.into_raw_fd(), None);
+        thread::sleep(Duration::from_millis(20));
+        let (waiting, server) = UnixStream::pair().unwrap();
+        pool.submit(server.into_raw_fd(), None);
+
+        thread::sleep(Duration::from_millis(50));
+        pool.shed_expired();
+        assert!(response(waiting).contains("timed out waiting"));
+
+        release_tx.send(()).unwrap();
+        assert_eq!(response(busy), "");
+    }
+
+    #[test]
+    fn keeps_serving_after_a_panic() {
+        let pool = ConnectionPool::new(1, 4, Duration::ZERO, None, |fd, peer| {
+            // Closed on the way out, panic or not
+            let _socket = unsafe { File::from_raw_fd(fd) };
+            if peer.is_some() {
+                panic!("handler bug");
+            }
+        });
+
+        let (first, server) = UnixStream::pair().unwrap();
+        pool.submit(server.into_raw_fd(), Some("192.0.2.7:5000".parse().unwrap()));
+        assert_eq!(response(first), "");
+        let (second, server) = UnixStream::pair().unwrap();
+        pool.submit(server.into_raw_fd(), None);
+        assert_eq!(response(second), "");
+
+        // The panicking connection does not stay counted as busy
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(pool.open_connections(), 0);
+    }
+}
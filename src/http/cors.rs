@@ -0,0 +1,244 @@
+//! Cross-origin resource sharing.
+//!
+//! Requests carrying an `Origin` the policy allows get `Access-Control-*`
+//! headers on their response. A preflight (`OPTIONS` with
+//! `Access-Control-Request-Method`) is answered here with 204 and never
+//! reaches a handler. Origins that are not allowed get no CORS headers, and
+//! the browser blocks the response.
+
+use std::env;
+
+use crate::http::{
+    errors::ServerError,
+    middleware::{Middleware, Next},
+    request::{HttpMethod, HttpRequest},
+    response::{Response, NO_CONTENT},
+};
+
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// Exact origins such as `https://dash.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers a page may send; `*` allows whatever the preflight
+    /// asks for.
+    pub allowed_headers: Vec<String>,
+    /// Response headers a page may read besides the CORS-safelisted ones.
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// How long, in seconds, a browser may cache a preflight result.
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: list("GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"),
+            allowed_headers: list("Content-Type"),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: Some(600),
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// Reads `CORS_ALLOWED_ORIGINS`, `CORS_ALLOWED_METHODS`,
+    /// `CORS_ALLOWED_HEADERS`, `CORS_EXPOSE_HEADERS`, `CORS_ALLOW_CREDENTIALS`
+    /// and `CORS_MAX_AGE`. Without any allowed origin CORS stays off.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.trim().is_empty());
+
+        let mut policy = Self {
+            allowed_origins: var("CORS_ALLOWED_ORIGINS").map(|v| list(&v)).unwrap_or(defaults.allowed_origins),
+            allowed_methods: var("CORS_ALLOWED_METHODS").map(|v| list(&v)).unwrap_or(defaults.allowed_methods),
+            allowed_headers: var("CORS_ALLOWED_HEADERS").map(|v| list(&v)).unwrap_or(defaults.allowed_headers),
+            exposed_headers: var("CORS_EXPOSE_HEADERS").map(|v| list(&v)).unwrap_or(defaults.exposed_headers),
+            allow_credentials: var("CORS_ALLOW_CREDENTIALS")
+                .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(defaults.allow_credentials),
+            max_age: match var("CORS_MAX_AGE") {
+                Some(v) => v.trim().parse().ok(),
+                None => defaults.max_age,
+            },
+        };
+        if policy.allow_credentials && policy.allows_any_origin() {
+            eprintln!("CORS_ALLOW_CREDENTIALS needs explicit CORS_ALLOWED_ORIGINS, not '*': credentials disabled");
+            policy.allow_credentials = false;
+        }
+        policy
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    /// The `Access-Control-Allow-Origin` value for `origin`, if allowed.
+    /// `*` is never turned into the caller's origin: that would let any
+    /// site read credentialed responses.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.allows_any_origin() {
+            Some("*".into())
+        } else if self.allowed_origins.iter().any(|o| o.eq_ignore_ascii_case(origin)) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Cors {
+    policy: CorsPolicy,
+}
+
+impl Cors {
+    pub fn new(policy: CorsPolicy) -> Self {
+        Self { policy }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(CorsPolicy::from_env())
+    }
+
+    fn preflight(&self, req: &HttpRequest, allow_origin: &str) -> Response {
+        let p = &self.policy;
+        let allowed_headers = if p.allowed_headers.iter().any(|h| h == "*") {
+            req.header("Access-Control-Request-Headers").unwrap_or("").to_string()
+        } else {
+            p.allowed_headers.join(", ")
+        };
+
+        let mut resp = self
+            .with_origin(Response::new(NO_CONTENT), allow_origin)
+            .set_header("Access-Control-Allow-Methods", &p.allowed_methods.join(", "));
+        if !allowed_headers.is_empty() {
+            resp = resp.set_header("Access-Control-Allow-Headers", &allowed_headers);
+        }
+        if let Some(max_age) = p.max_age {
+            resp = resp.set_header("Access-Control-Max-Age", &max_age.to_string());
+        }
+        resp
+    }
+
+    fn with_origin(&self, resp: Response, allow_origin: &str) -> Response {
+        let mut resp = resp.set_header("Access-Control-Allow-Origin", allow_origin);
+        if allow_origin != "*" {
+            // The answer depends on the Origin, so caches must keep them apart
+            resp = resp.vary("Origin");
+            // Browsers ignore credentials on a wildcard answer anyway
+            if self.policy.allow_credentials {
+                resp = resp.set_header("Access-Control-Allow-Credentials", "true");
+            }
+        }
+        resp
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError> {
+        let Some(allow_origin) = req.header("Origin").and_then(|o| self.policy.allow_origin(o)) else {
+            let resp = next.run(req)?;
+            // With a list of origins the answer depends on the Origin, so
+            // caches must not hand this one to an allowed origin
+            return Ok(if self.policy.allowed_origins.is_empty() || self.policy.allows_any_origin() {
+                resp
+            } else {
+                resp.vary("Origin")
+            });
+        };
+
+        if req.method == HttpMethod::OPTIONS && req.header("Access-Control-Request-Method").is_some() {
+            return Ok(self.preflight(req, &allow_origin));
+        }
+
+        let mut resp = self.with_origin(next.run(req)?, &allow_origin);
+        if !self.policy.exposed_headers.is_empty() {
+            resp = resp.set_header("Access-Control-Expose-Headers", &self.policy.exposed_headers.join(", "));
+        }
+        Ok(resp)
+    }
+}
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use crate::http::handler::Dispatcher;
+    use crate::http::router::router::SimpleHandler;
+    use crate::http::response::OK;
+
+    fn request(raw: &str) -> HttpRequest {
+        HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap()
+    }
+
+    fn dispatcher(policy: CorsPolicy) -> Dispatcher {
+        Dispatcher::builder()
+            .get("/metrics", Arc::new(SimpleHandler(|_: &HttpRequest| Ok(Response::new(OK)))))
+            .wrap(Arc::new(Cors::new(policy)))
+            .build()
+    }
+
+    fn header<'a>(resp: &'a Response, name: &str) -> Option<&'a str> {
+        resp.headers.get(name)
+    }
+
+    #[test]
+    fn answers_preflight_for_allowed_origins() {
+        let d = dispatcher(CorsPolicy {
+            allowed_origins: vec!["https://dash.example".into()],
+            allow_credentials: true,
+            ..CorsPolicy::default()
+        });
+
+        let raw = "OPTIONS /metrics HTTP/1.1\r\nHost: x\r\nOrigin: https://dash.example\r\n\
+                   Access-Control-Request-Method: GET\r\n\r\n";
+        let resp = d.dispatch(&mut request(raw)).unwrap();
+        assert_eq!(resp.status.code, 204);
+        assert_eq!(header(&resp, "Access-Control-Allow-Origin"), Some("https://dash.example"));
+        assert_eq!(header(&resp, "Access-Control-Allow-Methods"), Some("GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"));
+        assert_eq!(header(&resp, "Access-Control-Allow-Credentials"), Some("true"));
+        assert_eq!(header(&resp, "Access-Control-Max-Age"), Some("600"));
+
+        let other = "GET /metrics HTTP/1.1\r\nHost: x\r\nOrigin: https://evil.example\r\n\r\n";
+        let resp = d.dispatch(&mut request(other)).unwrap();
+        assert_eq!(resp.status.code, 200);
+        assert_eq!(header(&resp, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&resp, "Vary"), Some("Origin"));
+        let none = d.dispatch(&mut request("GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+        assert_eq!(header(&none, "Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn never_reflects_origins_for_a_wildcard() {
+        let d = dispatcher(CorsPolicy { allowed_origins: vec!["*".into()], allow_credentials: true, ..CorsPolicy::default() });
+
+        let resp = d.dispatch(&mut request("GET /metrics HTTP/1.1\r\nHost: x\r\nOrigin: https://evil.example\r\n\r\n")).unwrap();
+        assert_eq!(header(&resp, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&resp, "Access-Control-Allow-Credentials"), None);
+        assert_eq!(header(&resp, "Vary"), None);
+    }
+
+    #[test]
+    fn decorates_normal_responses_and_plain_options() {
+        let d = dispatcher(CorsPolicy {
+            allowed_origins: vec!["*".into()],
+            exposed_headers: vec!["X-RateLimit-Remaining".into()],
+            ..CorsPolicy::default()
+        });
+
+        let resp = d.dispatch(&mut request("GET /metrics HTTP/1.1\r\nHost: x\r\nOrigin: https://a.example\r\n\r\n")).unwrap();
+        assert_eq!(header(&resp, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&resp, "Access-Control-Expose-Headers"), Some("X-RateLimit-Remaining"));
+
+        let resp = d.dispatch(&mut request("OPTIONS /metrics HTTP/1.0\r\n\r\n")).unwrap();
+        assert_eq!(resp.status.code, 204);
+        assert_eq!(header(&resp, "Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+}
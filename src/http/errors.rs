@@ -5,10 +5,22 @@ use std::io;
 pub enum ServerError {
     BadRequest(String),
     NotFound,
+    /// Carries the value of the `Allow` header.
+    MethodNotAllowed(String),
+    /// The request method is not one the server knows.
+    NotImplemented,
     Conflict(String),
     TooManyRequests,
     Internal(String),
     ServiceUnavailable,
+    RequestTimeout,
+    PayloadTooLarge,
+    UriTooLong,
+    HeaderFieldsTooLarge,
+    /// An upstream could not be reached or sent an unusable response.
+    BadGateway(String),
+    /// An upstream did not answer in time.
+    GatewayTimeout,
     Io(io::Error),
 }
 
@@ -17,10 +29,18 @@ impl Display for ServerError {
         match self {
             ServerError::BadRequest(msg) => write!(f, "BadRequest: {}", msg),
             ServerError::NotFound => write!(f, "NotFound"),
+            ServerError::MethodNotAllowed(_) => write!(f, "MethodNotAllowed"),
+            ServerError::NotImplemented => write!(f, "NotImplemented"),
             ServerError::Conflict(msg) => write!(f, "Conflict: {}", msg),
             ServerError::TooManyRequests => write!(f, "TooManyRequests"),
             ServerError::Internal(msg) => write!(f, "Internal: {}", msg),
             ServerError::ServiceUnavailable => write!(f, "ServiceUnavailable"),
+            ServerError::RequestTimeout => write!(f, "RequestTimeout"),
+            ServerError::PayloadTooLarge => write!(f, "PayloadTooLarge"),
+            ServerError::UriTooLong => write!(f, "UriTooLong"),
+            ServerError::HeaderFieldsTooLarge => write!(f, "HeaderFieldsTooLarge"),
+            ServerError::BadGateway(msg) => write!(f, "BadGateway: {}", msg),
+            ServerError::GatewayTimeout => write!(f, "GatewayTimeout"),
             ServerError::Io(e) => write!(f, "IO: {}", e),
         }
     }
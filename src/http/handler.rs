@@ -1,11 +1,18 @@
 use std::sync::Arc;
 use std::collections::HashMap;
-use super::response::{Response, OK};
+use super::response::{Response, OK, NO_CONTENT};
 use crate::http::errors::ServerError;
 use super::request::{HttpMethod, HttpRequest};
+use super::middleware::{ErrorMapper, Middleware, Next};
+use super::route::{host_name, HostPattern, PathPattern};
 
 pub trait RequestHandlerStrategy: Send + Sync + 'static {
     fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError>;
+
+    /// Largest body this handler accepts, if it sets its own. Anything past
+    /// the server's `max_body_bytes` is spooled to disk (`spool`), so
+    /// handlers that set this should read the body with `body_reader`.
+    fn max_body_bytes(&self) -> Option<u64> { None }
 }
 
 pub struct GetHandler;
@@ -36,7 +43,7 @@ impl RequestHandlerStrategy for HeadHandler {
 pub struct PostHandler;
 impl RequestHandlerStrategy for PostHandler {
     fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
-        if req.headers.get("Content-Type").map(|v| v.starts_with("text/plain")).unwrap_or(true) {
+        if req.header("Content-Type").map(|v| v.starts_with("text/plain")).unwrap_or(true) {
             let body = String::from_utf8_lossy(&req.body).into_owned();
             Ok(Response::new(OK)
                 .set_header("Content-Type", "text/plain; charset=utf-8")
@@ -47,60 +54,268 @@ impl RequestHandlerStrategy for PostHandler {
     }
 }
 
+struct Route {
+    pattern: PathPattern,
+    handlers: HashMap<HttpMethod, Arc<dyn RequestHandlerStrategy>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Route {
+    /// HEAD is answered by the GET handler unless one is registered for it.
+    fn handler(&self, method: &HttpMethod) -> Option<&Arc<dyn RequestHandlerStrategy>> {
+        self.handlers.get(method).or_else(|| match method {
+            HttpMethod::HEAD => self.handlers.get(&HttpMethod::GET),
+            _ => None,
+        })
+    }
+}
+
 pub struct Dispatcher {
-    get: Arc<dyn RequestHandlerStrategy>,
-    head: Arc<dyn RequestHandlerStrategy>,
-    post: Arc<dyn RequestHandlerStrategy>,
+    routes: Vec<Route>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    /// Virtual hosts, most specific pattern first.
+    hosts: Vec<(HostPattern, Dispatcher)>,
 }
 
 impl Dispatcher {
-    pub fn new() -> Self {
-        Self { get: Arc::new(GetHandler), head: Arc::new(HeadHandler), post: Arc::new(PostHandler) }
-    }
+    pub fn new() -> Self { DispatcherBuilder::default().build() }
     pub fn builder() -> DispatcherBuilder { DispatcherBuilder::default() }
-    pub fn dispatch(&self, req: &HttpRequest) -> Result<Response, ServerError> {
-        match req.method { HttpMethod::GET=>self.get.handle(req), HttpMethod::HEAD=>self.head.handle(req), HttpMethod::POST=>self.post.handle(req), HttpMethod::Unsupported(ref m)=>Err(ServerError::BadRequest(format!("Unsupported method: {}", m))) }
+
+    /// Runs the global middleware, then hands `req` to the virtual host its
+    /// `Host` matches, or routes it to the most specific matching handler
+    /// (through that route's middleware).
+    pub fn dispatch(&self, req: &mut HttpRequest) -> Result<Response, ServerError> {
+        let route = |req: &mut HttpRequest| match self.virtual_host(req) {
+            Some(host) => host.dispatch(req),
+            None => self.route(req),
+        };
+        Next::new(&self.middleware, &route).run(req)
+    }
+
+    /// The body size accepted by the handler `req` would be routed to, if
+    /// that handler sets its own.
+    pub fn body_limit(&self, req: &HttpRequest) -> Option<u64> {
+        match self.virtual_host(req) {
+            Some(host) => host.body_limit(req),
+            None => self.best_route(req)?.0.handler(&req.method)?.max_body_bytes(),
+        }
+    }
+
+    fn virtual_host(&self, req: &HttpRequest) -> Option<&Dispatcher> {
+        let host = host_name(req.header("Host")?);
+        self.hosts.iter().find(|(pattern, _)| pattern.matches(&host)).map(|(_, dispatcher)| dispatcher)
+    }
+
+    /// The most specific route with a handler for the request's method,
+    /// with the path parameters it captured.
+    fn best_route(&self, req: &HttpRequest) -> Option<(&Route, Vec<(String, String)>)> {
+        let mut best: Option<(&Route, Vec<(String, String)>)> = None;
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(&req.path) else { continue };
+            if route.handler(&req.method).is_some()
+                && best.as_ref().map(|(b, _)| route.pattern.priority() < b.pattern.priority()).unwrap_or(true)
+            {
+                best = Some((route, params));
+            }
+        }
+        best
+    }
+
+    /// Stores the captured path parameters on `req` and calls the route's
+    /// handler. A path that matches only for other methods yields 405 with
+    /// the allowed ones; OPTIONS without a handler of its own lists them.
+    /// Methods the server does not know at all yield 501.
+    fn route(&self, req: &mut HttpRequest) -> Result<Response, ServerError> {
+        if let HttpMethod::Unsupported(_) = req.method {
+            return Err(ServerError::NotImplemented);
+        }
+
+        if let Some((route, params)) = self.best_route(req) {
+            req.path_params = params;
+            if let Some(handler) = route.handler(&req.method) {
+                if handler.max_body_bytes().is_some_and(|max| req.body_len() > max) {
+                    return Err(ServerError::PayloadTooLarge);
+                }
+                let endpoint = |req: &mut HttpRequest| handler.handle(req);
+                return Next::new(&route.middleware, &endpoint).run(req);
+            }
+        }
+
+        let mut allowed: Vec<HttpMethod> = self.routes
+            .iter()
+            .filter(|route| route.pattern.matches(&req.path).is_some())
+            .flat_map(|route| route.handlers.keys().cloned())
+            .collect();
+
+        if allowed.is_empty() {
+            return Err(ServerError::NotFound);
+        }
+        if allowed.contains(&HttpMethod::GET) {
+            allowed.push(HttpMethod::HEAD);
+        }
+        allowed.push(HttpMethod::OPTIONS);
+        let mut allowed: Vec<&str> = allowed.iter().map(HttpMethod::as_str).collect();
+        allowed.sort_unstable();
+        allowed.dedup();
+
+        match req.method {
+            HttpMethod::OPTIONS => Ok(Response::new(NO_CONTENT).set_header("Allow", &allowed.join(", "))),
+            _ => Err(ServerError::MethodNotAllowed(allowed.join(", "))),
+        }
     }
 }
 
 #[derive(Default)]
 #[derive(Clone)]
 pub struct DispatcherBuilder {
-    get_map: HashMap<String, Arc<dyn RequestHandlerStrategy>>,
-    head_map: HashMap<String, Arc<dyn RequestHandlerStrategy>>,
-    post_map: HashMap<String, Arc<dyn RequestHandlerStrategy>>,
+    routes: Vec<(String, HttpMethod, Arc<dyn RequestHandlerStrategy>)>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    route_middleware: Vec<(String, Arc<dyn Middleware>)>,
+    hosts: Vec<(String, DispatcherBuilder)>,
 }
 
 impl DispatcherBuilder {
-    pub fn get(mut self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.get_map.insert(path.to_string(), handler); self }
-    pub fn head(mut self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.head_map.insert(path.to_string(), handler); self }
-    pub fn post(mut self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.post_map.insert(path.to_string(), handler); self }
+    /// Registers `handler` for `method` on a path template such as
+    /// `/jobs/{id}/result` or `/files/*` (see `route::PathPattern`).
+    pub fn route(mut self, method: HttpMethod, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self {
+        self.routes.push((path.to_string(), method, handler));
+        self
+    }
+
+    pub fn get(self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.route(HttpMethod::GET, path, handler) }
+    pub fn head(self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.route(HttpMethod::HEAD, path, handler) }
+    pub fn post(self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.route(HttpMethod::POST, path, handler) }
+    pub fn put(self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.route(HttpMethod::PUT, path, handler) }
+    pub fn delete(self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.route(HttpMethod::DELETE, path, handler) }
+    pub fn patch(self, path: &str, handler: Arc<dyn RequestHandlerStrategy>) -> Self { self.route(HttpMethod::PATCH, path, handler) }
+
+    /// Adds middleware that runs for every request, including ones that end
+    /// in 404/405. Layers run in registration order, the first outermost.
+    pub fn wrap(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Adds middleware that only runs for requests routed to `path` (the
+    /// same template string the route was registered with), for any method.
+    pub fn wrap_route(mut self, path: &str, middleware: Arc<dyn Middleware>) -> Self {
+        self.route_middleware.push((path.to_string(), middleware));
+        self
+    }
+
+    /// Serves requests whose `Host` matches `pattern` (see
+    /// `route::HostPattern`) from `routes` instead. This builder's own routes
+    /// stay the default host, and its `wrap` middleware runs for every host.
+    pub fn host(mut self, pattern: &str, routes: DispatcherBuilder) -> Self {
+        self.hosts.push((pattern.to_string(), routes));
+        self
+    }
 
     pub fn build(self) -> Dispatcher {
-        // Build a dispatcher that first checks explicit maps; fallback to defaults
-        struct MapHandler { map: HashMap<String, Arc<dyn RequestHandlerStrategy>> }
-        impl RequestHandlerStrategy for MapHandler {
-            fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
-                if let Some(h) = self.map.get(&req.path) { h.handle(req) } else { Err(ServerError::NotFound) }
+        self.build_with_fallback(true)
+    }
+
+    /// A virtual host never gets the demo handlers: one left without routes
+    /// answers 404 rather than the demo pages.
+    fn build_with_fallback(mut self, demo_fallback: bool) -> Dispatcher {
+        // Without any routes or hosts, serve the demo handlers
+        if demo_fallback && self.routes.is_empty() && self.hosts.is_empty() {
+            self = self
+                .get("/*", Arc::new(GetHandler))
+                .head("/*", Arc::new(HeadHandler))
+                .post("/*", Arc::new(PostHandler));
+        }
+
+        let mut routes: Vec<Route> = Vec::new();
+        for (path, method, handler) in self.routes {
+            let pattern = PathPattern::parse(&path);
+            // Re-registering a path and method replaces the earlier handler
+            match routes.iter_mut().find(|r| r.pattern == pattern) {
+                Some(route) => {
+                    route.handlers.insert(method, handler);
+                }
+                None => routes.push(Route { pattern, handlers: HashMap::from([(method, handler)]), middleware: Vec::new() }),
             }
         }
 
-        let get: Arc<dyn RequestHandlerStrategy> = if self.get_map.is_empty() {
-            Arc::new(GetHandler)
-        } else {
-            Arc::new(MapHandler { map: self.get_map })
-        };
-        let head: Arc<dyn RequestHandlerStrategy> = if self.head_map.is_empty() {
-            Arc::new(HeadHandler)
-        } else {
-            Arc::new(MapHandler { map: self.head_map })
-        };
-        let post: Arc<dyn RequestHandlerStrategy> = if self.post_map.is_empty() {
-            Arc::new(PostHandler)
-        } else {
-            Arc::new(MapHandler { map: self.post_map })
-        };
+        for (path, middleware) in self.route_middleware {
+            let pattern = PathPattern::parse(&path);
+            match routes.iter_mut().find(|r| r.pattern == pattern) {
+                Some(route) => route.middleware.push(middleware),
+                None => panic!("wrap_route: no route registered for '{}'", path),
+            }
+        }
+
+        let mut hosts: Vec<(HostPattern, Dispatcher)> = Vec::new();
+        for (pattern, builder) in self.hosts {
+            let pattern = HostPattern::parse(&pattern);
+            // Re-registering a host pattern replaces the earlier routes
+            hosts.retain(|(p, _)| *p != pattern);
+            hosts.push((pattern, builder.build_with_fallback(false)));
+        }
+        hosts.sort_by_key(|(pattern, _)| pattern.priority());
+
+        // Errors become responses before they reach user middleware
+        self.middleware.push(Arc::new(ErrorMapper));
+
+        Dispatcher { routes, middleware: self.middleware, hosts }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::http::router::router::SimpleHandler;
+
+    fn answer(text: &'static str) -> Arc<dyn RequestHandlerStrategy> {
+        Arc::new(SimpleHandler(move |_: &HttpRequest| Ok(Response::new(OK).with_body(text))))
+    }
+
+    fn body(d: &Dispatcher, raw: &str) -> Result<String, u16> {
+        let mut req = HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap();
+        let resp = d.dispatch(&mut req).unwrap();
+        match resp.status.code {
+            200 => Ok(String::from_utf8(resp.body).unwrap()),
+            code => Err(code),
+        }
+    }
+
+    #[test]
+    fn routes_by_virtual_host() {
+        let d = Dispatcher::builder()
+            .get("/jobs", answer("default jobs"))
+            .get("/pi", answer("default pi"))
+            .host("jobs.internal", Dispatcher::builder().get("/jobs", answer("jobs host")))
+            .host("*.internal", Dispatcher::builder().get("/pi", answer("any internal")))
+            .host("*.compute.internal", Dispatcher::builder().get("/pi", answer("compute host")))
+            .build();
+
+        assert_eq!(body(&d, "GET /jobs HTTP/1.1\r\nHost: Jobs.Internal:8080\r\n\r\n"), Ok("jobs host".into()));
+        // A virtual host only serves its own routes
+        assert_eq!(body(&d, "GET /pi HTTP/1.1\r\nHost: jobs.internal\r\n\r\n"), Err(404));
+        assert_eq!(body(&d, "GET /pi HTTP/1.1\r\nHost: a.compute.internal\r\n\r\n"), Ok("compute host".into()));
+        assert_eq!(body(&d, "GET /pi HTTP/1.1\r\nHost: db.internal\r\n\r\n"), Ok("any internal".into()));
+
+        assert_eq!(body(&d, "GET /pi HTTP/1.1\r\nHost: example.com\r\n\r\n"), Ok("default pi".into()));
+        assert_eq!(body(&d, "GET /jobs HTTP/1.0\r\n\r\n"), Ok("default jobs".into()));
+    }
+
+    #[test]
+    fn virtual_hosts_without_routes_serve_nothing() {
+        let d = Dispatcher::builder().host("empty.internal", Dispatcher::builder()).build();
+        assert_eq!(body(&d, "GET / HTTP/1.1\r\nHost: empty.internal\r\n\r\n"), Err(404));
+
+        // Only the top level falls back to the demo handlers
+        let demo = Dispatcher::builder().build();
+        assert!(body(&demo, "GET / HTTP/1.1\r\nHost: empty.internal\r\n\r\n").is_ok());
+    }
 
-        Dispatcher { get, head, post }
+    #[test]
+    fn unknown_methods_are_not_implemented() {
+        let d = Dispatcher::builder().get("/jobs", answer("jobs")).build();
+        assert_eq!(body(&d, "BREW /jobs HTTP/1.1\r\nHost: x\r\n\r\n"), Err(501));
+        assert_eq!(body(&d, "BREW /nowhere HTTP/1.1\r\nHost: x\r\n\r\n"), Err(501));
+        assert_eq!(body(&d, "PUT /jobs HTTP/1.1\r\nHost: x\r\n\r\n"), Err(405));
     }
 }
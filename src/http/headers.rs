@@ -0,0 +1,195 @@
+//! Header fields of a request or response.
+//!
+//! Names are matched case-insensitively but kept as received. Fields stay
+//! in the order they were added, and a repeated field (several
+//! `Set-Cookie`, say) keeps every value.
+
+use crate::http::errors::ServerError;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value, keeping any already present.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces every value of `name` with `value`. The field keeps the
+    /// position of its first occurrence.
+    pub fn set(&mut self, name: &str, value: &str) {
+        let first = self.entries.iter().position(|(k, _)| k.eq_ignore_ascii_case(name));
+        self.remove(name);
+        // Nothing before the first occurrence was removed, so it is still in place
+        let at = first.unwrap_or(self.entries.len());
+        self.entries.insert(at, (name.to_string(), value.to_string()));
+    }
+
+    /// Removes every value of `name`; returns whether there were any.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.entries.len() != before
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The message's `Content-Length`. Repeated values must agree, since
+    /// peers picking different ones is how requests get smuggled.
+    pub fn content_length(&self) -> Result<Option<usize>, ServerError> {
+        content_length(self.get_all("Content-Length"))
+    }
+
+    /// Whether the body is chunked. Only a single `Transfer-Encoding:
+    /// chunked` without a `Content-Length` is accepted; anything else is a
+    /// framing peers could read differently.
+    pub fn chunked(&self) -> Result<bool, ServerError> {
+        chunked(self.get_all("Transfer-Encoding"), self.contains("Content-Length"))
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+/// Field names are tokens (RFC 7230 section 3.2.6).
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Values must not contain CR, LF or NUL.
+pub fn is_valid_value(value: &str) -> bool {
+    !value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0'))
+}
+
+/// Resolves the values of every `Content-Length` field, each of which may
+/// itself be a comma-separated list, to a single length.
+pub(crate) fn content_length<'a>(values: impl IntoIterator<Item = &'a str>) -> Result<Option<usize>, ServerError> {
+    let mut length = None;
+    for value in values.into_iter().flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        let parsed = value
+            .parse::<usize>()
+            .map_err(|_| ServerError::BadRequest(format!("Invalid Content-Length: '{}'", value)))?;
+        if length.is_some_and(|l| l != parsed) {
+            return Err(ServerError::BadRequest("Conflicting Content-Length values".into()));
+        }
+        length = Some(parsed);
+    }
+    Ok(length)
+}
+
+/// Resolves the values of every `Transfer-Encoding` field to whether the
+/// body is chunked, refusing repeated fields, codings other than `chunked`
+/// and a `Content-Length` alongside.
+pub(crate) fn chunked<'a>(values: impl IntoIterator<Item = &'a str>, has_length: bool) -> Result<bool, ServerError> {
+    let mut values = values.into_iter();
+    let Some(value) = values.next() else { return Ok(false) };
+    if values.next().is_some() {
+        return Err(ServerError::BadRequest("Repeated Transfer-Encoding".into()));
+    }
+    if !value.trim().eq_ignore_ascii_case("chunked") {
+        return Err(ServerError::BadRequest(format!("Unsupported Transfer-Encoding: '{}'", value.trim())));
+    }
+    if has_length {
+        return Err(ServerError::BadRequest("Both Transfer-Encoding and Content-Length".into()));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_case_insensitively_and_keeps_duplicates() {
+        let mut h = Headers::new();
+        h.append("Set-Cookie", "a=1");
+        h.append("content-type", "text/plain");
+        h.append("set-cookie", "b=2");
+
+        assert_eq!(h.get("Content-Type"), Some("text/plain"));
+        assert_eq!(h.get_all("SET-COOKIE").collect::<Vec<_>>(), ["a=1", "b=2"]);
+
+        h.set("Set-Cookie", "c=3");
+        assert_eq!(h.iter().collect::<Vec<_>>(), [("Set-Cookie", "c=3"), ("content-type", "text/plain")]);
+
+        assert!(h.remove("CONTENT-TYPE"));
+        assert!(!h.contains("Content-Type"));
+        assert_eq!(h.len(), 1);
+    }
+
+    #[test]
+    fn rejects_conflicting_content_lengths() {
+        assert_eq!(content_length(["10", "10, 10"]).unwrap(), Some(10));
+        assert_eq!(content_length([]).unwrap(), None);
+        assert!(content_length(["10", "11"]).is_err());
+        assert!(content_length(["10, 12"]).is_err());
+        assert!(content_length(["-1"]).is_err());
+    }
+
+    #[test]
+    fn accepts_only_a_lone_chunked_transfer_encoding() {
+        assert!(!chunked([], true).unwrap());
+        assert!(chunked([" Chunked "], false).unwrap());
+        assert!(chunked(["chunked", "chunked"], false).is_err());
+        assert!(chunked(["gzip, chunked"], false).is_err());
+        assert!(chunked(["chunked"], true).is_err());
+    }
+
+    #[test]
+    fn requests_use_the_header_map() {
+        use crate::http::request::HttpRequest;
+        let parse = |raw: &str| HttpRequest::parse(&mut std::io::Cursor::new(raw.as_bytes().to_vec()));
+
+        let req = parse("POST /x HTTP/1.1\r\nhost: a\r\ncontent-length: 5\r\nAccept: a\r\naccept: b\r\n\r\nhello").unwrap();
+        assert_eq!(req.body, b"hello");
+        assert_eq!(req.headers.get_all("Accept").collect::<Vec<_>>(), ["a", "b"]);
+
+        assert!(parse("POST /x HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!").is_err());
+        assert!(parse("POST /x HTTP/1.1\r\nHost: a\r\nContent-Length : 5\r\n\r\nhello").is_err());
+
+        let smuggled = "POST /x HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(smuggled), Err(ServerError::BadRequest(_))));
+        let repeated = "POST /x HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(repeated), Err(ServerError::BadRequest(_))));
+    }
+}
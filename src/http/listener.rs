@@ -0,0 +1,569 @@
+use std::{
+    env,
+    fmt::{self, Display, Formatter},
+    fs::{self, Permissions},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::{
+        fd::RawFd,
+        unix::{ffi::OsStrExt, fs::{FileTypeExt, PermissionsExt}, net::UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use libc::{
+    self, c_int, sockaddr, sockaddr_in, sockaddr_in6, sockaddr_storage, sockaddr_un, socklen_t,
+    AF_INET, AF_INET6, AF_UNIX, IPPROTO_IPV6, IPV6_V6ONLY, SOCK_STREAM, SOL_SOCKET, SO_REUSEADDR,
+};
+
+/// First fd a service manager passes with `LISTEN_FDS`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A source of listening sockets. `HttpServer` polls and accepts on
+/// whatever fds these return, so TCP, Unix and inherited sockets can be
+/// served side by side.
+pub trait Listener: Display + Send + Sync {
+    /// Creates (or adopts) the listening sockets.
+    fn open(&self) -> io::Result<Vec<RawFd>>;
+
+    /// Called after the sockets were closed, e.g. to remove a socket file.
+    fn cleanup(&self) {}
+}
+
+/// A TCP socket bound to a `BIND_ADDRESS` entry.
+pub struct TcpListener {
+    pub addr: BindAddr,
+    /// IPv6 only: whether IPv4-mapped connections are refused.
+    pub v6_only: bool,
+    /// Sets `SO_REUSEPORT`, so other processes may bind the address too.
+    pub reuse_port: bool,
+}
+
+impl Listener for TcpListener {
+    fn open(&self) -> io::Result<Vec<RawFd>> {
+        bind(&self.addr, self.v6_only, self.reuse_port).map(|fd| vec![fd])
+    }
+}
+
+impl Display for TcpListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.addr.fmt(f)
+    }
+}
+
+/// A Unix domain socket at `path`. A socket file left behind by an earlier
+/// run is replaced; one another process still accepts on is not.
+pub struct UnixListener {
+    pub path: PathBuf,
+    /// Permission bits for the socket file (e.g. `0o660`); connecting
+    /// requires write permission on it.
+    pub mode: Option<u32>,
+}
+
+impl Listener for UnixListener {
+    fn open(&self) -> io::Result<Vec<RawFd>> {
+        remove_stale_socket(&self.path)?;
+        let addr = unix_addr(&self.path)?;
+
+        let fd = unsafe { libc::socket(AF_UNIX, SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let rc = unsafe {
+            libc::bind(fd, (&addr as *const sockaddr_un).cast::<sockaddr>(), std::mem::size_of::<sockaddr_un>() as socklen_t)
+        };
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+
+        // Nobody can connect before listen(), so the mode is in place first
+        let listening = self
+            .mode
+            .map(|mode| fs::set_permissions(&self.path, Permissions::from_mode(mode)))
+            .unwrap_or(Ok(()))
+            .and_then(|_| listen(fd));
+        if let Err(e) = listening {
+            unsafe { libc::close(fd) };
+            let _ = fs::remove_file(&self.path);
+            return Err(e);
+        }
+        Ok(vec![fd])
+    }
+
+    fn cleanup(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Display for UnixListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "unix:{}", self.path.display())?;
+        match self.mode {
+            Some(mode) => write!(f, " (mode {:o})", mode),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Listening sockets passed in by a service manager (systemd socket
+/// activation): `LISTEN_FDS` of them starting at fd 3, if `LISTEN_PID`
+/// names this process.
+pub struct SystemdListener;
+
+impl Listener for SystemdListener {
+    fn open(&self) -> io::Result<Vec<RawFd>> {
+        let fds: Vec<RawFd> = (0..inherited_fd_count() as RawFd).map(|i| LISTEN_FDS_START + i).collect();
+        for &fd in &fds {
+            let accepting = get_int_opt(fd, SOL_SOCKET, libc::SO_ACCEPTCONN)?;
+            let kind = get_int_opt(fd, SOL_SOCKET, libc::SO_TYPE)?;
+            if accepting != 1 || kind != SOCK_STREAM {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("Inherited fd {} is not a listening stream socket", fd)));
+            }
+            // Commands run by handlers must not inherit the listeners
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        Ok(fds)
+    }
+}
+
+impl Display for SystemdListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let count = inherited_fd_count();
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        write!(f, "{} inherited socket(s) from LISTEN_FDS", count)?;
+        if !names.is_empty() {
+            write!(f, " ({})", names.replace(':', ", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sockets opened before this process was forked from the one that owns
+/// them (see `prefork`). Closing them here leaves the owner's copies open.
+pub struct SharedListener {
+    pub fds: Vec<RawFd>,
+}
+
+impl Listener for SharedListener {
+    fn open(&self) -> io::Result<Vec<RawFd>> {
+        Ok(self.fds.clone())
+    }
+}
+
+impl Display for SharedListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} socket(s) shared by the master process", self.fds.len())
+    }
+}
+
+/// How many listening sockets a service manager passed to this process.
+pub fn inherited_fd_count() -> usize {
+    let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.trim().parse::<u32>().ok()) == Some(std::process::id());
+    if !for_us {
+        return 0;
+    }
+    env::var("LISTEN_FDS").ok().and_then(|n| n.trim().parse().ok()).unwrap_or(0)
+}
+
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(ErrorKind::AddrInUse, format!("Another process is listening on {}", path.display())));
+    }
+    fs::remove_file(path)
+}
+
+fn unix_addr(path: &Path) -> io::Result<sockaddr_un> {
+    let mut addr: sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = AF_UNIX as libc::sa_family_t;
+
+    let bytes = path.as_os_str().as_bytes();
+    // Leave room for the terminating NUL
+    if bytes.is_empty() || bytes.len() >= addr.sun_path.len() || bytes.contains(&0) {
+        return Err(create_parse_error(&format!("Invalid Unix socket path: '{}'", path.display())));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+    Ok(addr)
+}
+
+/// A parsed `BIND_ADDRESS` entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindAddr {
+    /// IPv4 address in network byte order, port in host byte order.
+    V4 { ip: u32, port: u16 },
+    V6 { ip: [u8; 16], port: u16, scope_id: u32 },
+}
+
+impl BindAddr {
+    pub fn port(&self) -> u16 {
+        match self {
+            BindAddr::V4 { port, .. } | BindAddr::V6 { port, .. } => *port,
+        }
+    }
+}
+
+impl Display for BindAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddr::V4 { ip, port } => {
+                let o = ip.to_ne_bytes();
+                write!(f, "{}.{}.{}.{}:{}", o[0], o[1], o[2], o[3], port)
+            }
+            BindAddr::V6 { ip, port, scope_id: 0 } => write!(f, "[{}]:{}", Ipv6Addr::from(*ip), port),
+            BindAddr::V6 { ip, port, scope_id } => write!(f, "[{}%{}]:{}", Ipv6Addr::from(*ip), scope_id, port),
+        }
+    }
+}
+
+/// Parses a comma-separated list of listen addresses, e.g.
+/// `127.0.0.1:8080,[::1]:8080`.
+pub fn parse_bind_addrs(list: &str) -> io::Result<Vec<BindAddr>> {
+    let addrs = list
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(parse_bind_addr)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if addrs.is_empty() {
+        return Err(create_parse_error("No listen address configured"));
+    }
+    Ok(addrs)
+}
+
+/// Parses `HOST:PORT` for IPv4 or `[ADDR]:PORT` / `[ADDR%SCOPE]:PORT` for IPv6.
+pub fn parse_bind_addr(addr: &str) -> io::Result<BindAddr> {
+    let addr = addr.trim();
+
+    if let Some(rest) = addr.strip_prefix('[') {
+        let (host_str, port_part) = rest.split_once(']')
+            .ok_or_else(|| create_parse_error(&format!("Missing ']' in IPv6 address: '{}'", addr)))?;
+        let port_str = port_part.strip_prefix(':')
+            .ok_or_else(|| create_parse_error("Address format must be '[HOST]:PORT'"))?;
+
+        let port = parse_port(port_str)?;
+        let (ip, scope_id) = parse_ipv6_host(host_str)?;
+        return Ok(BindAddr::V6 { ip, port, scope_id });
+    }
+
+    let (ip, port) = parse_ipv4_addr(addr)?;
+    Ok(BindAddr::V4 { ip, port })
+}
+
+fn parse_port(port_str: &str) -> io::Result<u16> {
+    let port_str = port_str.trim();
+    port_str.parse()
+        .map_err(|_| create_parse_error(&format!("Invalid port value: '{}'", port_str)))
+}
+
+fn parse_ipv6_host(host: &str) -> io::Result<([u8; 16], u32)> {
+    let (ip_str, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+
+    let ip_str = if ip_str == "*" { "::" } else { ip_str };
+    let ip: Ipv6Addr = ip_str.parse()
+        .map_err(|_| create_parse_error(&format!("Invalid IPv6 address: '{}'", ip_str)))?;
+
+    let scope_id = match scope {
+        None => 0,
+        Some(s) => match s.parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => {
+                let name = std::ffi::CString::new(s)
+                    .map_err(|_| create_parse_error(&format!("Invalid interface name: '{}'", s)))?;
+                let idx = unsafe { libc::if_nametoindex(name.as_ptr()) };
+                if idx == 0 {
+                    return Err(create_parse_error(&format!("Unknown interface: '{}'", s)));
+                }
+                idx
+            }
+        },
+    };
+
+    Ok((ip.octets(), scope_id))
+}
+
+/// Creates a listening socket for `addr`. For IPv6 sockets `v6_only`
+/// decides whether IPv4-mapped connections are accepted too (dual-stack).
+/// With `reuse_port` several sockets (e.g. one per process) may bind the
+/// same address and the kernel spreads connections across them.
+pub fn bind(addr: &BindAddr, v6_only: bool, reuse_port: bool) -> io::Result<RawFd> {
+    match *addr {
+        BindAddr::V4 { ip, port } => create_listen_socket(ip, port, reuse_port),
+        BindAddr::V6 { ip, port, scope_id } => create_listen_socket_v6(ip, port, scope_id, v6_only, reuse_port),
+    }
+}
+
+pub fn create_listen_socket(ip_host: u32, port_host: u16, reuse_port: bool) -> io::Result<RawFd> {
+    let fd = new_socket(AF_INET, reuse_port)?;
+
+    let mut addr: sockaddr_in = unsafe { std::mem::zeroed() };
+    addr.sin_family = AF_INET as u16;
+    addr.sin_port = port_host.to_be();     // convert port to network byte order
+    addr.sin_addr.s_addr = ip_host;        // use ip as-is (already parsed)
+
+    bind_and_listen(
+        fd,
+        (&addr as *const sockaddr_in).cast::<sockaddr>(),
+        std::mem::size_of::<sockaddr_in>() as socklen_t,
+    )
+}
+
+pub fn create_listen_socket_v6(ip: [u8; 16], port_host: u16, scope_id: u32, v6_only: bool, reuse_port: bool) -> io::Result<RawFd> {
+    let fd = new_socket(AF_INET6, reuse_port)?;
+
+    if let Err(e) = set_int_opt(fd, IPPROTO_IPV6, IPV6_V6ONLY, v6_only as c_int) {
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+
+    let mut addr: sockaddr_in6 = unsafe { std::mem::zeroed() };
+    addr.sin6_family = AF_INET6 as u16;
+    addr.sin6_port = port_host.to_be();
+    addr.sin6_addr.s6_addr = ip;
+    addr.sin6_scope_id = scope_id;
+
+    bind_and_listen(
+        fd,
+        (&addr as *const sockaddr_in6).cast::<sockaddr>(),
+        std::mem::size_of::<sockaddr_in6>() as socklen_t,
+    )
+}
+
+fn new_socket(family: c_int, reuse_port: bool) -> io::Result<RawFd> {
+    let fd = unsafe { libc::socket(family, SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Allow immediate reuse of port
+    let _ = set_int_opt(fd, SOL_SOCKET, SO_REUSEADDR, 1);
+    if reuse_port {
+        if let Err(e) = set_int_opt(fd, SOL_SOCKET, libc::SO_REUSEPORT, 1) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+    }
+    Ok(fd)
+}
+
+fn set_int_opt(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const c_int).cast(),
+            std::mem::size_of_val(&value) as socklen_t,
+        )
+    };
+    if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+fn get_int_opt(fd: RawFd, level: c_int, name: c_int) -> io::Result<c_int> {
+    let mut value: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as socklen_t;
+    let rc = unsafe { libc::getsockopt(fd, level, name, (&mut value as *mut c_int).cast(), &mut len) };
+    if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(value) }
+}
+
+fn bind_and_listen(fd: RawFd, addr: *const sockaddr, len: socklen_t) -> io::Result<RawFd> {
+    let rc = unsafe { libc::bind(fd, addr, len) };
+    if rc < 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+
+    if let Err(e) = listen(fd) {
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+
+    Ok(fd)
+}
+
+fn listen(fd: RawFd) -> io::Result<()> {
+    let rc = unsafe { libc::listen(fd, 128) };
+    if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+/// Waits until at least one listener has a pending connection and returns
+/// the ready ones. An empty result means `timeout_ms` expired.
+pub fn poll_listeners(fds: &[RawFd], timeout_ms: c_int) -> io::Result<Vec<RawFd>> {
+    let mut pfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+        .collect();
+
+    let rc = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout_ms) };
+    if rc < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == ErrorKind::Interrupted {
+            return Ok(Vec::new());
+        }
+        return Err(e);
+    }
+
+    Ok(pfds.iter().filter(|p| p.revents & libc::POLLIN != 0).map(|p| p.fd).collect())
+}
+
+/// Converts an address filled in by `accept4`. IPv4-mapped IPv6 peers of a
+/// dual-stack listener are reported as plain IPv4.
+pub fn socket_addr(storage: &sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as c_int {
+        AF_INET => {
+            let addr = unsafe { &*(storage as *const sockaddr_storage).cast::<sockaddr_in>() };
+            let ip = Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes());
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port)))
+        }
+        AF_INET6 => {
+            let addr = unsafe { &*(storage as *const sockaddr_storage).cast::<sockaddr_in6>() };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let ip = ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip));
+            Some(SocketAddr::new(ip, u16::from_be(addr.sin6_port)))
+        }
+        _ => None,
+    }
+}
+
+fn create_parse_error(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn parse_ipv4_addr(addr: &str) -> io::Result<(u32, u16)> {
+    let split = addr.trim();
+
+    let (host_str, port_str) = split.rsplit_once(':')
+        .ok_or_else(|| create_parse_error("Address format must be 'HOST:PORT'"))?;
+
+    let host_str = host_str.trim();
+    let port = parse_port(port_str)?;
+
+    if host_str.contains(':') {
+        return Err(create_parse_error(&format!("IPv6 addresses must be bracketed: '[{}]:PORT'", host_str)));
+    }
+
+    let final_host_str = match host_str {
+        "*" | "0.0.0.0" => {
+            return Ok((0u32, port));
+        }
+        host if host.eq_ignore_ascii_case("localhost") => "127.0.0.1",
+        host => host,
+    };
+
+    let mut octets: [u8; 4] = [0; 4];
+
+    for (i, part) in final_host_str.split('.').enumerate() {
+        if i >= 4 {
+            return Err(create_parse_error(&format!("Invalid IPv4 format: '{}' has too many octets", final_host_str)));
+        }
+
+        let octet_val = part.parse::<u8>()
+            .map_err(|_| create_parse_error(&format!("Invalid octet value: '{}'", part)))?;
+
+        octets[i] = octet_val;
+    }
+
+    if final_host_str.split('.').count() != 4 {
+        return Err(create_parse_error(&format!("Invalid IPv4 format: '{}' must have 4 octets", final_host_str)));
+    }
+
+    let ip_host: u32 = u32::from_ne_bytes(octets);
+    let port_host: u16 = port;
+
+    Ok((ip_host, port_host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ipv4_and_wildcards() {
+        assert_eq!(
+            parse_bind_addr("127.0.0.1:8080").unwrap(),
+            BindAddr::V4 { ip: u32::from_ne_bytes([127, 0, 0, 1]), port: 8080 }
+        );
+        assert_eq!(parse_bind_addr("0.0.0.0:80").unwrap(), BindAddr::V4 { ip: 0, port: 80 });
+        assert_eq!(parse_bind_addr("localhost:1").unwrap().to_string(), "127.0.0.1:1");
+        assert!(parse_bind_addr("1.2.3:80").is_err());
+        assert!(parse_bind_addr("1.2.3.4").is_err());
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6() {
+        let any = parse_bind_addr("[::]:8080").unwrap();
+        assert_eq!(any, BindAddr::V6 { ip: [0; 16], port: 8080, scope_id: 0 });
+
+        let lo = parse_bind_addr("[::1]:9000").unwrap();
+        assert_eq!(lo.to_string(), "[::1]:9000");
+
+        let scoped = parse_bind_addr("[fe80::1%3]:80").unwrap();
+        assert_eq!(scoped, BindAddr::V6 { ip: "fe80::1".parse::<Ipv6Addr>().unwrap().octets(), port: 80, scope_id: 3 });
+
+        assert!(parse_bind_addr("::1:8080").is_err());
+        assert!(parse_bind_addr("[::1]8080").is_err());
+        assert!(parse_bind_addr("[::g]:80").is_err());
+    }
+
+    #[test]
+    fn unix_listener_replaces_only_stale_sockets() {
+        let path = std::env::temp_dir().join(format!("http-server-test-{}.sock", std::process::id()));
+        let listener = UnixListener { path: path.clone(), mode: Some(0o600) };
+
+        let fds = listener.open().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(UnixStream::connect(&path).is_ok());
+        assert_eq!(listener.open().unwrap_err().kind(), ErrorKind::AddrInUse);
+
+        // Closed without cleanup, as after a crash: the file is stale
+        unsafe { libc::close(fds[0]) };
+        let fds = listener.open().unwrap();
+        unsafe { libc::close(fds[0]) };
+        listener.cleanup();
+        assert!(!path.exists());
+
+        fs::write(&path, b"not a socket").unwrap();
+        assert_eq!(listener.open().unwrap_err().kind(), ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reuse_port_lets_listeners_share_an_address() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = parse_bind_addr(&format!("127.0.0.1:{}", port)).unwrap();
+        let shared = TcpListener { addr, v6_only: false, reuse_port: true };
+        let exclusive = TcpListener { addr, v6_only: false, reuse_port: false };
+
+        let first = shared.open().unwrap();
+        let second = shared.open().unwrap();
+        assert_eq!(exclusive.open().unwrap_err().kind(), ErrorKind::AddrInUse);
+        unsafe {
+            libc::close(first[0]);
+            libc::close(second[0]);
+        }
+    }
+
+    #[test]
+    fn parses_address_lists() {
+        let addrs = parse_bind_addrs("127.0.0.1:8080, [::1]:8080").unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[1].port(), 8080);
+        assert!(parse_bind_addrs(" , ").is_err());
+    }
+}
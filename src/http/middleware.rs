@@ -0,0 +1,171 @@
+//! Request/response middleware around `RequestHandlerStrategy`.
+//!
+//! Middleware registered globally on `DispatcherBuilder::wrap` runs for
+//! every request, before routing; middleware registered with
+//! `DispatcherBuilder::wrap_route` only runs once that route matched.
+//! Each layer receives the request mutably and a `Next` to call the rest of
+//! the chain. It may return early instead (short-circuit) and may rewrite
+//! whatever the inner layers returned.
+
+use std::sync::Arc;
+
+use crate::http::{
+    errors::ServerError,
+    request::HttpRequest,
+    response::{
+        Response,
+        BAD_REQUEST, NOT_FOUND, METHOD_NOT_ALLOWED, NOT_IMPLEMENTED, REQUEST_TIMEOUT, CONFLICT, PAYLOAD_TOO_LARGE, URI_TOO_LONG,
+        TOO_MANY_REQUESTS, REQUEST_HEADER_FIELDS_TOO_LARGE, INTERNAL_SERVER_ERROR, SERVICE_UNAVAILABLE,
+        BAD_GATEWAY, GATEWAY_TIMEOUT,
+    },
+};
+
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError>;
+}
+
+/// The remainder of a middleware chain, ending in the handler.
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&mut HttpRequest) -> Result<Response, ServerError>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        layers: &'a [Arc<dyn Middleware>],
+        endpoint: &'a dyn Fn(&mut HttpRequest) -> Result<Response, ServerError>,
+    ) -> Self {
+        Self { layers, endpoint }
+    }
+
+    /// Passes the request to the next layer, or to the handler if this
+    /// was the last one.
+    pub fn run(self, req: &mut HttpRequest) -> Result<Response, ServerError> {
+        match self.layers.split_first() {
+            Some((layer, rest)) => layer.handle(req, Next { layers: rest, endpoint: self.endpoint }),
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+/// Turns handler and routing errors into their JSON error responses, so
+/// outer middleware sees a regular `Response` (e.g. to add headers to a
+/// 404). `DispatcherBuilder` installs it as the innermost global layer.
+pub struct ErrorMapper;
+
+impl Middleware for ErrorMapper {
+    fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError> {
+        Ok(next.run(req).unwrap_or_else(|e| error_response(&e)))
+    }
+}
+
+pub fn error_response(err: &ServerError) -> Response {
+    let status = match err {
+        ServerError::BadRequest(_) => BAD_REQUEST,
+        ServerError::NotFound => NOT_FOUND,
+        ServerError::MethodNotAllowed(_) => METHOD_NOT_ALLOWED,
+        ServerError::NotImplemented => NOT_IMPLEMENTED,
+        ServerError::RequestTimeout => REQUEST_TIMEOUT,
+        ServerError::Conflict(_) => CONFLICT,
+        ServerError::PayloadTooLarge => PAYLOAD_TOO_LARGE,
+        ServerError::UriTooLong => URI_TOO_LONG,
+        ServerError::TooManyRequests => TOO_MANY_REQUESTS,
+        ServerError::HeaderFieldsTooLarge => REQUEST_HEADER_FIELDS_TOO_LARGE,
+        ServerError::ServiceUnavailable => SERVICE_UNAVAILABLE,
+        ServerError::BadGateway(_) => BAD_GATEWAY,
+        ServerError::GatewayTimeout => GATEWAY_TIMEOUT,
+        ServerError::Internal(_) | ServerError::Io(_) => INTERNAL_SERVER_ERROR,
+    };
+
+    let json_body = format!("{{\"error\": \"{}\"}}", err);
+    let resp = Response::new(status)
+        .set_header("Content-Type", "application/json")
+        .with_body(json_body);
+
+    match err {
+        ServerError::MethodNotAllowed(allow) => resp.set_header("Allow", allow),
+        _ => resp,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::http::handler::{Dispatcher, RequestHandlerStrategy};
+    use crate::http::response::OK;
+
+    struct Echo;
+    impl RequestHandlerStrategy for Echo {
+        fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
+            Ok(Response::new(OK).with_body(req.path.clone()))
+        }
+    }
+
+    /// Appends its tag to a response header on the way out.
+    struct Tag(&'static str);
+    impl Middleware for Tag {
+        fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError> {
+            let resp = next.run(req)?;
+            let seen = resp.headers.get("X-Chain").unwrap_or_default().to_string();
+            Ok(resp.set_header("X-Chain", &format!("{}{}", seen, self.0)))
+        }
+    }
+
+    struct Deny;
+    impl Middleware for Deny {
+        fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError> {
+            match req.header("X-Token") {
+                Some(_) => next.run(req),
+                None => Err(ServerError::BadRequest("missing token".into())),
+            }
+        }
+    }
+
+    struct Rewrite;
+    impl Middleware for Rewrite {
+        fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError> {
+            if req.path == "/old" {
+                req.path = "/new".into();
+            }
+            next.run(req)
+        }
+    }
+
+    fn request(raw: &str) -> HttpRequest {
+        HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap()
+    }
+
+    fn dispatcher() -> Dispatcher {
+        Dispatcher::builder()
+            .get("/new", Arc::new(Echo))
+            .get("/private", Arc::new(Echo))
+            .wrap(Arc::new(Tag("a")))
+            .wrap(Arc::new(Rewrite))
+            .wrap(Arc::new(Tag("b")))
+            .wrap_route("/private", Arc::new(Deny))
+            .build()
+    }
+
+    #[test]
+    fn layers_run_in_order_and_can_rewrite_requests() {
+        let resp = dispatcher().dispatch(&mut request("GET /old HTTP/1.0\r\n\r\n")).unwrap();
+        assert_eq!(resp.body, b"/new");
+        assert_eq!(resp.headers.get("X-Chain"), Some("ba"));
+    }
+
+    #[test]
+    fn route_middleware_short_circuits_into_an_error_response() {
+        let d = dispatcher();
+
+        let denied = d.dispatch(&mut request("GET /private HTTP/1.0\r\n\r\n")).unwrap();
+        assert_eq!(denied.status.code, 400);
+        assert_eq!(denied.headers.get("X-Chain"), Some("ba"));
+
+        let allowed = d.dispatch(&mut request("GET /private HTTP/1.0\r\nX-Token: t\r\n\r\n")).unwrap();
+        assert_eq!(allowed.status.code, 200);
+
+        let missing = d.dispatch(&mut request("GET /nope HTTP/1.0\r\n\r\n")).unwrap();
+        assert_eq!(missing.status.code, 404);
+    }
+}
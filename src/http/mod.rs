@@ -1,12 +1,32 @@
 pub mod request;
+pub mod headers;
 pub mod response;
 pub mod handler;
+pub mod route;
+pub mod middleware;
+pub mod rate_limit;
+pub mod cors;
+pub mod compression;
+pub mod proxy;
+pub mod access_log;
 pub mod errors;
+pub mod chunked;
+pub mod upgrade;
+pub mod websocket;
+pub mod urlencoded;
+pub mod multipart;
+pub mod spool;
 pub mod server;
+pub mod connection_pool;
+pub mod listener;
+pub mod reactor;
+pub mod prefork;
 pub mod router {
     pub mod router;
     pub mod jobs;
     pub mod cpu_bound;
     pub mod io_bound;
     pub mod command;
+    pub mod files;
+    pub mod ws;
 }
\ No newline at end of file
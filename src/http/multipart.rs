@@ -0,0 +1,132 @@
+//! `multipart/form-data` parsing (RFC 7578).
+
+use crate::http::errors::ServerError;
+
+/// One body part. `data` borrows from the request body.
+#[derive(Debug)]
+pub struct Part<'a> {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: &'a [u8],
+}
+
+/// Extracts the boundary from a `multipart/form-data; boundary=...`
+/// Content-Type, or `None` if it is not a multipart type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim().trim_matches('"').to_string())
+        .filter(|b| !b.is_empty())
+}
+
+/// Splits `body` into its parts. Anything before the first delimiter
+/// (the preamble) and after the closing one (the epilogue) is ignored.
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, ServerError> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let bad = |msg: &str| ServerError::BadRequest(format!("Malformed multipart body: {}", msg));
+
+    let mut pos = find(body, &delimiter).ok_or_else(|| bad("missing boundary"))? + delimiter.len();
+    let mut parts = Vec::new();
+
+    // The delimiter inside a part is always preceded by CRLF
+    let mut next_delimiter = b"\r\n".to_vec();
+    next_delimiter.extend_from_slice(&delimiter);
+
+    loop {
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        // Transport padding may follow the delimiter before its CRLF
+        let line_end = find(rest, b"\r\n").ok_or_else(|| bad("missing CRLF after boundary"))?;
+        if !rest[..line_end].iter().all(|b| *b == b' ' || *b == b'\t') {
+            return Err(bad("unexpected data after boundary"));
+        }
+        pos += line_end + 2;
+
+        let rest = &body[pos..];
+        let (head_len, head) = if rest.starts_with(b"\r\n") {
+            (2, "")
+        } else {
+            let end = find(rest, b"\r\n\r\n").ok_or_else(|| bad("unterminated part headers"))?;
+            let head = std::str::from_utf8(&rest[..end]).map_err(|_| bad("part headers are not UTF-8"))?;
+            (end + 4, head)
+        };
+        pos += head_len;
+
+        let data_len = find(&body[pos..], &next_delimiter).ok_or_else(|| bad("missing closing boundary"))?;
+        let mut part = Part { name: None, filename: None, content_type: None, data: &body[pos..pos + data_len] };
+
+        for line in head.split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else { continue };
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                for param in value.split(';').skip(1) {
+                    if let Some((k, v)) = param.split_once('=') {
+                        let v = v.trim().trim_matches('"').to_string();
+                        match k.trim().to_ascii_lowercase().as_str() {
+                            "name" => part.name = Some(v),
+                            "filename" => part.filename = Some(v),
+                            _ => {}
+                        }
+                    }
+                }
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(value.trim().to_string());
+            }
+        }
+
+        parts.push(part);
+        pos += data_len + next_delimiter.len();
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_boundary() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc").as_deref(), Some("abc"));
+        assert_eq!(boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(boundary("application/octet-stream"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn parses_fields_and_files() {
+        let body = b"preamble\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+hello\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+Content-Type: text/plain\r\n\r\n\
+line1\r\nline2\r\n--XyZ--\r\nepilogue";
+
+        let parts = parse(body, "XyZ").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("note"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].data, b"hello");
+        assert_eq!(parts[1].filename.as_deref(), Some("a.txt"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(parts[1].data, b"line1\r\nline2");
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        assert!(parse(b"no boundary here", "XyZ").is_err());
+        assert!(parse(b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nabc", "XyZ").is_err());
+    }
+}
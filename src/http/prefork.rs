@@ -0,0 +1,232 @@
+//! Prefork mode: a master process forks worker processes that each run a
+//! complete server, and replaces any that die.
+//!
+//! The master opens the listeners and the workers inherit them, or, with
+//! `reuse_port`, every worker binds the TCP addresses itself with
+//! `SO_REUSEPORT` and the kernel spreads connections across them (Unix and
+//! inherited sockets are still shared). A crashing worker only takes its
+//! own connections down. SIGTERM/SIGINT to the master are forwarded to the
+//! workers, which drain as usual; SIGHUP is forwarded too.
+//!
+//! Forking a multi-threaded process copies only the calling thread, so
+//! `run` has to be called before anything starts threads; workers build
+//! their job pools and servers after the fork.
+
+use std::{
+    io,
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use libc::{c_int, pid_t};
+
+use crate::{
+    http::{
+        listener::{Listener, SharedListener},
+        reactor::set_nonblocking,
+        server::{close_listeners, open_listeners, ServerConfig},
+    },
+    utils::signal,
+};
+
+/// Workers that die sooner than this after starting are restarted only
+/// after `RESTART_BACKOFF`, so a worker that cannot start does not spin.
+const MIN_UPTIME: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const POLL: Duration = Duration::from_millis(100);
+/// Extra time workers get on top of their own drain deadline.
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct PreforkConfig {
+    pub workers: usize,
+    /// Each worker binds the TCP addresses itself with `SO_REUSEPORT`
+    /// instead of sharing the master's sockets.
+    pub reuse_port: bool,
+    /// How long workers may take to drain after a shutdown signal.
+    pub shutdown_timeout: Duration,
+}
+
+struct Slot {
+    pid: Option<pid_t>,
+    started: Instant,
+    restart_at: Option<Instant>,
+}
+
+/// Runs the master loop until a shutdown signal, forking `cfg.workers`
+/// processes that each call `worker` with their index (from 0), the
+/// configuration to serve and the listener shared with them, and exit with
+/// its return value. Returns the exit code for the master: 0 if every
+/// worker stopped cleanly, otherwise the worst worker status.
+pub fn run<F>(server_cfg: &ServerConfig, cfg: &PreforkConfig, worker: F) -> io::Result<i32>
+where
+    F: Fn(usize, ServerConfig, Option<SharedListener>) -> i32,
+{
+    let mut master_cfg = server_cfg.clone();
+    if cfg.reuse_port {
+        master_cfg.bind_addr.clear();
+    }
+    let listeners: Vec<Arc<dyn Listener>> = master_cfg.listeners()?;
+    let fds = if listeners.is_empty() { Vec::new() } else { open_listeners(&listeners)? };
+    // Every worker is woken for a new connection but only one gets it; the
+    // others must not block in accept()
+    for &fd in &fds {
+        set_nonblocking(fd)?;
+    }
+
+    let mut worker_cfg = server_cfg.clone();
+    worker_cfg.unix_socket = None;
+    worker_cfg.socket_activation = false;
+    if cfg.reuse_port {
+        worker_cfg.reuse_port = true;
+    } else {
+        worker_cfg.bind_addr.clear();
+    }
+    let shared = (!fds.is_empty()).then(|| SharedListener { fds: fds.clone() });
+
+    let master = process::id() as pid_t;
+    let spawn = |index: usize| -> io::Result<pid_t> {
+        let pid = unsafe { libc::fork() };
+        match pid {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                become_worker(master);
+                let shared = shared.as_ref().map(|s| SharedListener { fds: s.fds.clone() });
+                process::exit(worker(index, worker_cfg.clone(), shared));
+            }
+            pid => Ok(pid),
+        }
+    };
+
+    println!("⚙️  prefork mode: {} worker processes{}", cfg.workers, if cfg.reuse_port { " (SO_REUSEPORT)" } else { "" });
+    let worst = supervise(cfg.workers, cfg.shutdown_timeout + EXIT_GRACE, spawn, signal::shutdown_requested);
+
+    // The workers owned the connections; the master just stops listening
+    close_listeners(&fds, &[]);
+    for listener in &listeners {
+        listener.cleanup();
+    }
+    Ok(worst)
+}
+
+/// The master loop: keeps `workers` processes started by `spawn` running,
+/// restarting any that exits, until `stop` returns true. Then forwards
+/// SIGTERM, gives the workers `grace` to exit and kills the rest. Returns
+/// the worst worker exit code.
+fn supervise<S, P>(workers: usize, grace: Duration, mut spawn: S, stop: P) -> i32
+where
+    S: FnMut(usize) -> io::Result<pid_t>,
+    P: Fn() -> bool,
+{
+    let mut slots: Vec<Slot> = (0..workers.max(1))
+        .map(|_| Slot { pid: None, started: Instant::now(), restart_at: Some(Instant::now()) })
+        .collect();
+    let mut worst = 0;
+
+    while !stop() {
+        for (pid, status) in reap(&slots) {
+            let Some(index) = slots.iter().position(|s| s.pid == Some(pid)) else { continue };
+            let slot = &mut slots[index];
+            let backoff = if slot.started.elapsed() < MIN_UPTIME { RESTART_BACKOFF } else { Duration::ZERO };
+            eprintln!("[prefork] worker {} (pid {}) {}; restarting", index, pid, describe(status));
+            slot.pid = None;
+            slot.restart_at = Some(Instant::now() + backoff);
+        }
+
+        if signal::take_reopen_request() {
+            forward(&slots, libc::SIGHUP);
+        }
+
+        let now = Instant::now();
+        for (index, slot) in slots.iter_mut().enumerate() {
+            if slot.pid.is_some() || slot.restart_at.map(|at| at > now).unwrap_or(true) {
+                continue;
+            }
+            match spawn(index) {
+                Ok(pid) => {
+                    println!("[prefork] worker {} started (pid {})", index, pid);
+                    *slot = Slot { pid: Some(pid), started: now, restart_at: None };
+                }
+                Err(e) => {
+                    eprintln!("[prefork] fork failed: {}", e);
+                    slot.restart_at = Some(now + RESTART_BACKOFF);
+                }
+            }
+        }
+
+        thread::sleep(POLL);
+    }
+
+    forward(&slots, libc::SIGTERM);
+
+    let deadline = Instant::now() + grace;
+    while slots.iter().any(|s| s.pid.is_some()) && Instant::now() < deadline {
+        for (pid, status) in reap(&slots) {
+            if let Some(slot) = slots.iter_mut().find(|s| s.pid == Some(pid)) {
+                slot.pid = None;
+                worst = worst.max(exit_code(status));
+            }
+        }
+        thread::sleep(POLL);
+    }
+
+    for slot in slots.iter_mut().filter(|s| s.pid.is_some()) {
+        let pid = slot.pid.take().unwrap_or_default();
+        eprintln!("[prefork] worker pid {} did not stop in time; killing it", pid);
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+        worst = worst.max(2);
+    }
+    worst
+}
+
+/// Detaches a freshly forked worker from the master's terminal and ties its
+/// lifetime to the master's.
+fn become_worker(master: pid_t) {
+    unsafe {
+        // Ctrl-C then reaches the master alone, which forwards one SIGTERM;
+        // a worker seeing two signals would skip draining
+        libc::setpgid(0, 0);
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+    }
+    // The master may have died before the death signal was armed
+    if unsafe { libc::getppid() } != master {
+        process::exit(1);
+    }
+}
+
+/// Collects every worker that exited, with its wait status. Only the
+/// workers' own pids are waited for: any other child of the process (one
+/// the embedding program started, say) is left to whoever started it.
+fn reap(slots: &[Slot]) -> Vec<(pid_t, c_int)> {
+    let mut exited = Vec::new();
+    for pid in slots.iter().filter_map(|s| s.pid) {
+        let mut status: c_int = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == pid {
+            exited.push((pid, status));
+        }
+    }
+    exited
+}
+
+fn forward(slots: &[Slot], sig: c_int) {
+    for pid in slots.iter().filter_map(|s| s.pid) {
+        unsafe { libc::kill(pid, sig) };
+    }
+}
+
+fn describe(status: c_int) -> String {
+    if libc::WIFSIGNALED(status) {
+        format!("was killed by signal {}", libc::WTERMSIG(status))
+    } else {
+        format!("exited with code {}", libc::WEXITSTATUS(status))
+    }
+}
+
+fn exit_code(status: c_int) -> i32 {
+    if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 1 }
+}
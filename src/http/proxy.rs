@@ -0,0 +1,646 @@
+//! Reverse proxy: forwards requests under a path prefix to upstream servers.
+//!
+//! Every request opens a fresh connection to an upstream picked round-robin
+//! or by fewest requests in flight, and the response is streamed back as it
+//! arrives. An upstream that fails `max_fails` times within `fail_timeout`
+//! (refused connection, timeout, unreadable response) is skipped for
+//! `fail_timeout`; these are the passive health checks. A failed connect
+//! moves on to the next upstream, but a request that was sent is never
+//! retried since it may not be idempotent.
+
+use std::{
+    env,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::http::{
+    chunked::ChunkedReader,
+    errors::ServerError,
+    handler::{DispatcherBuilder, RequestHandlerStrategy},
+    headers::{self, Headers},
+    request::{is_timeout, read_line_limited, HttpMethod, HttpRequest},
+    response::{Response, Status},
+};
+
+/// Added to `Via` on requests and responses passing through.
+const VIA: &str = "1.1 rust-raw";
+const MAX_HEAD_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// Fields describing a single connection, which a proxy must not forward.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection", "keep-alive", "proxy-connection", "proxy-authorization",
+    "te", "trailer", "transfer-encoding", "upgrade",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    /// The upstream with the fewest requests in flight; ties go round-robin.
+    LeastConnections,
+}
+
+impl Balance {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "round_robin" | "roundrobin" | "rr" => Some(Balance::RoundRobin),
+            "least_conn" | "least_connections" => Some(Balance::LeastConnections),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub balance: Balance,
+    pub connect_timeout: Duration,
+    /// Bounds every read and write on the upstream connection.
+    pub timeout: Duration,
+    /// Failures within `fail_timeout` before an upstream is skipped; 0
+    /// disables health checks.
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+    /// Sends `/api/jobs` to the upstream as `/jobs` when the prefix is `/api`.
+    pub strip_prefix: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            balance: Balance::RoundRobin,
+            connect_timeout: Duration::from_secs(2),
+            timeout: Duration::from_secs(30),
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+            strip_prefix: false,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Reads `PROXY_BALANCE`, `PROXY_CONNECT_TIMEOUT`, `PROXY_TIMEOUT`,
+    /// `PROXY_MAX_FAILS`, `PROXY_FAIL_TIMEOUT` and `PROXY_STRIP_PREFIX`.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let secs = |name: &str, default: Duration| {
+            var(name)
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|s| *s > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+
+        Self {
+            balance: var("PROXY_BALANCE").and_then(|v| Balance::parse(&v)).unwrap_or(defaults.balance),
+            connect_timeout: secs("PROXY_CONNECT_TIMEOUT", defaults.connect_timeout),
+            timeout: secs("PROXY_TIMEOUT", defaults.timeout),
+            max_fails: var("PROXY_MAX_FAILS").and_then(|v| v.trim().parse().ok()).unwrap_or(defaults.max_fails),
+            fail_timeout: secs("PROXY_FAIL_TIMEOUT", defaults.fail_timeout),
+            strip_prefix: var("PROXY_STRIP_PREFIX")
+                .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(defaults.strip_prefix),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    fails: u32,
+    first_fail: Option<Instant>,
+    down_until: Option<Instant>,
+}
+
+struct Upstream {
+    /// `HOST:PORT`, resolved on every connect.
+    addr: String,
+    active: AtomicUsize,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    fn is_up(&self, now: Instant) -> bool {
+        let health = self.health.lock().unwrap();
+        health.down_until.map(|until| now >= until).unwrap_or(true)
+    }
+
+    fn record_failure(&self, cfg: &ProxyConfig) {
+        if cfg.max_fails == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut health = self.health.lock().unwrap();
+        if health.first_fail.map(|t| now.duration_since(t) > cfg.fail_timeout).unwrap_or(true) {
+            health.fails = 0;
+            health.first_fail = Some(now);
+        }
+        health.fails += 1;
+        if health.fails >= cfg.max_fails {
+            *health = Health { down_until: Some(now + cfg.fail_timeout), ..Health::default() };
+            eprintln!("[proxy] upstream {} marked down for {:?}", self.addr, cfg.fail_timeout);
+        }
+    }
+
+    fn record_success(&self) {
+        *self.health.lock().unwrap() = Health::default();
+    }
+}
+
+/// Counts a request against its upstream until the response body is done.
+struct InFlight(Arc<Upstream>);
+
+impl InFlight {
+    fn new(upstream: &Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(upstream))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A response body read from the upstream connection.
+struct UpstreamBody<R> {
+    inner: R,
+    _in_flight: InFlight,
+}
+
+impl<R: Read> Read for UpstreamBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// Forwards every request it handles to one of its upstreams.
+pub struct ProxyHandler {
+    prefix: String,
+    upstreams: Vec<Arc<Upstream>>,
+    cfg: ProxyConfig,
+    next: AtomicUsize,
+}
+
+impl ProxyHandler {
+    /// `prefix` is the path the handler is mounted at, e.g. `/api`.
+    pub fn new(prefix: &str, upstreams: &[String], cfg: ProxyConfig) -> Self {
+        let prefix = match prefix.trim().trim_end_matches('/') {
+            "" => "/".to_string(),
+            p if p.starts_with('/') => p.to_string(),
+            p => format!("/{}", p),
+        };
+        let upstreams = upstreams
+            .iter()
+            .map(|addr| Arc::new(Upstream { addr: addr.trim().to_string(), active: AtomicUsize::new(0), health: Mutex::default() }))
+            .collect();
+        Self { prefix, upstreams, cfg, next: AtomicUsize::new(0) }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Live upstreams in the order they should be tried.
+    fn candidates(&self) -> Vec<Arc<Upstream>> {
+        let now = Instant::now();
+        let mut live: Vec<Arc<Upstream>> = self.upstreams.iter().filter(|u| u.is_up(now)).cloned().collect();
+        if live.is_empty() {
+            return live;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % live.len();
+        live.rotate_left(start);
+        if self.cfg.balance == Balance::LeastConnections {
+            // The sort is stable, so ties keep the round-robin order
+            live.sort_by_key(|u| u.active.load(Ordering::SeqCst));
+        }
+        live
+    }
+
+    fn connect(&self, upstream: &Upstream) -> io::Result<TcpStream> {
+        let mut last = io::Error::new(ErrorKind::NotFound, format!("No address for '{}'", upstream.addr));
+        for addr in upstream.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.cfg.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    /// The request line and header block sent upstream.
+    fn request_head(&self, req: &HttpRequest, upstream: &Upstream) -> String {
+        let mut target = match req.path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) if self.cfg.strip_prefix && self.prefix != "/" => rest.to_string(),
+            _ => req.path.clone(),
+        };
+        if !target.starts_with('/') {
+            target.insert(0, '/');
+        }
+        if !req.query.is_empty() {
+            target.push('?');
+            target.push_str(&req.query);
+        }
+
+        let mut head = format!("{} {} HTTP/1.1\r\n", req.method.as_str(), target);
+        let mut field = |name: &str, value: &str| {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        };
+
+        let listed = connection_options(&req.headers);
+        for (name, value) in &req.headers {
+            let lower = name.to_ascii_lowercase();
+            // The body was already read, so nothing is left to `Expect`
+            let replaced = matches!(
+                lower.as_str(),
+                "content-length" | "expect" | "x-forwarded-for" | "x-forwarded-proto" | "x-forwarded-host" | "via"
+            );
+            if replaced || is_hop_by_hop(&lower, &listed) || !headers::is_valid_value(value) {
+                continue;
+            }
+            field(name, value);
+        }
+
+        if !req.headers.contains("Host") {
+            field("Host", &upstream.addr);
+        }
+        let peer = req.peer_addr.map(|a| a.ip().to_string());
+        let forwarded_for: Vec<&str> = req.headers.get_all("X-Forwarded-For").chain(peer.as_deref()).collect();
+        if !forwarded_for.is_empty() {
+            field("X-Forwarded-For", &forwarded_for.join(", "));
+        }
+        // Only the chain of addresses is kept; what the client claims about
+        // the scheme and host it used is replaced with what this hop saw
+        field("X-Forwarded-Proto", "http");
+        if let Some(host) = req.header("Host") {
+            field("X-Forwarded-Host", host);
+        }
+        let via: Vec<&str> = req.headers.get_all("Via").chain(Some(VIA)).collect();
+        field("Via", &via.join(", "));
+        if !req.body.is_empty() || matches!(req.method, HttpMethod::POST | HttpMethod::PUT | HttpMethod::PATCH) {
+            field("Content-Length", &req.body.len().to_string());
+        }
+        field("Connection", "close");
+
+        head.push_str("\r\n");
+        head
+    }
+
+    /// Sends `req` over `stream` and turns the upstream's answer into a
+    /// response whose body is read from the connection as it is sent on.
+    fn exchange(&self, stream: TcpStream, req: &HttpRequest, upstream: &Upstream, in_flight: InFlight) -> io::Result<Response> {
+        stream.set_read_timeout(Some(self.cfg.timeout))?;
+        stream.set_write_timeout(Some(self.cfg.timeout))?;
+        let _ = stream.set_nodelay(true);
+
+        let mut out = self.request_head(req, upstream).into_bytes();
+        out.extend_from_slice(&req.body);
+        (&stream).write_all(&out)?;
+
+        let mut reader = BufReader::new(stream);
+        let (status, fields) = loop {
+            let (status, fields) = read_head(&mut reader)?;
+            match status.code {
+                // Upgrade was not forwarded, so the upstream has no business switching
+                101 => return Err(invalid("Unexpected 101 from upstream")),
+                // Interim responses such as 100 Continue are not relayed
+                100..=199 => continue,
+                _ => break (status, fields),
+            }
+        };
+
+        let length = headers::content_length(fields.get_all("Content-Length")).map_err(|e| invalid(&e.to_string()))?;
+        let chunked = fields
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .last()
+            .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+
+        let mut resp = Response::new(status);
+        let listed = connection_options(&fields);
+        for (name, value) in &fields {
+            let lower = name.to_ascii_lowercase();
+            if lower != "content-length" && !is_hop_by_hop(&lower, &listed) {
+                resp.headers.append(name, value);
+            }
+        }
+        resp.headers.append("Via", VIA);
+
+        let bodiless = req.method == HttpMethod::HEAD || status.code == 204 || status.code == 304;
+        Ok(match length {
+            // Keep the length a HEAD answer announces for the real body
+            Some(len) if req.method == HttpMethod::HEAD => resp.with_stream_len(io::empty(), len as u64),
+            _ if bodiless => resp,
+            _ if chunked => resp.with_stream(UpstreamBody { inner: ChunkedReader::new(reader), _in_flight: in_flight }),
+            Some(len) => resp.with_stream_len(UpstreamBody { inner: reader, _in_flight: in_flight }, len as u64),
+            // Delimited by the upstream closing the connection
+            None => resp.with_stream(UpstreamBody { inner: reader, _in_flight: in_flight }),
+        })
+    }
+}
+
+impl RequestHandlerStrategy for ProxyHandler {
+    fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
+        let candidates = self.candidates();
+        if candidates.is_empty() {
+            return Err(ServerError::BadGateway("No live upstream".into()));
+        }
+
+        for upstream in candidates {
+            let in_flight = InFlight::new(&upstream);
+            let stream = match self.connect(&upstream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("[proxy] connect to {} failed: {}", upstream.addr, e);
+                    upstream.record_failure(&self.cfg);
+                    continue;
+                }
+            };
+
+            return match self.exchange(stream, req, &upstream, in_flight) {
+                Ok(resp) => {
+                    upstream.record_success();
+                    Ok(resp)
+                }
+                Err(e) => {
+                    eprintln!("[proxy] {} {} via {} failed: {}", req.method.as_str(), req.path, upstream.addr, e);
+                    upstream.record_failure(&self.cfg);
+                    if is_timeout(&e) {
+                        Err(ServerError::GatewayTimeout)
+                    } else {
+                        Err(ServerError::BadGateway("Invalid upstream response".into()))
+                    }
+                }
+            };
+        }
+
+        Err(ServerError::BadGateway("No upstream reachable".into()))
+    }
+}
+
+/// Reads a status line and header block.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(Status, Headers)> {
+    let line = read_line_limited(reader, MAX_HEAD_LINE)?.ok_or_else(|| invalid("Status line too long"))?;
+    if line.is_empty() {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "Upstream closed the connection"));
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status = parts.next().and_then(|code| code.parse::<u16>().ok()).and_then(Status::from_code);
+    let status = match status {
+        Some(status) if version.starts_with("HTTP/1.") => status,
+        _ => return Err(invalid(&format!("Malformed status line: '{}'", line))),
+    };
+
+    let mut fields = Headers::new();
+    loop {
+        let line = read_line_limited(reader, MAX_HEAD_LINE)?.ok_or_else(|| invalid("Header line too long"))?;
+        if line.is_empty() {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Upstream closed the connection mid-header"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return Ok((status, fields));
+        }
+        if fields.len() >= MAX_HEADERS {
+            return Err(invalid("Too many header fields"));
+        }
+        match line.split_once(':') {
+            Some((name, value)) if headers::is_valid_name(name) && headers::is_valid_value(value) => {
+                fields.append(name, value.trim());
+            }
+            _ => return Err(invalid(&format!("Invalid header line: '{}'", line))),
+        }
+    }
+}
+
+/// Field names listed in `Connection`, which are hop-by-hop as well.
+fn connection_options(fields: &Headers) -> Vec<String> {
+    fields
+        .get_all("Connection")
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+fn is_hop_by_hop(lower_name: &str, listed: &[String]) -> bool {
+    HOP_BY_HOP.contains(&lower_name) || listed.iter().any(|t| t == lower_name)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Parses `PROXY_ROUTES`: `;`-separated `PREFIX=UPSTREAM,UPSTREAM` entries,
+/// e.g. `/api=127.0.0.1:9001,127.0.0.1:9002;/legacy=10.0.0.5:8080`.
+pub fn parse_routes(spec: &str) -> Vec<(String, Vec<String>)> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = entry.split_once('=').map(|(prefix, upstreams)| {
+                let upstreams: Vec<String> =
+                    upstreams.split(',').map(str::trim).filter(|u| !u.is_empty()).map(String::from).collect();
+                (prefix.trim().to_string(), upstreams)
+            });
+            match parsed {
+                Some((prefix, upstreams)) if !prefix.is_empty() && !upstreams.is_empty() => Some((prefix, upstreams)),
+                _ => {
+                    eprintln!("[proxy] ignoring route '{}': expected PREFIX=HOST:PORT[,HOST:PORT...]", entry);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Mounts `handler` at its prefix and everything below it, for every method.
+pub fn mount(mut builder: DispatcherBuilder, handler: ProxyHandler) -> DispatcherBuilder {
+    let handler = Arc::new(handler);
+    let below = match handler.prefix() {
+        "/" => "/*".to_string(),
+        prefix => format!("{}/*", prefix),
+    };
+    let paths = [handler.prefix().to_string(), below];
+
+    for method in [HttpMethod::GET, HttpMethod::POST, HttpMethod::PUT, HttpMethod::DELETE, HttpMethod::PATCH, HttpMethod::OPTIONS] {
+        for path in &paths {
+            builder = builder.route(method.clone(), path, handler.clone());
+        }
+    }
+    builder
+}
+
+/// Mounts a `ProxyHandler` for every `PROXY_ROUTES` entry.
+pub fn register(mut builder: DispatcherBuilder) -> DispatcherBuilder {
+    let routes = parse_routes(&env::var("PROXY_ROUTES").unwrap_or_default());
+    let cfg = ProxyConfig::from_env();
+    for (prefix, upstreams) in routes {
+        let handler = ProxyHandler::new(&prefix, &upstreams, cfg.clone());
+        println!("[proxy] {} -> {} ({:?})", handler.prefix(), upstreams.join(", "), cfg.balance);
+        builder = mount(builder, handler);
+    }
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Serves `reply` to every connection and reports the request it got.
+    fn upstream(reply: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = v.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8_lossy(&body));
+                let _ = tx.send(request);
+                let _ = stream.write_all(reply.as_bytes());
+            }
+        });
+        (addr, rx)
+    }
+
+    fn request(raw: &str) -> HttpRequest {
+        let mut req = HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap();
+        req.peer_addr = Some("192.0.2.7:5555".parse().unwrap());
+        req
+    }
+
+    fn body(resp: Response) -> String {
+        let mut body = String::new();
+        resp.stream.unwrap().reader.read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn forwards_requests_and_streams_responses_back() {
+        let (addr, seen) = upstream(
+            "HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nKeep-Alive: timeout=5\r\n\
+             Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        );
+        let cfg = ProxyConfig { strip_prefix: true, ..ProxyConfig::default() };
+        let proxy = ProxyHandler::new("/api/", &[addr], cfg);
+
+        let req = request(
+            "POST /api/echo?x=1 HTTP/1.1\r\nHost: front\r\nConnection: keep-alive\r\n\
+             X-Forwarded-For: 10.0.0.1\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: evil\r\n\
+             Content-Length: 4\r\n\r\nping",
+        );
+        let resp = proxy.handle(&req).unwrap();
+
+        let sent = seen.recv().unwrap();
+        assert!(sent.starts_with("POST /echo?x=1 HTTP/1.1\r\n"), "{}", sent);
+        assert!(sent.contains("Host: front\r\n"));
+        assert!(sent.contains("X-Forwarded-For: 10.0.0.1, 192.0.2.7\r\n"));
+        assert!(sent.contains("X-Forwarded-Proto: http\r\n") && !sent.contains("https"));
+        assert!(sent.contains("X-Forwarded-Host: front\r\n") && !sent.contains("evil"));
+        assert!(sent.contains("Via: 1.1 rust-raw\r\n"));
+        assert!(sent.contains("Connection: close\r\n"));
+        assert!(!sent.contains("keep-alive"));
+        assert!(sent.ends_with("\r\nping"));
+
+        assert_eq!(resp.status.code, 201);
+        assert_eq!(resp.headers.get("Via"), Some("1.1 rust-raw"));
+        assert!(!resp.headers.contains("Keep-Alive") && !resp.headers.contains("Transfer-Encoding"));
+        assert_eq!(body(resp), "hello");
+    }
+
+    #[test]
+    fn balances_round_robin_and_by_least_connections() {
+        let (a, _) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nA");
+        let (b, _) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nB");
+        let req = request("GET /x HTTP/1.1\r\nHost: front\r\n\r\n");
+
+        let proxy = ProxyHandler::new("/", &[a.clone(), b.clone()], ProxyConfig::default());
+        let order: String = (0..4).map(|_| body(proxy.handle(&req).unwrap())).collect();
+        assert!(order == "ABAB" || order == "BABA", "{}", order);
+
+        let cfg = ProxyConfig { balance: Balance::LeastConnections, ..ProxyConfig::default() };
+        let proxy = ProxyHandler::new("/", &[a, b], cfg);
+        // An unread body keeps its request in flight
+        let held = proxy.handle(&req).unwrap();
+        let next: Vec<String> = (0..2).map(|_| body(proxy.handle(&req).unwrap())).collect();
+        assert_eq!(next[0], next[1]);
+        assert_ne!(body(held), next[0]);
+    }
+
+    #[test]
+    fn skips_failing_upstreams() {
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let (live, _) = upstream("HTTP/1.1 204 No Content\r\n\r\n");
+        let cfg = ProxyConfig { max_fails: 2, fail_timeout: Duration::from_secs(60), ..ProxyConfig::default() };
+        let req = request("GET /x HTTP/1.1\r\nHost: front\r\n\r\n");
+
+        let proxy = ProxyHandler::new("/", &[dead.clone(), live], cfg.clone());
+        for _ in 0..4 {
+            assert_eq!(proxy.handle(&req).unwrap().status.code, 204);
+        }
+        assert!(!proxy.upstreams[0].is_up(Instant::now()));
+        assert!(proxy.upstreams[1].is_up(Instant::now()));
+
+        let proxy = ProxyHandler::new("/", &[dead], cfg);
+        assert!(matches!(proxy.handle(&req), Err(ServerError::BadGateway(_))));
+        assert!(matches!(proxy.handle(&req), Err(ServerError::BadGateway(_))));
+        assert!(matches!(proxy.handle(&req), Err(ServerError::BadGateway(msg)) if msg == "No live upstream"));
+    }
+
+    #[test]
+    fn times_out_on_a_silent_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(2));
+        });
+
+        let cfg = ProxyConfig { timeout: Duration::from_millis(200), ..ProxyConfig::default() };
+        let proxy = ProxyHandler::new("/", &[addr], cfg);
+        let req = request("GET /slow HTTP/1.1\r\nHost: front\r\n\r\n");
+        assert!(matches!(proxy.handle(&req), Err(ServerError::GatewayTimeout)));
+    }
+
+    #[test]
+    fn parses_route_specs() {
+        let routes = parse_routes("/api=127.0.0.1:9001, 127.0.0.1:9002; /legacy=[::1]:80;broken;/empty=");
+        assert_eq!(routes, [
+            ("/api".to_string(), vec!["127.0.0.1:9001".to_string(), "127.0.0.1:9002".to_string()]),
+            ("/legacy".to_string(), vec!["[::1]:80".to_string()]),
+        ]);
+    }
+}
@@ -0,0 +1,388 @@
+//! Per-client rate limiting with token buckets.
+//!
+//! Every client gets a bucket holding up to `burst` tokens, refilled at
+//! `rate_per_sec`. A request takes one token; an empty bucket answers 429
+//! with `Retry-After`. Clients are told apart by peer IP, or by an API key
+//! header carrying one of the configured keys.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::http::{
+    errors::ServerError,
+    middleware::{error_response, Middleware, Next},
+    request::HttpRequest,
+    response::Response,
+};
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8` or `::1/128`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parses `ADDR/PREFIX`, or a bare address as a single-host network.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (s.trim().parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Tokens added per second; 0 disables the limiter.
+    pub rate_per_sec: f64,
+    /// Bucket size, i.e. how many requests may arrive at once.
+    pub burst: u32,
+    /// Header carrying an API key, e.g. `X-API-Key`. Requests that send one
+    /// of `keys` in it are limited per key rather than per IP.
+    pub key_header: Option<String>,
+    /// The API keys that get a bucket of their own. Any other value of
+    /// `key_header` is ignored, so clients cannot mint fresh buckets.
+    pub keys: HashSet<String>,
+    /// Peers in these networks are never limited.
+    pub exempt: Vec<Cidr>,
+    /// Clients on the Unix socket, which have no address, are never
+    /// limited. Otherwise they all share one bucket.
+    pub exempt_unix: bool,
+    /// Buckets unused for this long are dropped.
+    pub idle_timeout: Duration,
+    /// Most buckets kept at once. A new client past it evicts idle buckets,
+    /// then the least recently used ones.
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate_per_sec: 200.0,
+            burst: 200,
+            key_header: None,
+            keys: HashSet::new(),
+            exempt: Vec::new(),
+            exempt_unix: false,
+            idle_timeout: Duration::from_secs(300),
+            max_clients: 100_000,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Outcome of taking a token, with the values for the response headers.
+#[derive(Debug, PartialEq)]
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+    /// Seconds until the next token, when denied.
+    retry_after: u64,
+}
+
+pub struct RateLimiter {
+    cfg: RateLimitConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: RateLimitConfig) -> Self {
+        Self { cfg, buckets: Mutex::new(HashMap::new()), last_sweep: Mutex::new(Instant::now()) }
+    }
+
+    /// Reads `RATE_LIMIT_PER_SEC`, `RATE_LIMIT_BURST`, `RATE_LIMIT_KEY_HEADER`,
+    /// `RATE_LIMIT_KEYS` (comma-separated), `RATE_LIMIT_EXEMPT`
+    /// (comma-separated CIDRs, and `unix` for the Unix socket),
+    /// `RATE_LIMIT_IDLE_SECS` and
+    /// `RATE_LIMIT_MAX_CLIENTS`.
+    pub fn from_env() -> Self {
+        let defaults = RateLimitConfig::default();
+        let rate_per_sec = env::var("RATE_LIMIT_PER_SEC").ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .unwrap_or(defaults.rate_per_sec);
+        let burst = env::var("RATE_LIMIT_BURST").ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(rate_per_sec.ceil() as u32);
+        let key_header = env::var("RATE_LIMIT_KEY_HEADER").ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        let keys: HashSet<String> = env::var("RATE_LIMIT_KEYS").unwrap_or_default()
+            .split(',')
+            .map(|k| k.trim().to_string())
+            .filter(|k| !k.is_empty())
+            .collect();
+        if key_header.is_some() && keys.is_empty() {
+            eprintln!("RATE_LIMIT_KEY_HEADER is set but RATE_LIMIT_KEYS is empty: limiting by IP only");
+        }
+        let exempt_list = env::var("RATE_LIMIT_EXEMPT").unwrap_or_default();
+        let exempt_unix = exempt_list.split(',').any(|s| s.trim().eq_ignore_ascii_case("unix"));
+        let exempt = exempt_list
+            .split(',')
+            .filter(|s| !s.trim().is_empty() && !s.trim().eq_ignore_ascii_case("unix"))
+            .filter_map(|s| Cidr::parse(s).or_else(|| {
+                eprintln!("Ignoring invalid RATE_LIMIT_EXEMPT entry '{}'", s.trim());
+                None
+            }))
+            .collect();
+        let idle_timeout = env::var("RATE_LIMIT_IDLE_SECS").ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.idle_timeout);
+        let max_clients = env::var("RATE_LIMIT_MAX_CLIENTS").ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(defaults.max_clients);
+
+        Self::new(RateLimitConfig {
+            rate_per_sec,
+            burst: burst.max(1),
+            key_header,
+            keys,
+            exempt,
+            exempt_unix,
+            idle_timeout,
+            max_clients: max_clients.max(1),
+        })
+    }
+
+    /// `None` when the request is not subject to limiting.
+    fn client_key(&self, req: &HttpRequest) -> Option<String> {
+        let exempt = match req.peer_addr {
+            Some(addr) => self.cfg.exempt.iter().any(|net| net.contains(addr.ip())),
+            None => self.cfg.exempt_unix,
+        };
+        if exempt {
+            return None;
+        }
+
+        let api_key = self.cfg.key_header.as_deref()
+            .and_then(|h| req.header(h))
+            .filter(|key| self.cfg.keys.contains(*key));
+        Some(match (api_key, req.peer_addr) {
+            (Some(key), _) => format!("key:{}", key),
+            (None, Some(addr)) => format!("ip:{}", addr.ip()),
+            (None, None) => "unix".to_string(),
+        })
+    }
+
+    fn take(&self, key: &str, now: Instant) -> Decision {
+        self.sweep(now);
+
+        let burst = self.cfg.burst as f64;
+        let rate = self.cfg.rate_per_sec;
+        let mut buckets = self.buckets.lock().expect("rate limiter mutex");
+        if buckets.len() >= self.cfg.max_clients && !buckets.contains_key(key) {
+            self.make_room(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: burst, updated: now });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((burst - bucket.tokens) / rate).ceil() as u64,
+            retry_after: if allowed { 0 } else { ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64 },
+        }
+    }
+
+    /// Drops idle buckets, at most once per `idle_timeout`.
+    fn sweep(&self, now: Instant) {
+        let mut last = self.last_sweep.lock().expect("rate limiter mutex");
+        if now.saturating_duration_since(*last) < self.cfg.idle_timeout {
+            return;
+        }
+        *last = now;
+
+        let idle = self.cfg.idle_timeout;
+        self.buckets
+            .lock()
+            .expect("rate limiter mutex")
+            .retain(|_, b| now.saturating_duration_since(b.updated) < idle);
+    }
+}
+
+impl RateLimiter {
+    /// Frees at least one slot in a full map: idle buckets go first, then
+    /// the least recently used eighth, so a flood of new clients pays for
+    /// the scan once every few inserts rather than on each one.
+    fn make_room(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let idle = self.cfg.idle_timeout;
+        buckets.retain(|_, b| now.saturating_duration_since(b.updated) < idle);
+        if buckets.len() < self.cfg.max_clients {
+            return;
+        }
+
+        let mut used: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+        let cut = used.len() / 8;
+        let oldest_kept = *used.select_nth_unstable(cut).1;
+        buckets.retain(|_, b| b.updated > oldest_kept);
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn handle(&self, req: &mut HttpRequest, next: Next<'_>) -> Result<Response, ServerError> {
+        if self.cfg.rate_per_sec <= 0.0 {
+            return next.run(req);
+        }
+        let Some(key) = self.client_key(req) else { return next.run(req) };
+
+        let decision = self.take(&key, Instant::now());
+        let resp = if decision.allowed {
+            next.run(req)?
+        } else {
+            error_response(&ServerError::TooManyRequests)
+                .set_header("Retry-After", &decision.retry_after.to_string())
+        };
+
+        Ok(resp
+            .set_header("X-RateLimit-Limit", &self.cfg.burst.to_string())
+            .set_header("X-RateLimit-Remaining", &decision.remaining.to_string())
+            .set_header("X-RateLimit-Reset", &decision.reset.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate_per_sec: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig { rate_per_sec, burst, ..RateLimitConfig::default() })
+    }
+
+    #[test]
+    fn buckets_drain_and_refill_per_client() {
+        let l = limiter(2.0, 3);
+        let t0 = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            let d = l.take("ip:a", t0);
+            assert!(d.allowed);
+            assert_eq!(d.remaining, remaining);
+        }
+        let denied = l.take("ip:a", t0);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, 1);
+        assert_eq!(denied.reset, 2);
+
+        // Other clients have their own bucket
+        assert!(l.take("ip:b", t0).allowed);
+
+        assert!(l.take("ip:a", t0 + Duration::from_millis(500)).allowed);
+        assert!(!l.take("ip:a", t0 + Duration::from_millis(500)).allowed);
+    }
+
+    #[test]
+    fn evicts_idle_buckets() {
+        let l = RateLimiter::new(RateLimitConfig { idle_timeout: Duration::from_secs(10), ..RateLimitConfig::default() });
+        let t0 = Instant::now();
+        l.take("ip:a", t0);
+        l.take("ip:b", t0 + Duration::from_secs(8));
+        l.take("ip:b", t0 + Duration::from_secs(12));
+        let buckets = l.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("ip:a"));
+        assert!(buckets.contains_key("ip:b"));
+    }
+
+    #[test]
+    fn matches_cidrs() {
+        let lan = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(lan.contains("10.1.200.3".parse().unwrap()));
+        assert!(lan.contains("::ffff:10.1.0.9".parse().unwrap()));
+        assert!(!lan.contains("10.2.0.1".parse().unwrap()));
+
+        let host = Cidr::parse("::1").unwrap();
+        assert!(host.contains("::1".parse().unwrap()));
+        assert!(!host.contains("::2".parse().unwrap()));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("203.0.113.5".parse().unwrap()));
+        assert_eq!(Cidr::parse("10.0.0.0/33"), None);
+        assert_eq!(Cidr::parse("nope"), None);
+    }
+
+    #[test]
+    fn only_configured_api_keys_get_their_own_bucket() {
+        let l = RateLimiter::new(RateLimitConfig {
+            key_header: Some("X-API-Key".into()),
+            keys: HashSet::from(["team-a".to_string()]),
+            ..RateLimitConfig::default()
+        });
+        let key = |key: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nHost: x\r\nX-API-Key: {}\r\n\r\n", key);
+            let mut req = HttpRequest::parse(&mut std::io::Cursor::new(raw.into_bytes())).unwrap();
+            req.peer_addr = Some("192.0.2.7:5000".parse().unwrap());
+            l.client_key(&req)
+        };
+
+        assert_eq!(key("team-a").as_deref(), Some("key:team-a"));
+        // A made-up key per request still lands in the IP's bucket
+        assert_eq!(key("random-1").as_deref(), Some("ip:192.0.2.7"));
+        assert_eq!(key("random-2").as_deref(), Some("ip:192.0.2.7"));
+    }
+
+    #[test]
+    fn unix_socket_clients_share_a_bucket_unless_exempt() {
+        let req = HttpRequest::parse(&mut std::io::Cursor::new(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".to_vec())).unwrap();
+        assert_eq!(req.peer_addr, None);
+
+        let limited = RateLimiter::new(RateLimitConfig::default());
+        assert_eq!(limited.client_key(&req).as_deref(), Some("unix"));
+        let exempt = RateLimiter::new(RateLimitConfig { exempt_unix: true, ..RateLimitConfig::default() });
+        assert_eq!(exempt.client_key(&req), None);
+    }
+
+    #[test]
+    fn caps_the_number_of_buckets() {
+        let l = RateLimiter::new(RateLimitConfig { max_clients: 16, ..RateLimitConfig::default() });
+        let t0 = Instant::now();
+        for i in 0..100 {
+            l.take(&format!("ip:{}", i), t0 + Duration::from_millis(i));
+        }
+
+        let buckets = l.buckets.lock().unwrap();
+        assert!(buckets.len() <= 16);
+        // The most recent clients are the ones kept
+        assert!(buckets.contains_key("ip:99"));
+        assert!(!buckets.contains_key("ip:0"));
+    }
+}
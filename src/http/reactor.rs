@@ -0,0 +1,1201 @@
+//! Event-driven connection handling built on epoll.
+//!
+//! A small, fixed set of I/O threads owns every client socket. Each one runs
+//! its own epoll loop: it accepts connections, reads until a complete request
+//! is buffered, and hands the parsed request to a shared pool of handler
+//! threads. Handlers send the serialized response back over a channel and
+//! wake the owning I/O thread through an eventfd; the I/O thread then writes
+//! it out without blocking.
+//!
+//! Connections that switch protocols, and requests with a body larger than
+//! `max_body_bytes` for a route that accepts one, leave the event loop for a
+//! thread of their own with blocking I/O.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, Cursor, Read, Write, ErrorKind},
+    net::SocketAddr,
+    os::fd::{FromRawFd, RawFd},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc,
+        Condvar,
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use libc::{c_int, c_void};
+
+use crate::utils::signal;
+use crate::http::{
+    access_log::{AccessLog, LogEntry},
+    chunked,
+    errors::ServerError,
+    headers,
+    listener,
+    handler::Dispatcher,
+    middleware::error_response,
+    request::{HttpRequest, RequestLimits},
+    response::SERVICE_UNAVAILABLE,
+    server::{self, AfterResponse, ConnectionLimits, HttpServer},
+    upgrade::{Upgrade, Upgraded},
+};
+
+const WAKE_TOKEN: u64 = u64::MAX;
+/// Listener `i` is registered under `LISTEN_TOKEN_BASE + i`.
+const LISTEN_TOKEN_BASE: u64 = u64::MAX - 1024;
+const MAX_EVENTS: usize = 256;
+const READ_CHUNK: usize = 16 * 1024;
+/// Unsent response bytes past which a handler producing output is paused
+/// until the client catches up.
+const HIGH_WATER: usize = 256 * 1024;
+
+/// A parsed request travelling from an I/O thread to the handler pool.
+struct Task {
+    conn_id: u64,
+    request: HttpRequest,
+    allow_keep_alive: bool,
+    reply: Replier,
+    closed: Arc<AtomicBool>,
+    backlog: Arc<Backlog>,
+}
+
+/// Output sent back from a handler thread to the owning I/O thread.
+enum Output {
+    Data(u64, Vec<u8>),
+    Done(u64, AfterResponse),
+}
+
+/// Sending half of an I/O thread's output channel plus its wake-up fd.
+#[derive(Clone)]
+struct Replier {
+    tx: Sender<Output>,
+    wake_fd: RawFd,
+}
+
+impl Replier {
+    fn send(&self, out: Output) {
+        if self.tx.send(out).is_ok() {
+            let one: u64 = 1;
+            unsafe { libc::write(self.wake_fd, (&one as *const u64).cast::<c_void>(), 8) };
+        }
+    }
+}
+
+/// Response bytes a handler has handed to the I/O thread that are not on
+/// the socket yet.
+#[derive(Default)]
+struct Backlog {
+    bytes: Mutex<usize>,
+    drained: Condvar,
+}
+
+impl Backlog {
+    fn add(&self, n: usize) {
+        *self.bytes.lock().unwrap() += n;
+    }
+
+    fn sent(&self, n: usize) {
+        let mut bytes = self.bytes.lock().unwrap();
+        *bytes = bytes.saturating_sub(n);
+        if *bytes <= HIGH_WATER {
+            self.drained.notify_all();
+        }
+    }
+
+    /// Blocks while more than `HIGH_WATER` bytes are pending, unless the
+    /// connection goes away.
+    fn wait(&self, closed: &AtomicBool) {
+        let mut bytes = self.bytes.lock().unwrap();
+        while *bytes > HIGH_WATER && !closed.load(Ordering::Acquire) {
+            bytes = self.drained.wait_timeout(bytes, Duration::from_millis(100)).unwrap().0;
+        }
+    }
+}
+
+/// `Write` adapter handed to `Response::write_to` on handler threads. Bytes
+/// are batched locally and forwarded to the I/O thread on every flush, which
+/// keeps streamed bodies incremental. A flush blocks while the client is
+/// too far behind, so a slow reader slows the handler down instead of
+/// piling its output up in memory. Fails once the I/O thread has closed the
+/// connection, so long-lived streams stop producing.
+struct ReplyWriter {
+    conn_id: u64,
+    reply: Replier,
+    buf: Vec<u8>,
+    closed: Arc<AtomicBool>,
+    backlog: Arc<Backlog>,
+    /// Whether any bytes were forwarded yet.
+    started: bool,
+}
+
+impl ReplyWriter {
+    fn check_open(&self) -> io::Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            Err(io::Error::new(ErrorKind::BrokenPipe, "Connection closed"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Write for ReplyWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.check_open()?;
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= HIGH_WATER {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_open()?;
+        if !self.buf.is_empty() {
+            let data = std::mem::take(&mut self.buf);
+            self.backlog.add(data.len());
+            self.started = true;
+            self.reply.send(Output::Data(self.conn_id, data));
+            self.backlog.wait(&self.closed);
+        }
+        self.check_open()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ConnState {
+    /// Accumulating bytes for the next request.
+    Reading,
+    /// A request is with the handler pool.
+    Waiting,
+    /// Nothing more will be read; close once the output is flushed.
+    Closing,
+}
+
+struct Conn {
+    fd: RawFd,
+    state: ConnState,
+    inbuf: Vec<u8>,
+    outbuf: Vec<u8>,
+    out_pos: usize,
+    /// Set once the handler finished; holds the keep-alive decision.
+    done: Option<bool>,
+    served: usize,
+    last_active: Instant,
+    interest: u32,
+    /// The peer shut down its sending side.
+    eof: bool,
+    peer: Option<SocketAddr>,
+    /// Shared with the handler serving the current request.
+    closed: Arc<AtomicBool>,
+    backlog: Arc<Backlog>,
+    /// Takes over the socket once the 101 response is flushed.
+    upgrade: Option<Upgrade>,
+}
+
+struct Shared {
+    dispatcher: Arc<Dispatcher>,
+    active: Arc<AtomicUsize>,
+    max_connections: usize,
+    limits: ConnectionLimits,
+    shutdown_timeout: Duration,
+    access_log: Option<Arc<AccessLog>>,
+    /// Connections handed over to a thread of their own and still running.
+    handed_over: AtomicUsize,
+}
+
+impl Shared {
+    /// Called once a handed-over connection is closed.
+    fn release(&self) {
+        self.handed_over.fetch_sub(1, Ordering::SeqCst);
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn run(server: &HttpServer, listen_fds: &[RawFd], access_log: Option<Arc<AccessLog>>) -> io::Result<()> {
+    for &fd in listen_fds {
+        set_nonblocking(fd)?;
+    }
+
+    let shared = Arc::new(Shared {
+        dispatcher: Arc::clone(&server.dispatcher),
+        active: Arc::clone(&server.active),
+        max_connections: server.cfg.max_connections,
+        limits: server.limits(),
+        shutdown_timeout: server.cfg.shutdown_timeout,
+        access_log: access_log.clone(),
+        handed_over: AtomicUsize::new(0),
+    });
+
+    let (task_tx, task_rx) = mpsc::channel::<Task>();
+    let task_rx = Arc::new(Mutex::new(task_rx));
+
+    for _ in 0..server.cfg.handler_threads.max(1) {
+        let task_rx = Arc::clone(&task_rx);
+        let dispatcher = Arc::clone(&shared.dispatcher);
+        let access_log = access_log.clone();
+        thread::spawn(move || handler_loop(task_rx, dispatcher, access_log));
+    }
+
+    println!(
+        "⚙️  epoll mode: {} I/O threads, {} handler threads",
+        server.cfg.io_threads.max(1),
+        server.cfg.handler_threads.max(1)
+    );
+
+    let mut io_threads = Vec::new();
+    for _ in 0..server.cfg.io_threads.max(1) {
+        let mut io = IoThread::new(listen_fds, Arc::clone(&shared), task_tx.clone())?;
+        io_threads.push(thread::spawn(move || io.run()));
+    }
+
+    for t in io_threads {
+        match t.join() {
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(io::Error::other("I/O thread panicked")),
+            Ok(Ok(())) => {}
+        }
+    }
+    Ok(())
+}
+
+fn handler_loop(tasks: Arc<Mutex<Receiver<Task>>>, dispatcher: Arc<Dispatcher>, access_log: Option<Arc<AccessLog>>) {
+    loop {
+        let Task { conn_id, mut request, allow_keep_alive, reply, closed, backlog } = {
+            let rx = tasks.lock().expect("task queue mutex");
+            match rx.recv() {
+                Ok(t) => t,
+                Err(_) => return,
+            }
+        };
+
+        let mut writer = ReplyWriter { conn_id, reply, buf: Vec::new(), closed, backlog, started: false };
+        let started = Instant::now();
+        // A panicking handler must not take the thread down with it
+        let responded = panic::catch_unwind(AssertUnwindSafe(|| {
+            server::respond(&dispatcher, &mut request, allow_keep_alive)
+        }));
+        let Ok((resp, keep_alive)) = responded else {
+            let after = panicked(&mut writer, &request.path);
+            finish(writer, after);
+            continue;
+        };
+
+        let long_lived = resp.long_lived;
+        let access_log = access_log.clone();
+        let send = move || {
+            let sent = panic::catch_unwind(AssertUnwindSafe(|| {
+                server::send_response(resp, keep_alive, &request, &mut writer, access_log.as_deref(), started)
+            }));
+            let after = sent.unwrap_or_else(|_| panicked(&mut writer, &request.path));
+            finish(writer, after);
+        };
+        // Such a stream would hold this handler thread until it ends
+        if long_lived {
+            thread::spawn(send);
+        } else {
+            send();
+        }
+    }
+}
+
+/// Answers 500 in place of whatever was not sent yet.
+fn panicked(writer: &mut ReplyWriter, path: &str) -> AfterResponse {
+    eprintln!("Handler panicked serving {}", path);
+    writer.buf.clear();
+    if !writer.started {
+        let _ = writer.write_all(&error_response(&ServerError::Internal("handler panicked".into())).to_bytes(false));
+    }
+    AfterResponse::Close
+}
+
+/// Hands the rest of the response to the I/O thread, then the connection.
+fn finish(mut writer: ReplyWriter, after: AfterResponse) {
+    let _ = writer.flush();
+    writer.reply.send(Output::Done(writer.conn_id, after));
+}
+
+struct IoThread {
+    epfd: RawFd,
+    listen_fds: Vec<RawFd>,
+    wake_fd: RawFd,
+    shared: Arc<Shared>,
+    tasks: Sender<Task>,
+    out_tx: Sender<Output>,
+    out_rx: Receiver<Output>,
+    conns: HashMap<u64, Conn>,
+    next_id: u64,
+    /// Set once shutdown started: no new connections or requests after it.
+    drain_deadline: Option<Instant>,
+}
+
+impl IoThread {
+    fn new(listen_fds: &[RawFd], shared: Arc<Shared>, tasks: Sender<Task>) -> io::Result<Self> {
+        let epfd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let wake_fd = cvt(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+
+        // EPOLLEXCLUSIVE avoids waking every I/O thread for each new connection
+        for (i, &fd) in listen_fds.iter().enumerate() {
+            let token = LISTEN_TOKEN_BASE + i as u64;
+            epoll_ctl(epfd, libc::EPOLL_CTL_ADD, fd, (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32, token)?;
+        }
+        epoll_ctl(epfd, libc::EPOLL_CTL_ADD, wake_fd, libc::EPOLLIN as u32, WAKE_TOKEN)?;
+
+        let (out_tx, out_rx) = mpsc::channel();
+        Ok(Self {
+            epfd,
+            listen_fds: listen_fds.to_vec(),
+            wake_fd,
+            shared,
+            tasks,
+            out_tx,
+            out_rx,
+            conns: HashMap::new(),
+            next_id: 0,
+            drain_deadline: None,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut events: Vec<libc::epoll_event> = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let mut last_sweep = Instant::now();
+
+        loop {
+            if signal::shutdown_requested() && self.drain_deadline.is_none() {
+                self.begin_drain();
+            }
+            if let Some(deadline) = self.drain_deadline {
+                let drained = self.conns.is_empty() && self.shared.handed_over.load(Ordering::SeqCst) == 0;
+                if drained || Instant::now() >= deadline {
+                    let ids: Vec<u64> = self.conns.keys().copied().collect();
+                    for id in ids {
+                        self.close(id);
+                    }
+                    return Ok(());
+                }
+            }
+
+            let n = unsafe { libc::epoll_wait(self.epfd, events.as_mut_ptr(), MAX_EVENTS as c_int, 250) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for ev in events.iter().take(n as usize) {
+                let (token, flags) = (ev.u64, ev.events);
+                match token {
+                    WAKE_TOKEN => self.drain_outputs(),
+                    t if t >= LISTEN_TOKEN_BASE => self.accept_all(self.listen_fds[(t - LISTEN_TOKEN_BASE) as usize]),
+                    id => self.on_event(id, flags),
+                }
+            }
+
+            if last_sweep.elapsed() >= Duration::from_secs(1) {
+                self.close_idle();
+                last_sweep = Instant::now();
+            }
+        }
+    }
+
+    /// Stops accepting and drops connections that are idle between
+    /// requests; the rest finish their current exchange.
+    fn begin_drain(&mut self) {
+        for (i, &fd) in self.listen_fds.iter().enumerate() {
+            let _ = epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd, 0, LISTEN_TOKEN_BASE + i as u64);
+        }
+        self.drain_deadline = Some(Instant::now() + self.shared.shutdown_timeout);
+        self.close_idle();
+    }
+
+    fn accept_all(&mut self, listen_fd: RawFd) {
+        loop {
+            let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+            let mut addr_len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            let fd = unsafe {
+                libc::accept4(
+                    listen_fd,
+                    (&mut addr as *mut libc::sockaddr_storage).cast::<libc::sockaddr>(),
+                    &mut addr_len,
+                    libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    ErrorKind::WouldBlock => return,
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        eprintln!("Accept error: {e}");
+                        return;
+                    }
+                }
+            }
+
+            let peer = listener::socket_addr(&addr);
+            if self.shared.active.load(Ordering::SeqCst) >= self.shared.max_connections {
+                let log = self.shared.access_log.as_deref();
+                HttpServer::reject_client(fd, SERVICE_UNAVAILABLE, "Service Unavailable: too many connections", peer, log);
+                continue;
+            }
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            if let Err(e) = epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd, libc::EPOLLIN as u32, id) {
+                eprintln!("epoll register error: {e}");
+                unsafe { libc::close(fd) };
+                continue;
+            }
+
+            self.shared.active.fetch_add(1, Ordering::SeqCst);
+            self.conns.insert(id, Conn {
+                fd,
+                state: ConnState::Reading,
+                inbuf: Vec::new(),
+                outbuf: Vec::new(),
+                out_pos: 0,
+                done: None,
+                served: 0,
+                last_active: Instant::now(),
+                interest: libc::EPOLLIN as u32,
+                eof: false,
+                peer,
+                closed: Arc::new(AtomicBool::new(false)),
+                backlog: Arc::new(Backlog::default()),
+                upgrade: None,
+            });
+        }
+    }
+
+    fn on_event(&mut self, id: u64, flags: u32) {
+        if flags & (libc::EPOLLERR as u32) != 0 {
+            self.close(id);
+            return;
+        }
+
+        if flags & (libc::EPOLLOUT as u32) != 0 {
+            self.flush(id);
+        }
+
+        if flags & ((libc::EPOLLIN | libc::EPOLLHUP) as u32) != 0 {
+            self.read(id);
+        }
+    }
+
+    fn read(&mut self, id: u64) {
+        let max_buffered = max_buffered(&self.shared.limits.request);
+        let Some(conn) = self.conns.get_mut(&id) else { return };
+
+        let mut buf = [0u8; READ_CHUNK];
+        // The rest stays in the socket (epoll is level-triggered) until the
+        // buffered requests are dealt with
+        while conn.inbuf.len() < max_buffered {
+            let n = unsafe { libc::read(conn.fd, buf.as_mut_ptr().cast::<c_void>(), buf.len()) };
+            if n > 0 {
+                conn.inbuf.extend_from_slice(&buf[..n as usize]);
+                conn.last_active = Instant::now();
+                continue;
+            }
+            if n == 0 {
+                // Peer closed its side; still answer what it already sent
+                conn.eof = true;
+                break;
+            }
+
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                ErrorKind::WouldBlock => break,
+                ErrorKind::Interrupted => continue,
+                _ => {
+                    self.close(id);
+                    return;
+                }
+            }
+        }
+
+        self.try_dispatch(id);
+
+        if let Some(conn) = self.conns.get(&id) {
+            // As much as the largest request allowed, and still not one
+            // complete request that could be served
+            if conn.state == ConnState::Reading && conn.inbuf.len() >= max_buffered {
+                self.fail(id, &ServerError::PayloadTooLarge);
+            } else if conn.eof && conn.state == ConnState::Reading {
+                self.close(id);
+            }
+        }
+    }
+
+    /// Hands the next buffered request to the handler pool, if complete.
+    fn try_dispatch(&mut self, id: u64) {
+        let Some(conn) = self.conns.get_mut(&id) else { return };
+        if conn.state != ConnState::Reading {
+            return;
+        }
+
+        let len = match frame_len(&conn.inbuf, &self.shared.limits.request) {
+            Ok(Some(len)) => len,
+            Ok(None) => return,
+            Err(ServerError::PayloadTooLarge) if self.accepts_larger_body(id) => {
+                self.stream_request(id);
+                return;
+            }
+            Err(e) => {
+                self.fail(id, &e);
+                return;
+            }
+        };
+
+        // Left in the buffer on failure, so `fail` can log the request line
+        let parsed = HttpRequest::read_next(&mut Cursor::new(&conn.inbuf[..len]), &self.shared.limits.request);
+        if parsed.is_ok() {
+            conn.inbuf.drain(..len);
+        }
+        let mut request = match parsed {
+            Ok(Some(req)) => req,
+            Ok(None) => return,
+            Err(e) => {
+                self.fail(id, &e);
+                return;
+            }
+        };
+
+        request.peer_addr = conn.peer;
+        conn.served += 1;
+        conn.state = ConnState::Waiting;
+        conn.done = None;
+        let allow_keep_alive = !conn.eof
+            && self.drain_deadline.is_none()
+            && conn.served < self.shared.limits.max_requests;
+        let closed = Arc::clone(&conn.closed);
+        let backlog = Arc::clone(&conn.backlog);
+        self.set_interest(id, 0);
+
+        let task = Task {
+            conn_id: id,
+            request,
+            allow_keep_alive,
+            reply: Replier { tx: self.out_tx.clone(), wake_fd: self.wake_fd },
+            closed,
+            backlog,
+        };
+        if self.tasks.send(task).is_err() {
+            self.close(id);
+        }
+    }
+
+    /// Queues an error response produced on the I/O thread itself and
+    /// closes the connection after it is written.
+    fn fail(&mut self, id: u64, err: &ServerError) {
+        let Some(conn) = self.conns.get_mut(&id) else { return };
+        let resp = error_response(err);
+        if let Some(log) = &self.shared.access_log {
+            let request_line = request_line(&conn.inbuf, &self.shared.limits.request);
+            log.record(&LogEntry {
+                peer: conn.peer,
+                request: None,
+                request_line: request_line.as_deref(),
+                status: resp.status.code,
+                bytes: resp.body.len() as u64,
+                latency: Duration::ZERO,
+            });
+        }
+        conn.inbuf.clear();
+        conn.outbuf.extend_from_slice(&resp.to_bytes(false));
+        conn.state = ConnState::Closing;
+        conn.done = Some(false);
+        self.flush(id);
+    }
+
+    fn drain_outputs(&mut self) {
+        let mut counter: u64 = 0;
+        unsafe { libc::read(self.wake_fd, (&mut counter as *mut u64).cast::<c_void>(), 8) };
+
+        while let Ok(out) = self.out_rx.try_recv() {
+            match out {
+                Output::Data(id, data) => {
+                    if let Some(conn) = self.conns.get_mut(&id) {
+                        conn.outbuf.extend_from_slice(&data);
+                        self.flush(id);
+                    }
+                }
+                Output::Done(id, after) => {
+                    if let Some(conn) = self.conns.get_mut(&id) {
+                        conn.done = Some(matches!(after, AfterResponse::KeepAlive));
+                        if let AfterResponse::Upgrade(upgrade) = after {
+                            conn.upgrade = Some(upgrade);
+                        }
+                        self.flush(id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes as much pending output as the socket accepts, then decides
+    /// what the connection does next.
+    fn flush(&mut self, id: u64) {
+        let Some(conn) = self.conns.get_mut(&id) else { return };
+
+        while conn.out_pos < conn.outbuf.len() {
+            let pending = &conn.outbuf[conn.out_pos..];
+            let n = unsafe { libc::write(conn.fd, pending.as_ptr().cast::<c_void>(), pending.len()) };
+            if n >= 0 {
+                conn.out_pos += n as usize;
+                conn.last_active = Instant::now();
+                conn.backlog.sent(n as usize);
+                continue;
+            }
+
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                ErrorKind::WouldBlock => {
+                    self.set_interest(id, libc::EPOLLOUT as u32);
+                    return;
+                }
+                ErrorKind::Interrupted => continue,
+                _ => {
+                    self.close(id);
+                    return;
+                }
+            }
+        }
+
+        conn.outbuf.clear();
+        conn.out_pos = 0;
+
+        match conn.done {
+            None => self.set_interest(id, 0),
+            Some(false) if conn.upgrade.is_some() => self.hand_over(id),
+            Some(false) => self.close(id),
+            Some(true) if conn.eof || self.drain_deadline.is_some() => self.close(id),
+            Some(true) => {
+                conn.done = None;
+                conn.state = ConnState::Reading;
+                conn.last_active = Instant::now();
+                self.set_interest(id, libc::EPOLLIN as u32);
+                // A pipelined request may already be buffered
+                self.try_dispatch(id);
+            }
+        }
+    }
+
+    fn set_interest(&mut self, id: u64, interest: u32) {
+        let Some(conn) = self.conns.get_mut(&id) else { return };
+        if conn.interest == interest {
+            return;
+        }
+        if epoll_ctl(self.epfd, libc::EPOLL_CTL_MOD, conn.fd, interest, id).is_ok() {
+            conn.interest = interest;
+        }
+    }
+
+    /// Closes connections idle between requests, answers 408 to clients
+    /// that stalled halfway through a request and drops clients that stopped
+    /// reading their response.
+    fn close_idle(&mut self) {
+        let limits = self.shared.limits;
+        let keep_alive_timeout = if self.drain_deadline.is_some() {
+            Duration::ZERO
+        } else {
+            limits.keep_alive_timeout
+        };
+
+        let mut idle = Vec::new();
+        let mut stalled = Vec::new();
+        for (id, c) in &self.conns {
+            let elapsed = c.last_active.elapsed();
+            match c.state {
+                ConnState::Reading if c.inbuf.is_empty() && elapsed >= keep_alive_timeout => idle.push(*id),
+                ConnState::Reading if !c.inbuf.is_empty() && elapsed >= limits.read_timeout => stalled.push(*id),
+                _ if c.out_pos < c.outbuf.len() && elapsed >= limits.write_timeout => idle.push(*id),
+                _ => {}
+            }
+        }
+
+        for id in idle {
+            self.close(id);
+        }
+        for id in stalled {
+            self.fail(id, &ServerError::RequestTimeout);
+        }
+    }
+
+    /// Takes a connection out of the event loop for a thread of its own,
+    /// with blocking I/O and the usual read and write timeouts. It still
+    /// counts as active, and holds up a drain, until `Shared::release`.
+    fn detach(&mut self, id: u64) -> Option<(Conn, File)> {
+        let conn = self.conns.remove(&id)?;
+        let _ = epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, conn.fd, 0, id);
+        let socket = unsafe { File::from_raw_fd(conn.fd) };
+
+        let limits = self.shared.limits;
+        let prepared = set_blocking(conn.fd)
+            .and_then(|_| server::set_timeout(conn.fd, libc::SO_RCVTIMEO, limits.read_timeout))
+            .and_then(|_| server::set_timeout(conn.fd, libc::SO_SNDTIMEO, limits.write_timeout));
+        if prepared.is_err() {
+            self.shared.active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        self.shared.handed_over.fetch_add(1, Ordering::SeqCst);
+        Some((conn, socket))
+    }
+
+    /// Runs the upgrade of a connection that switched protocols.
+    fn hand_over(&mut self, id: u64) {
+        let Some((mut conn, socket)) = self.detach(id) else { return };
+        let Some(upgrade) = conn.upgrade.take() else {
+            self.shared.release();
+            return;
+        };
+
+        let shared = Arc::clone(&self.shared);
+        thread::spawn(move || {
+            upgrade.run(Upgraded::new(socket, conn.inbuf));
+            shared.release();
+        });
+    }
+
+    /// Whether the route of the request at the start of the buffer takes
+    /// bodies larger than `max_body_bytes`.
+    fn accepts_larger_body(&self, id: u64) -> bool {
+        let Some(conn) = self.conns.get(&id) else { return false };
+        let limits = &self.shared.limits.request;
+        match HttpRequest::read_head(&mut Cursor::new(&conn.inbuf[..]), limits) {
+            Ok(Some(req)) => self.shared.dispatcher.body_limit(&req).is_some_and(|max| max > limits.max_body_bytes as u64),
+            _ => false,
+        }
+    }
+
+    /// Serves one request on a thread of its own, which spools the body as
+    /// it arrives rather than buffering it here, then closes the connection.
+    fn stream_request(&mut self, id: u64) {
+        let Some((conn, mut socket)) = self.detach(id) else { return };
+
+        let shared = Arc::clone(&self.shared);
+        thread::spawn(move || {
+            if let Ok(rest) = socket.try_clone() {
+                let mut reader = BufReader::new(Cursor::new(conn.inbuf).chain(rest));
+                let connection = server::Connection {
+                    dispatcher: &shared.dispatcher,
+                    limits: &shared.limits.request,
+                    peer: conn.peer,
+                    access_log: shared.access_log.as_deref(),
+                };
+                let _ = server::handle_connection(&mut reader, &mut socket, &connection, false);
+            }
+            shared.release();
+        });
+    }
+
+    fn close(&mut self, id: u64) {
+        if let Some(conn) = self.conns.remove(&id) {
+            conn.closed.store(true, Ordering::Release);
+            conn.backlog.drained.notify_all();
+            let _ = epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, conn.fd, 0, id);
+            unsafe { libc::close(conn.fd) };
+            self.shared.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Length of the first complete request in `buf`, or `None` if more bytes
+/// are needed. Only framing is inspected here; `HttpRequest::read_next`
+/// does the real parsing. Size limits are enforced here too, so an
+/// oversized request is refused before it is fully buffered.
+fn frame_len(buf: &[u8], limits: &RequestLimits) -> Result<Option<usize>, ServerError> {
+    // Skip stray CRLFs between pipelined requests, but not an endless
+    // stream of them
+    let start = buf.iter().position(|b| *b != b'\r' && *b != b'\n').unwrap_or(buf.len());
+    if start > limits.max_request_line {
+        return Err(ServerError::BadRequest("Too many empty lines before the request line".into()));
+    }
+
+    let line_len = find(&buf[start..], b"\n").map(|i| i + 1).unwrap_or(buf.len() - start);
+    if line_len > limits.max_request_line {
+        return Err(ServerError::UriTooLong);
+    }
+
+    let Some(head_end) = find(&buf[start..], b"\r\n\r\n").map(|i| start + i + 4) else {
+        if has_bare_lf(&buf[start..]) {
+            return Err(bare_lf_error());
+        }
+        if buf.len() - start - line_len > limits.max_header_bytes {
+            return Err(ServerError::HeaderFieldsTooLarge);
+        }
+        return Ok(None);
+    };
+    // The parser refuses these too; were either side to accept them, the
+    // two would disagree on where a pipelined request starts
+    if has_bare_lf(&buf[start..head_end]) {
+        return Err(bare_lf_error());
+    }
+    if head_end - start - line_len > limits.max_header_bytes {
+        return Err(ServerError::HeaderFieldsTooLarge);
+    }
+
+    let head = String::from_utf8_lossy(&buf[start..head_end]);
+    let mut lengths = Vec::new();
+    let mut encodings = Vec::new();
+
+    for line in head.split("\r\n").skip(1) {
+        if let Some((name, value)) = line.split_once(':') {
+            if !headers::is_valid_name(name) {
+                return Err(ServerError::BadRequest(format!("Invalid header format: '{}'", line)));
+            }
+            if name.eq_ignore_ascii_case("Content-Length") {
+                lengths.push(value);
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                encodings.push(value);
+            }
+        }
+    }
+    // Same rules as `read_next`, so both agree on where the request ends
+    let chunked = headers::chunked(encodings, !lengths.is_empty())?;
+    let content_length = headers::content_length(lengths)?;
+
+    if chunked {
+        return chunked_end(buf, head_end, limits);
+    }
+
+    let content_length = content_length.unwrap_or(0);
+    if content_length > limits.max_body_bytes {
+        return Err(ServerError::PayloadTooLarge);
+    }
+
+    let total = head_end + content_length;
+    Ok(if buf.len() >= total { Some(total) } else { None })
+}
+
+/// Bytes a connection may have buffered: room for one request of the
+/// largest size the limits allow.
+fn max_buffered(limits: &RequestLimits) -> usize {
+    limits.max_request_line
+        .saturating_add(limits.max_header_bytes)
+        .saturating_add(limits.max_body_bytes)
+        .max(READ_CHUNK)
+}
+
+/// The complete request line at the start of `buf`, for logging a request
+/// that was refused before it could be parsed.
+fn request_line(buf: &[u8], limits: &RequestLimits) -> Option<String> {
+    let start = buf.iter().position(|b| *b != b'\r' && *b != b'\n')?;
+    let len = find(&buf[start..], b"\n")?;
+    (len <= limits.max_request_line).then(|| String::from_utf8_lossy(&buf[start..start + len]).trim().to_string())
+}
+
+/// Walks the chunk framing that starts at `pos` and returns where the
+/// message ends (after trailers), if it is fully buffered. Chunk-size lines
+/// are bounded like the request line and trailers like the header block.
+fn chunked_end(buf: &[u8], mut pos: usize, limits: &RequestLimits) -> Result<Option<usize>, ServerError> {
+    let mut body_len: usize = 0;
+    loop {
+        let Some(line_end) = find(&buf[pos..], b"\r\n").map(|i| pos + i) else {
+            if buf.len() - pos > limits.max_request_line {
+                return Err(ServerError::BadRequest("Chunk size line too long".into()));
+            }
+            return Ok(None);
+        };
+        if line_end - pos > limits.max_request_line {
+            return Err(ServerError::BadRequest("Chunk size line too long".into()));
+        }
+        let line = String::from_utf8_lossy(&buf[pos..line_end]);
+        // Same rules as `ChunkedReader`, so both read the same size
+        let size = chunked::parse_chunk_size(&line)
+            .filter(|_| !line.contains('\n'))
+            .and_then(|size| usize::try_from(size).ok())
+            .ok_or_else(|| ServerError::BadRequest(format!("Invalid chunk size: '{}'", line.trim())))?;
+        body_len = body_len.saturating_add(size);
+        if body_len > limits.max_body_bytes {
+            return Err(ServerError::PayloadTooLarge);
+        }
+        pos = line_end + 2;
+
+        if size == 0 {
+            // Trailers end with an empty line
+            let trailers = pos;
+            loop {
+                let end = find(&buf[pos..], b"\r\n").map(|i| pos + i);
+                if end.unwrap_or(buf.len()) - trailers > limits.max_header_bytes {
+                    return Err(ServerError::HeaderFieldsTooLarge);
+                }
+                let Some(end) = end else { return Ok(None) };
+                if has_bare_lf(&buf[pos..end]) {
+                    return Err(bare_lf_error());
+                }
+                let empty = end == pos;
+                pos = end + 2;
+                if empty {
+                    return Ok(Some(pos));
+                }
+            }
+        }
+
+        pos += size + 2;
+        if pos > buf.len() {
+            return Ok(None);
+        }
+        if &buf[pos - 2..pos] != b"\r\n" {
+            return Err(ServerError::BadRequest("Missing CRLF after chunk data".into()));
+        }
+    }
+}
+
+/// Whether a line in `buf` ends in LF without the CR before it.
+fn has_bare_lf(buf: &[u8]) -> bool {
+    buf.iter().enumerate().any(|(i, b)| *b == b'\n' && (i == 0 || buf[i - 1] != b'\r'))
+}
+
+fn bare_lf_error() -> ServerError {
+    ServerError::BadRequest("Line not terminated by CRLF".into())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn cvt(rc: c_int) -> io::Result<c_int> {
+    if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(rc) }
+}
+
+fn epoll_ctl(epfd: RawFd, op: c_int, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+    let mut ev = libc::epoll_event { events, u64: token };
+    cvt(unsafe { libc::epoll_ctl(epfd, op, fd, &mut ev) }).map(|_| ())
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) }).map(|_| ())
+}
+
+fn set_blocking(fd: RawFd) -> io::Result<()> {
+    let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) }).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::response::{IterReader, Response, OK};
+    use crate::http::router::router::SimpleHandler;
+
+    fn task(path: &str, reply: &Replier) -> Task {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path);
+        Task {
+            conn_id: 7,
+            request: HttpRequest::parse(&mut Cursor::new(raw.into_bytes())).unwrap(),
+            allow_keep_alive: true,
+            reply: reply.clone(),
+            closed: Arc::new(AtomicBool::new(false)),
+            backlog: Arc::new(Backlog::default()),
+        }
+    }
+
+    #[test]
+    fn handler_threads_survive_panicking_handlers() {
+        let dispatcher = Dispatcher::builder()
+            .get("/boom", Arc::new(SimpleHandler(|_: &HttpRequest| -> Result<Response, ServerError> { panic!("boom") })))
+            .get("/ok", Arc::new(SimpleHandler(|_: &HttpRequest| Ok(Response::new(OK).with_body("fine")))))
+            .build();
+        let (task_tx, task_rx) = mpsc::channel();
+        let (out_tx, out_rx) = mpsc::channel();
+        let reply = Replier { tx: out_tx, wake_fd: -1 };
+        let rx = Arc::new(Mutex::new(task_rx));
+        thread::spawn(move || handler_loop(rx, Arc::new(dispatcher), None));
+
+        let next = |path: &str| {
+            task_tx.send(task(path, &reply)).unwrap();
+            let mut body = Vec::new();
+            loop {
+                match out_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                    Output::Data(_, data) => body.extend(data),
+                    Output::Done(_, after) => return (String::from_utf8(body).unwrap(), after),
+                }
+            }
+        };
+
+        let (body, after) = next("/boom");
+        assert!(body.contains(" 500 Internal Server Error"));
+        assert!(matches!(after, AfterResponse::Close));
+        let (body, after) = next("/ok");
+        assert!(body.ends_with("fine"));
+        assert!(matches!(after, AfterResponse::KeepAlive));
+    }
+
+    #[test]
+    fn long_lived_streams_leave_the_handler_thread() {
+        let (events_tx, events_rx) = mpsc::channel::<Vec<u8>>();
+        let events_rx = Mutex::new(Some(events_rx));
+        let dispatcher = Dispatcher::builder()
+            .get("/events", Arc::new(SimpleHandler(move |_: &HttpRequest| {
+                let events = events_rx.lock().unwrap().take().unwrap();
+                Ok(Response::new(OK).with_long_lived_stream(IterReader::new(events.into_iter())))
+            })))
+            .get("/ok", Arc::new(SimpleHandler(|_: &HttpRequest| Ok(Response::new(OK).with_body("fine")))))
+            .build();
+        let (task_tx, task_rx) = mpsc::channel();
+        let (out_tx, out_rx) = mpsc::channel();
+        let reply = Replier { tx: out_tx, wake_fd: -1 };
+        let rx = Arc::new(Mutex::new(task_rx));
+        // A single handler thread
+        thread::spawn(move || handler_loop(rx, Arc::new(dispatcher), None));
+
+        task_tx.send(task("/events", &reply)).unwrap();
+        let mut other = task("/ok", &reply);
+        other.conn_id = 8;
+        task_tx.send(other).unwrap();
+
+        // Served while the stream is still open
+        loop {
+            match out_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Output::Done(8, _) => break,
+                Output::Done(id, _) => panic!("connection {} finished early", id),
+                Output::Data(..) => {}
+            }
+        }
+        drop(events_tx);
+        loop {
+            if let Output::Done(id, _) = out_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                assert_eq!(id, 7);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn reply_writer_waits_for_a_slow_client() {
+        let (out_tx, out_rx) = mpsc::channel();
+        let backlog = Arc::new(Backlog::default());
+        let mut writer = ReplyWriter {
+            conn_id: 1,
+            reply: Replier { tx: out_tx, wake_fd: -1 },
+            buf: Vec::new(),
+            closed: Arc::new(AtomicBool::new(false)),
+            backlog: Arc::clone(&backlog),
+            started: false,
+        };
+        let producer = thread::spawn(move || writer.write_all(&vec![b'x'; 3 * HIGH_WATER]).and_then(|_| writer.flush()));
+
+        // The first batch goes out, then the writer waits for it to be sent
+        let Output::Data(_, first) = out_rx.recv_timeout(Duration::from_secs(5)).unwrap() else { panic!() };
+        thread::sleep(Duration::from_millis(50));
+        assert!(out_rx.try_recv().is_err());
+
+        let mut received = first.len();
+        backlog.sent(first.len());
+        while received < 3 * HIGH_WATER {
+            let Output::Data(_, data) = out_rx.recv_timeout(Duration::from_secs(5)).unwrap() else { panic!() };
+            received += data.len();
+            backlog.sent(data.len());
+        }
+        producer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn frame_waits_for_full_head_and_body() {
+        assert_eq!(frame_len(b"GET / HTTP/1.1\r\nHost: a\r\n", &RequestLimits::default()).unwrap(), None);
+        assert_eq!(frame_len(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", &RequestLimits::default()).unwrap(), Some(27));
+
+        let post = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhel";
+        assert_eq!(frame_len(post, &RequestLimits::default()).unwrap(), None);
+        let post = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET";
+        assert_eq!(frame_len(post, &RequestLimits::default()).unwrap(), Some(post.len() - 3));
+    }
+
+    #[test]
+    fn frame_handles_chunked_bodies() {
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut msg = head.to_vec();
+        msg.extend_from_slice(b"5\r\nhello\r\n");
+        assert_eq!(frame_len(&msg, &RequestLimits::default()).unwrap(), None);
+        msg.extend_from_slice(b"0\r\n\r\n");
+        assert_eq!(frame_len(&msg, &RequestLimits::default()).unwrap(), Some(msg.len()));
+    }
+
+    #[test]
+    fn frame_reads_chunk_sizes_like_the_decoder() {
+        let head = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for body in ["+5\r\nhello\r\n0\r\n\r\n", "5\r\nhelloXY0\r\n\r\n", "5\r\nhello\r\n0\r\nX-T: 1\n\r\n"] {
+            let msg = format!("{}{}", head, body);
+            assert!(matches!(frame_len(msg.as_bytes(), &RequestLimits::default()), Err(ServerError::BadRequest(_))), "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn frame_rejects_bad_content_length() {
+        assert!(frame_len(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n", &RequestLimits::default()).is_err());
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 4\r\n\r\nabcd";
+        assert!(frame_len(conflicting, &RequestLimits::default()).is_err());
+    }
+
+    #[test]
+    fn frame_rejects_ambiguous_transfer_encoding() {
+        let both = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(frame_len(both, &RequestLimits::default()), Err(ServerError::BadRequest(_))));
+        let repeated = b"POST / HTTP/1.1\r\nTransfer-Encoding: identity\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(frame_len(repeated, &RequestLimits::default()), Err(ServerError::BadRequest(_))));
+        let listed = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(frame_len(listed, &RequestLimits::default()), Err(ServerError::BadRequest(_))));
+    }
+
+    #[test]
+    fn frame_enforces_size_limits() {
+        let limits = RequestLimits { max_request_line: 32, max_headers: 10, max_header_bytes: 32, max_body_bytes: 4 };
+
+        let long_line = format!("GET /{} HTTP/1.1", "a".repeat(40));
+        assert!(matches!(frame_len(long_line.as_bytes(), &limits), Err(ServerError::UriTooLong)));
+
+        let big_head = format!("GET / HTTP/1.1\r\nX-Pad: {}\r\n", "a".repeat(40));
+        assert!(matches!(frame_len(big_head.as_bytes(), &limits), Err(ServerError::HeaderFieldsTooLarge)));
+
+        let big_body = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        assert!(matches!(frame_len(big_body, &limits), Err(ServerError::PayloadTooLarge)));
+
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\n";
+        assert!(matches!(frame_len(chunked, &limits), Err(ServerError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn frame_bounds_blank_lines_chunk_sizes_and_trailers() {
+        let limits = RequestLimits { max_request_line: 32, max_headers: 10, max_header_bytes: 32, max_body_bytes: 64 };
+
+        let blank = "\r\n".repeat(20);
+        assert!(matches!(frame_len(blank.as_bytes(), &limits), Err(ServerError::BadRequest(_))));
+        assert_eq!(frame_len(b"\r\n\r\nGET / HTTP/1.1\r\n\r\n", &limits).unwrap(), Some(22));
+
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let size_line = format!("{}1;{}", head, "x".repeat(40));
+        assert!(matches!(frame_len(size_line.as_bytes(), &limits), Err(ServerError::BadRequest(_))));
+
+        let trailers = format!("{}0\r\n{}", head, "X-T: 1\r\n".repeat(5));
+        assert!(matches!(frame_len(trailers.as_bytes(), &limits), Err(ServerError::HeaderFieldsTooLarge)));
+        let endless_trailer = format!("{}0\r\nX-T: {}", head, "x".repeat(40));
+        assert!(matches!(frame_len(endless_trailer.as_bytes(), &limits), Err(ServerError::HeaderFieldsTooLarge)));
+    }
+
+    #[test]
+    fn frame_and_parser_agree_on_bare_lf_and_padded_names() {
+        let limits = RequestLimits::default();
+        let pipelined = [
+            &b"GET /a HTTP/1.1\nHost: a\n\nGET /b HTTP/1.1\r\nHost: a\r\n\r\n"[..],
+            b"GET /a HTTP/1.1\r\nHost: a\n\r\nGET /b HTTP/1.1\r\nHost: a\r\n\r\n",
+            b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length : 3\r\n\r\nabcGET /b HTTP/1.1\r\nHost: a\r\n\r\n",
+        ];
+        for raw in pipelined {
+            // Neither model serves /a and then reads /b from a different offset
+            assert!(matches!(frame_len(raw, &limits), Err(ServerError::BadRequest(_))), "{:?}", String::from_utf8_lossy(raw));
+            let parsed = HttpRequest::read_next(&mut Cursor::new(raw.to_vec()), &limits);
+            assert!(matches!(parsed, Err(ServerError::BadRequest(_))), "{:?}", String::from_utf8_lossy(raw));
+        }
+        // A bare LF that has arrived is refused before the head is complete
+        assert!(frame_len(b"GET /a HTTP/1.1\nHost", &limits).is_err());
+    }
+}
@@ -1,37 +1,151 @@
-use std::collections::HashMap;
-use std::io::{Read, BufRead, BufReader};
-use crate::http::errors::ServerError;
+use std::net::SocketAddr;
+use std::io::{self, Read, BufRead, ErrorKind};
+use crate::http::{chunked::{self, ChunkedReader}, errors::ServerError, headers::{self, Headers}, spool::SpooledBody, urlencoded};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Upper bounds applied while reading a request, so a client cannot make
+/// the server buffer arbitrarily large lines, header blocks or bodies.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Longest accepted request line, in bytes (414 beyond it).
+    pub max_request_line: usize,
+    /// Most header fields per request (431 beyond it).
+    pub max_headers: usize,
+    /// Total size of the header block, in bytes (431 beyond it).
+    pub max_header_bytes: usize,
+    /// Largest accepted body, in bytes (413 beyond it).
+    pub max_body_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum HttpMethod {
     GET,
     HEAD,
     POST,
+    PUT,
+    DELETE,
+    PATCH,
+    OPTIONS,
     Unsupported(String),
 }
 
+impl HttpMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::Unsupported(m) => m,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: String,
     pub version: String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Vec<u8>,
+    /// Set instead of `body` when the body outgrew `max_body_bytes` on a
+    /// route that accepts larger ones.
+    pub spooled_body: Option<SpooledBody>,
     pub query: String,
+    /// Decoded query-string pairs followed by the pairs of an
+    /// `application/x-www-form-urlencoded` body, in order.
+    pub params: Vec<(String, String)>,
+    /// Captures from the matched route template, filled in by the dispatcher.
+    pub path_params: Vec<(String, String)>,
+    /// The client's address, set by the connection layer.
+    pub peer_addr: Option<SocketAddr>,
 }
 
 impl HttpRequest {
-    pub fn parse<R: Read>(reader: &mut R) -> Result<Self, ServerError> {
-        let mut reader = BufReader::new(reader);
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Self, ServerError> {
+        Self::read_next(reader, &RequestLimits::default())?
+            .ok_or_else(|| ServerError::BadRequest("Empty request line".into()))
+    }
+
+    /// Reads the next request from a (possibly persistent) connection.
+    /// Returns `Ok(None)` when the peer closed the connection (or let the
+    /// read timeout expire) before sending anything.
+    pub fn read_next<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<Option<Self>, ServerError> {
+        Self::read_next_with(reader, limits, |_| None)
+    }
+
+    /// Like `read_next`, but asks `body_limit` (once the head is read) how
+    /// large a body the request's route accepts. Past `max_body_bytes`, up
+    /// to that many bytes are spooled to disk rather than refused.
+    pub fn read_next_with<R, F>(reader: &mut R, limits: &RequestLimits, body_limit: F) -> Result<Option<Self>, ServerError>
+    where
+        R: BufRead,
+        F: Fn(&HttpRequest) -> Option<u64>,
+    {
+        let Some(line) = read_request_line(reader, limits)? else { return Ok(None) };
+        Self::read_rest(&line, reader, limits, body_limit).map(Some)
+    }
+
+    /// Reads the rest of a request whose request line `read_request_line`
+    /// returned, body included (see `read_next_with`).
+    pub(crate) fn read_rest<R, F>(request_line: &str, reader: &mut R, limits: &RequestLimits, body_limit: F) -> Result<Self, ServerError>
+    where
+        R: BufRead,
+        F: Fn(&HttpRequest) -> Option<u64>,
+    {
+        let mut req = Self::parse_head(request_line, reader, limits)?;
+
+        let spill = body_limit(&req).filter(|max| *max > limits.max_body_bytes as u64);
+        if req.headers.chunked()? {
+            let chunked = ChunkedReader::with_limits(&mut *reader, limits);
+            let fits = read_body(chunked, limits.max_body_bytes, spill, &mut req).map_err(|e| match chunked::limit_error(e) {
+                Ok(err) => err,
+                Err(e) => match read_error(e) {
+                    ServerError::Io(e) => ServerError::BadRequest(format!("Invalid chunked body: {}", e)),
+                    other => other,
+                },
+            })?;
+            if !fits {
+                return Err(ServerError::PayloadTooLarge);
+            }
+        } else if let Some(content_length) = req.headers.content_length()? {
+            if content_length as u64 > spill.unwrap_or(limits.max_body_bytes as u64) {
+                return Err(ServerError::PayloadTooLarge);
+            }
 
-        let mut request_line = String::new();
-        reader.read_line(&mut request_line)?;
-        let request_line = request_line.trim();
+            read_body(reader.take(content_length as u64), limits.max_body_bytes, spill, &mut req).map_err(read_error)?;
+        }
 
-        if request_line.is_empty() {
-            return Err(ServerError::BadRequest("Empty request line".into()));
+        if req.is_form() {
+            let form = String::from_utf8_lossy(&req.body).into_owned();
+            req.params.extend(urlencoded::parse(&form));
         }
 
+        Ok(req)
+    }
+
+    /// Reads the request line and headers of the next request, leaving the
+    /// body unread. Returns `Ok(None)` like `read_next`.
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<Option<Self>, ServerError> {
+        let Some(line) = read_request_line(reader, limits)? else { return Ok(None) };
+        Self::parse_head(&line, reader, limits).map(Some)
+    }
+
+    /// Parses `request_line` and reads the headers that follow it.
+    fn parse_head<R: BufRead>(request_line: &str, reader: &mut R, limits: &RequestLimits) -> Result<Self, ServerError> {
         let parts: Vec<&str> = request_line.split_whitespace().collect();
         if parts.len() != 3 {
             return Err(ServerError::BadRequest(format!(
@@ -43,6 +157,10 @@ impl HttpRequest {
             "GET" => HttpMethod::GET,
             "HEAD" => HttpMethod::HEAD,
             "POST" => HttpMethod::POST,
+            "PUT" => HttpMethod::PUT,
+            "DELETE" => HttpMethod::DELETE,
+            "PATCH" => HttpMethod::PATCH,
+            "OPTIONS" => HttpMethod::OPTIONS,
             other => HttpMethod::Unsupported(other.to_string()),
         };
 
@@ -57,50 +175,285 @@ impl HttpRequest {
 
         let version = parts[2].to_string();
 
-
-        if version != "HTTP/1.0" {
+        if version != "HTTP/1.0" && version != "HTTP/1.1" {
             return Err(ServerError::BadRequest(format!(
-                "Only HTTP/1.0 is supported (got '{}')", version
+                "Only HTTP/1.0 and HTTP/1.1 are supported (got '{}')", version
             )));
         }
 
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
+        let mut header_bytes = 0;
+        let mut header_count = 0;
         loop {
-            let mut line = String::new();
-            if reader.read_line(&mut line)? == 0 {
+            let remaining = limits.max_header_bytes.saturating_sub(header_bytes);
+            let line = read_line_limited(reader, remaining)
+                .map_err(read_error)?
+                .ok_or(ServerError::HeaderFieldsTooLarge)?;
+            if line.is_empty() {
                 break; // EOF
             }
+            header_bytes += line.len();
 
-            let line = line.trim_end_matches(['\r', '\n']);
+            let line = strip_crlf(&line)?;
             if line.is_empty() {
                 break; // End of headers
             }
 
-            if let Some((name, value)) = line.split_once(':') {
-                headers.insert(name.trim().to_string(), value.trim().to_string());
-            } else {
-                return Err(ServerError::BadRequest(format!(
-                    "Invalid header format: '{}'", line
-                )));
+            header_count += 1;
+            if header_count > limits.max_headers {
+                return Err(ServerError::HeaderFieldsTooLarge);
             }
-        }
 
-        let mut body = Vec::new();
-        if let Some(content_length) = headers
-            .get("Content-Length")
-            .and_then(|v| v.parse::<usize>().ok())
-        {
-            let mut limited = reader.take(content_length as u64);
-            limited.read_to_end(&mut body)?;
+            match line.split_once(':') {
+                // Whitespace before the colon lets peers disagree on the
+                // field name, so it is refused rather than trimmed
+                Some((name, value)) if headers::is_valid_name(name) => {
+                    headers.append(name, value.trim());
+                }
+                _ => {
+                    return Err(ServerError::BadRequest(format!(
+                        "Invalid header format: '{}'", line
+                    )));
+                }
+            }
         }
 
-        Ok(HttpRequest {
+        let req = HttpRequest {
             method,
             path,
             version,
             headers,
-            body,
+            body: Vec::new(),
+            spooled_body: None,
+            params: urlencoded::parse(&query),
+            path_params: Vec::new(),
+            peer_addr: None,
             query
-        })
+        };
+
+        if req.version == "HTTP/1.1" && req.header("Host").is_none() {
+            return Err(ServerError::BadRequest("Missing Host header".into()));
+        }
+        Ok(req)
+    }
+
+    /// Size of the body, in memory or spooled.
+    pub fn body_len(&self) -> u64 {
+        self.spooled_body.as_ref().map_or(self.body.len() as u64, SpooledBody::len)
+    }
+
+    /// Reads the body, in memory or spooled.
+    pub fn body_reader(&self) -> Box<dyn Read + '_> {
+        match &self.spooled_body {
+            Some(spooled) => Box::new(spooled.reader()),
+            None => Box::new(&self.body[..]),
+        }
+    }
+
+    /// Case-insensitive header lookup; the first value if repeated.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    fn is_form(&self) -> bool {
+        self.header("Content-Type")
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"))
+            .unwrap_or(false)
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 defaults to persistent connections, HTTP/1.0 only when asked.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.header("Connection")
+                .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        };
+
+        if self.version == "HTTP/1.1" {
+            !has_token("close")
+        } else {
+            has_token("keep-alive")
+        }
+    }
+}
+
+/// Reads the next request line, without its line break. Returns
+/// `Ok(None)` like `HttpRequest::read_next`.
+pub(crate) fn read_request_line<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<Option<String>, ServerError> {
+    // Tolerate stray CRLFs left between pipelined requests, but not
+    // an endless stream of them
+    let mut skipped = 0;
+    loop {
+        let line = match read_line_limited(reader, limits.max_request_line) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err(ServerError::UriTooLong),
+            // Nothing arrived before the read timeout: treat like an idle close
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(read_error(e)),
+        };
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if !line.trim().is_empty() {
+            return Ok(Some(strip_crlf(&line)?.trim().to_string()));
+        }
+        skipped += line.len();
+        if skipped > limits.max_request_line {
+            return Err(ServerError::BadRequest("Too many empty lines before the request line".into()));
+        }
+    }
+}
+
+/// `line` without its CRLF. A bare LF is refused, as the epoll framer only
+/// ends lines at CRLF and both must agree on where a request ends.
+fn strip_crlf(line: &str) -> Result<&str, ServerError> {
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(line),
+        None if line.ends_with('\n') => Err(ServerError::BadRequest("Line not terminated by CRLF".into())),
+        // Cut short by EOF
+        None => Ok(line),
+    }
+}
+
+/// Reads one line (including its terminator) without buffering more than
+/// `max` bytes. Returns `Ok(None)` if the line is longer than that and an
+/// empty string at EOF.
+pub(crate) fn read_line_limited<R: BufRead>(reader: &mut R, max: usize) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // A stall halfway through a line is a timeout, not an idle close
+                if !line.is_empty() && is_timeout(&e) {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "read timed out mid-line"));
+                }
+                return Err(e);
+            }
+        };
+        if available.is_empty() {
+            break;
+        }
+
+        let (take, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), false),
+        };
+
+        if line.len() + take > max {
+            return Ok(None);
+        }
+
+        line.extend_from_slice(&available[..take]);
+        reader.consume(take);
+        if done {
+            break;
+        }
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "request head is not valid UTF-8"))
+}
+
+/// Reads a body into `req.body`, moving it to a spooled file once it
+/// outgrows `max_memory` if the route accepts up to `spill` bytes. Returns
+/// false when the body is larger than allowed.
+fn read_body<R: Read>(body: R, max_memory: usize, spill: Option<u64>, req: &mut HttpRequest) -> io::Result<bool> {
+    // Read one byte past the limit to tell "exactly at" from "over"
+    let mut body = body.take(max_memory as u64 + 1);
+    body.read_to_end(&mut req.body)?;
+    if req.body.len() <= max_memory {
+        return Ok(true);
+    }
+    let Some(max) = spill else { return Ok(false) };
+
+    let head = std::mem::take(&mut req.body);
+    let mut rest = body.into_inner().take(max + 1 - head.len() as u64);
+    let spooled = SpooledBody::create(&head, &mut rest)?;
+    let fits = spooled.len() <= max;
+    req.spooled_body = Some(spooled);
+    Ok(fits)
+}
+
+/// `SO_RCVTIMEO` expiry surfaces as `WouldBlock` on Linux.
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Maps I/O failures while a request is half read: a stalled client
+/// becomes 408, undecodable bytes become 400.
+fn read_error(e: io::Error) -> ServerError {
+    match e.kind() {
+        _ if is_timeout(&e) => ServerError::RequestTimeout,
+        ErrorKind::InvalidData => ServerError::BadRequest(e.to_string()),
+        _ => ServerError::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(raw: &str, limits: &RequestLimits) -> Result<Option<HttpRequest>, ServerError> {
+        HttpRequest::read_next(&mut Cursor::new(raw.as_bytes().to_vec()), limits)
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let limits = RequestLimits { max_request_line: 32, max_headers: 2, max_header_bytes: 64, max_body_bytes: 4 };
+
+        let long_line = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(40));
+        assert!(matches!(read(&long_line, &limits), Err(ServerError::UriTooLong)));
+        let many = "GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\n\r\n";
+        assert!(matches!(read(many, &limits), Err(ServerError::HeaderFieldsTooLarge)));
+        let big_head = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", "a".repeat(80));
+        assert!(matches!(read(&big_head, &limits), Err(ServerError::HeaderFieldsTooLarge)));
+        let big_body = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(matches!(read(big_body, &limits), Err(ServerError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn bounds_blank_lines_and_chunk_framing() {
+        let limits = RequestLimits { max_request_line: 32, max_headers: 10, max_header_bytes: 64, max_body_bytes: 64 };
+
+        let blank = format!("{}GET / HTTP/1.1\r\nHost: a\r\n\r\n", "\r\n".repeat(20));
+        assert!(matches!(read(&blank, &limits), Err(ServerError::BadRequest(_))));
+        assert!(read("\r\n\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n", &limits).unwrap().is_some());
+
+        let head = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let size_line = format!("{}1;{}\r\na\r\n0\r\n\r\n", head, "x".repeat(40));
+        assert!(matches!(read(&size_line, &limits), Err(ServerError::BadRequest(_))));
+        let trailers = format!("{}1\r\na\r\n0\r\n{}\r\n", head, "X-Trailer: 1\r\n".repeat(10));
+        assert!(matches!(read(&trailers, &limits), Err(ServerError::HeaderFieldsTooLarge)));
+
+        let ok = format!("{}1\r\na\r\n0\r\nX-Trailer: 1\r\n\r\n", head);
+        assert_eq!(read(&ok, &limits).unwrap().unwrap().body, b"a");
+    }
+
+    #[test]
+    fn spools_large_bodies_for_routes_that_accept_them() {
+        let limits = RequestLimits { max_body_bytes: 4, ..RequestLimits::default() };
+        let body = "POST /files HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\n0123456789";
+        let read_with = |raw: &str, max: u64| HttpRequest::read_next_with(&mut Cursor::new(raw.as_bytes().to_vec()), &limits, |_| Some(max));
+
+        let req = read_with(body, 10).unwrap().unwrap();
+        assert!(req.body.is_empty());
+        assert_eq!(req.body_len(), 10);
+        let mut spooled = String::new();
+        req.body_reader().read_to_string(&mut spooled).unwrap();
+        assert_eq!(spooled, "0123456789");
+
+        assert!(matches!(read_with(body, 9), Err(ServerError::PayloadTooLarge)));
+        let chunked = "POST /files HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n6\r\n012345\r\n4\r\n6789\r\n0\r\n\r\n";
+        assert_eq!(read_with(chunked, 10).unwrap().unwrap().body_len(), 10);
+        assert!(matches!(read_with(chunked, 9), Err(ServerError::PayloadTooLarge)));
+        // Small bodies stay in memory
+        assert_eq!(read_with("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nab", 10).unwrap().unwrap().body, b"ab");
     }
 }
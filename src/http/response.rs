@@ -1,6 +1,12 @@
-use std::collections::HashMap;
-use std::fmt::Write as _;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fmt::{self, Write as _};
+use std::io::{self, Read, Write};
+use std::time::SystemTime;
+
+use crate::http::chunked::ChunkedWriter;
+use crate::http::errors::ServerError;
+use crate::http::headers::{is_valid_name, is_valid_value, Headers};
+use crate::http::upgrade::Upgrade;
+use crate::utils::time::http_date;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Status {
@@ -8,20 +14,156 @@ pub struct Status {
     pub reason: &'static str,
 }
 
+pub const CONTINUE: Status = Status { code: 100, reason: "Continue" };
+pub const SWITCHING_PROTOCOLS: Status = Status { code: 101, reason: "Switching Protocols" };
+pub const PROCESSING: Status = Status { code: 102, reason: "Processing" };
+pub const EARLY_HINTS: Status = Status { code: 103, reason: "Early Hints" };
 pub const OK: Status = Status { code: 200, reason: "OK" };
+pub const CREATED: Status = Status { code: 201, reason: "Created" };
+pub const ACCEPTED: Status = Status { code: 202, reason: "Accepted" };
+pub const NON_AUTHORITATIVE_INFORMATION: Status = Status { code: 203, reason: "Non-Authoritative Information" };
+pub const NO_CONTENT: Status = Status { code: 204, reason: "No Content" };
+pub const RESET_CONTENT: Status = Status { code: 205, reason: "Reset Content" };
+pub const PARTIAL_CONTENT: Status = Status { code: 206, reason: "Partial Content" };
+pub const MULTI_STATUS: Status = Status { code: 207, reason: "Multi-Status" };
+pub const ALREADY_REPORTED: Status = Status { code: 208, reason: "Already Reported" };
+pub const IM_USED: Status = Status { code: 226, reason: "IM Used" };
+pub const MULTIPLE_CHOICES: Status = Status { code: 300, reason: "Multiple Choices" };
+pub const MOVED_PERMANENTLY: Status = Status { code: 301, reason: "Moved Permanently" };
+pub const FOUND: Status = Status { code: 302, reason: "Found" };
+pub const SEE_OTHER: Status = Status { code: 303, reason: "See Other" };
+pub const NOT_MODIFIED: Status = Status { code: 304, reason: "Not Modified" };
+pub const USE_PROXY: Status = Status { code: 305, reason: "Use Proxy" };
+pub const TEMPORARY_REDIRECT: Status = Status { code: 307, reason: "Temporary Redirect" };
+pub const PERMANENT_REDIRECT: Status = Status { code: 308, reason: "Permanent Redirect" };
 pub const BAD_REQUEST: Status = Status { code: 400, reason: "Bad Request" };
+pub const UNAUTHORIZED: Status = Status { code: 401, reason: "Unauthorized" };
+pub const PAYMENT_REQUIRED: Status = Status { code: 402, reason: "Payment Required" };
+pub const FORBIDDEN: Status = Status { code: 403, reason: "Forbidden" };
 pub const NOT_FOUND: Status = Status { code: 404, reason: "Not Found" };
+pub const METHOD_NOT_ALLOWED: Status = Status { code: 405, reason: "Method Not Allowed" };
+pub const NOT_ACCEPTABLE: Status = Status { code: 406, reason: "Not Acceptable" };
+pub const PROXY_AUTHENTICATION_REQUIRED: Status = Status { code: 407, reason: "Proxy Authentication Required" };
+pub const REQUEST_TIMEOUT: Status = Status { code: 408, reason: "Request Timeout" };
 pub const CONFLICT: Status = Status { code: 409, reason: "Conflict" };
+pub const GONE: Status = Status { code: 410, reason: "Gone" };
+pub const LENGTH_REQUIRED: Status = Status { code: 411, reason: "Length Required" };
+pub const PRECONDITION_FAILED: Status = Status { code: 412, reason: "Precondition Failed" };
+pub const PAYLOAD_TOO_LARGE: Status = Status { code: 413, reason: "Payload Too Large" };
+pub const URI_TOO_LONG: Status = Status { code: 414, reason: "URI Too Long" };
+pub const UNSUPPORTED_MEDIA_TYPE: Status = Status { code: 415, reason: "Unsupported Media Type" };
+pub const RANGE_NOT_SATISFIABLE: Status = Status { code: 416, reason: "Range Not Satisfiable" };
+pub const EXPECTATION_FAILED: Status = Status { code: 417, reason: "Expectation Failed" };
+pub const MISDIRECTED_REQUEST: Status = Status { code: 421, reason: "Misdirected Request" };
+pub const UNPROCESSABLE_ENTITY: Status = Status { code: 422, reason: "Unprocessable Entity" };
+pub const LOCKED: Status = Status { code: 423, reason: "Locked" };
+pub const FAILED_DEPENDENCY: Status = Status { code: 424, reason: "Failed Dependency" };
+pub const TOO_EARLY: Status = Status { code: 425, reason: "Too Early" };
+pub const UPGRADE_REQUIRED: Status = Status { code: 426, reason: "Upgrade Required" };
+pub const PRECONDITION_REQUIRED: Status = Status { code: 428, reason: "Precondition Required" };
 pub const TOO_MANY_REQUESTS: Status = Status { code: 429, reason: "Too Many Requests" };
+pub const REQUEST_HEADER_FIELDS_TOO_LARGE: Status = Status { code: 431, reason: "Request Header Fields Too Large" };
+pub const UNAVAILABLE_FOR_LEGAL_REASONS: Status = Status { code: 451, reason: "Unavailable For Legal Reasons" };
 pub const INTERNAL_SERVER_ERROR: Status = Status { code: 500, reason: "Internal Server Error" };
+pub const NOT_IMPLEMENTED: Status = Status { code: 501, reason: "Not Implemented" };
+pub const BAD_GATEWAY: Status = Status { code: 502, reason: "Bad Gateway" };
 pub const SERVICE_UNAVAILABLE: Status = Status { code: 503, reason: "Service Unavailable" };
+pub const GATEWAY_TIMEOUT: Status = Status { code: 504, reason: "Gateway Timeout" };
+pub const HTTP_VERSION_NOT_SUPPORTED: Status = Status { code: 505, reason: "HTTP Version Not Supported" };
+pub const VARIANT_ALSO_NEGOTIATES: Status = Status { code: 506, reason: "Variant Also Negotiates" };
+pub const INSUFFICIENT_STORAGE: Status = Status { code: 507, reason: "Insufficient Storage" };
+pub const LOOP_DETECTED: Status = Status { code: 508, reason: "Loop Detected" };
+pub const NOT_EXTENDED: Status = Status { code: 510, reason: "Not Extended" };
+pub const NETWORK_AUTHENTICATION_REQUIRED: Status = Status { code: 511, reason: "Network Authentication Required" };
+
+/// Every registered status code (IANA HTTP Status Code Registry), in order.
+const STATUSES: &[Status] = &[
+    CONTINUE, SWITCHING_PROTOCOLS, PROCESSING, EARLY_HINTS, OK, CREATED, ACCEPTED,
+    NON_AUTHORITATIVE_INFORMATION, NO_CONTENT, RESET_CONTENT, PARTIAL_CONTENT, MULTI_STATUS,
+    ALREADY_REPORTED, IM_USED, MULTIPLE_CHOICES, MOVED_PERMANENTLY, FOUND, SEE_OTHER, NOT_MODIFIED,
+    USE_PROXY, TEMPORARY_REDIRECT, PERMANENT_REDIRECT, BAD_REQUEST, UNAUTHORIZED, PAYMENT_REQUIRED,
+    FORBIDDEN, NOT_FOUND, METHOD_NOT_ALLOWED, NOT_ACCEPTABLE, PROXY_AUTHENTICATION_REQUIRED,
+    REQUEST_TIMEOUT, CONFLICT, GONE, LENGTH_REQUIRED, PRECONDITION_FAILED, PAYLOAD_TOO_LARGE,
+    URI_TOO_LONG, UNSUPPORTED_MEDIA_TYPE, RANGE_NOT_SATISFIABLE, EXPECTATION_FAILED,
+    MISDIRECTED_REQUEST, UNPROCESSABLE_ENTITY, LOCKED, FAILED_DEPENDENCY, TOO_EARLY,
+    UPGRADE_REQUIRED, PRECONDITION_REQUIRED, TOO_MANY_REQUESTS, REQUEST_HEADER_FIELDS_TOO_LARGE,
+    UNAVAILABLE_FOR_LEGAL_REASONS, INTERNAL_SERVER_ERROR, NOT_IMPLEMENTED, BAD_GATEWAY,
+    SERVICE_UNAVAILABLE, GATEWAY_TIMEOUT, HTTP_VERSION_NOT_SUPPORTED, VARIANT_ALSO_NEGOTIATES,
+    INSUFFICIENT_STORAGE, LOOP_DETECTED, NOT_EXTENDED, NETWORK_AUTHENTICATION_REQUIRED,
+];
+
+impl Status {
+    /// The status for `code` with its registered reason phrase. Unregistered
+    /// codes get an empty phrase, which the status line allows; `None` if
+    /// `code` is not a three-digit status at all.
+    pub fn from_code(code: u16) -> Option<Status> {
+        if !(100..=599).contains(&code) {
+            return None;
+        }
+        Some(STATUSES.iter().copied().find(|s| s.code == code).unwrap_or(Status { code, reason: "" }))
+    }
+}
+
+/// A body produced incrementally instead of held in memory. With a known
+/// length it is sent with `Content-Length`; otherwise it is chunked on
+/// HTTP/1.1 and delimited by closing the connection on HTTP/1.0.
+pub struct StreamBody {
+    pub reader: Box<dyn Read + Send>,
+    pub len: Option<u64>,
+}
+
+impl fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamBody").field("len", &self.len).finish_non_exhaustive()
+    }
+}
+
+/// Adapts an iterator of byte buffers into a `Read`, so handlers can
+/// serialize large results piece by piece.
+pub struct IterReader<I: Iterator<Item = Vec<u8>>> {
+    iter: I,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> IterReader<I> {
+    pub fn new(iter: I) -> Self {
+        Self { iter, current: Vec::new(), pos: 0 }
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Read for IterReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.current.len() {
+            match self.iter.next() {
+                Some(next) => {
+                    self.current = next;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Response {
     pub version: String,
     pub status: Status,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Vec<u8>,
+    pub stream: Option<StreamBody>,
+    pub keep_alive: bool,
+    /// Set on a 101 response: takes over the connection once the head is sent.
+    pub upgrade: Option<Upgrade>,
+    /// The stream stays open for as long as what it follows, like an event
+    /// stream; the epoll server writes it from a thread of its own.
+    pub long_lived: bool,
 }
 
 impl Response {
@@ -29,66 +171,273 @@ impl Response {
         Self {
             version: "HTTP/1.0".into(),
             status,
-            headers: HashMap::new(),
+            headers: Headers::new(),
             body: Vec::new(),
+            stream: None,
+            keep_alive: false,
+            upgrade: None,
+            long_lived: false,
         }
     }
 
     pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
         self.body = body.into();
+        self.stream = None;
+        self
+    }
+
+    /// Streams the body from `reader` without a known length.
+    pub fn with_stream(mut self, reader: impl Read + Send + 'static) -> Self {
+        self.body.clear();
+        self.stream = Some(StreamBody { reader: Box::new(reader), len: None });
+        self
+    }
+
+    /// Streams the body from `reader`, which may take indefinitely long.
+    pub fn with_long_lived_stream(mut self, reader: impl Read + Send + 'static) -> Self {
+        self = self.with_stream(reader);
+        self.long_lived = true;
+        self
+    }
+
+    /// Streams exactly `len` bytes from `reader`.
+    pub fn with_stream_len(mut self, reader: impl Read + Send + 'static, len: u64) -> Self {
+        self.body.clear();
+        self.stream = Some(StreamBody { reader: Box::new(reader), len: Some(len) });
         self
     }
 
+    /// Switches the connection to `protocol` (the `Upgrade` header value)
+    /// and hands it to `upgrade` after this response.
+    pub fn with_upgrade(mut self, protocol: &str, upgrade: Upgrade) -> Self {
+        self.status = SWITCHING_PROTOCOLS;
+        self.body.clear();
+        self.stream = None;
+        self.upgrade = Some(upgrade);
+        self.set_header("Upgrade", protocol)
+    }
+
+    /// Sets `name`, replacing any value it already had.
     pub fn set_header(mut self, name: &str, value: &str) -> Self {
-        self.headers.insert(name.to_string(), value.to_string());
+        self.headers.set(name, value);
+        self
+    }
+
+    /// Adds a value for `name` alongside existing ones (e.g. `Set-Cookie`).
+    pub fn append_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
         self
     }
 
+    /// Adds `field` to `Vary`, keeping the fields already listed.
+    pub fn vary(self, field: &str) -> Self {
+        let current = self.headers.get("Vary").unwrap_or("").to_string();
+        if current.split(',').any(|f| f.trim().eq_ignore_ascii_case(field) || f.trim() == "*") {
+            return self;
+        }
+        let value = if current.trim().is_empty() { field.to_string() } else { format!("{}, {}", current, field) };
+        self.set_header("Vary", &value)
+    }
 
-    fn http_date() -> String {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        format!("Epoch {}", now)
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
     }
 
-    pub fn to_bytes(&self, is_head: bool) -> Vec<u8> {
-        let mut buffer = String::new();
+    /// True when the body can only be delimited by closing the connection
+    /// (a stream of unknown length sent to an HTTP/1.0 client).
+    pub fn is_close_delimited(&self) -> bool {
+        self.version == "HTTP/1.0" && self.stream.as_ref().map(|s| s.len.is_none()).unwrap_or(false)
+    }
+
+    fn is_chunked(&self) -> bool {
+        self.version != "HTTP/1.0" && self.stream.as_ref().map(|s| s.len.is_none()).unwrap_or(false)
+    }
+
+    /// Fails if a header name is not a token or a value contains CR, LF or
+    /// NUL, which would let it end the head early and inject fields or a
+    /// body of its own.
+    pub fn check_headers(&self) -> Result<(), ServerError> {
+        match self.headers.iter().find(|(k, v)| !is_valid_name(k) || !is_valid_value(v)) {
+            Some((name, _)) => Err(ServerError::Internal(format!("Invalid response header '{}'", name.escape_debug()))),
+            None => Ok(()),
+        }
+    }
+
+    fn head_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.version, self.status.code, self.status.reason);
+        let mut field = |name: &str, value: &dyn fmt::Display| {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        };
 
-        let _ = write!(
-            buffer,
-            "{} {} {}\r\n",
-            self.version, self.status.code, self.status.reason
-        );
+        let keep_alive = self.keep_alive && !self.is_close_delimited();
 
-        let date = Self::http_date();
-        let body_len = self.body.len();
+        field("Date", &http_date(SystemTime::now()));
+        field("Server", &"rust-raw/0.1");
+        if self.status.code == 101 {
+            field("Connection", &"Upgrade");
+        } else {
+            field("Connection", &if keep_alive { "keep-alive" } else { "close" });
+        }
 
-        let _ = writeln!(buffer, "Date: {}", date);
-        let _ = writeln!(buffer, "Server: rust-raw/0.1");
-        let _ = writeln!(buffer, "Connection: close");
-        let _ = writeln!(buffer, "Content-Length: {}", body_len);
+        match &self.stream {
+            // After a 101 the bytes on the wire belong to the new protocol
+            None if self.status.code == 101 => {}
+            // A 304 describes the cached representation; it has no body of its own
+            None if self.status.code == 204 || self.status.code == 304 => {}
+            None => field("Content-Length", &self.body.len()),
+            Some(StreamBody { len: Some(len), .. }) => field("Content-Length", len),
+            Some(StreamBody { len: None, .. }) => {
+                if self.is_chunked() {
+                    field("Transfer-Encoding", &"chunked");
+                }
+            }
+        }
 
-        if !self.headers.contains_key("Content-Type") {
-            let _ = writeln!(buffer, "Content-Type: text/plain; charset=utf-8");
+        // Only content gets a default type; 1xx, 204 and 304 never have any
+        let bodiless = self.status.code < 200 || self.status.code == 204 || self.status.code == 304;
+        let has_content = self.stream.is_some() || !self.body.is_empty();
+        if !self.headers.contains("Content-Type") && !bodiless && has_content {
+            field("Content-Type", &"text/plain; charset=utf-8");
         }
 
         for (key, value) in &self.headers {
             let key_lower = key.to_ascii_lowercase();
-            if ["content-length", "connection", "date", "server"].contains(&key_lower.as_str()) {
+            if ["content-length", "connection", "date", "server", "transfer-encoding"].contains(&key_lower.as_str()) {
                 continue;
             }
-            let _ = writeln!(buffer, "{}: {}", key, value);
+            // `check_headers` rejects these before a handler's response is
+            // sent; never let one through regardless
+            if !is_valid_name(key) || !is_valid_value(value) {
+                continue;
+            }
+            field(key, &value);
         }
 
-        buffer.push_str("\r\n");
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
 
-        let mut response_bytes = buffer.into_bytes();
+    pub fn to_bytes(&self, is_head: bool) -> Vec<u8> {
+        let mut response_bytes = self.head_bytes();
         if !is_head {
             response_bytes.extend_from_slice(&self.body);
         }
 
         response_bytes
     }
+
+    /// Writes the response to `w`, pulling streamed bodies through in
+    /// increments. Returns the number of body bytes sent.
+    pub fn write_to<W: Write>(mut self, w: &mut W, is_head: bool) -> io::Result<u64> {
+        w.write_all(&self.head_bytes())?;
+
+        if is_head {
+            w.flush()?;
+            return Ok(0);
+        }
+
+        let chunked = self.is_chunked();
+        let sent = match self.stream.take() {
+            None => {
+                w.write_all(&self.body)?;
+                self.body.len() as u64
+            }
+            Some(StreamBody { reader, len: Some(len) }) => {
+                let copied = io::copy(&mut reader.take(len), w)?;
+                if copied < len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended before its declared length"));
+                }
+                copied
+            }
+            Some(StreamBody { mut reader, len: None }) => {
+                // Open-ended streams (e.g. event streams) may idle before
+                // their first byte; let the client see the head right away
+                w.flush()?;
+                if chunked {
+                    let mut chunks = ChunkedWriter::new(&mut *w);
+                    let copied = io::copy(&mut reader, &mut chunks)?;
+                    chunks.finish()?;
+                    copied
+                } else {
+                    copy_flushing(&mut reader, w)?
+                }
+            }
+        };
+
+        w.flush()?;
+        Ok(sent)
+    }
+}
+
+/// Like `io::copy`, but flushes after every read so each piece the reader
+/// produces goes out on its own.
+fn copy_flushing<R: Read + ?Sized, W: Write>(reader: &mut R, w: &mut W) -> io::Result<u64> {
+    let mut buf = [0u8; 16 * 1024];
+    let mut copied = 0u64;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        w.write_all(&buf[..n])?;
+        w.flush()?;
+        copied += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::time::parse_http_date;
+
+    #[test]
+    fn serializes_head_with_crlf_and_imf_date() {
+        let resp = Response::new(OK).set_header("X-Id", "7").with_body("hi");
+        let bytes = resp.to_bytes(false);
+        let text = String::from_utf8(bytes).unwrap();
+
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, "hi");
+        assert!(!head.replace("\r\n", "").contains('\n'));
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(head.contains("\r\nContent-Length: 2\r\n"));
+        assert!(head.ends_with("X-Id: 7"));
+
+        let date = head.lines().find_map(|l| l.strip_prefix("Date: ")).unwrap();
+        assert!(parse_http_date(date).is_some(), "{}", date);
+    }
+
+    #[test]
+    fn types_only_responses_with_content() {
+        let head = |resp: Response| String::from_utf8(resp.to_bytes(false)).unwrap();
+        assert!(head(Response::new(OK).with_body("hi")).contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        for resp in [Response::new(NO_CONTENT), Response::new(NOT_MODIFIED), Response::new(OK)] {
+            assert!(!head(resp).contains("Content-Type"));
+        }
+        let typed = Response::new(NOT_MODIFIED).set_header("Content-Type", "application/json");
+        assert!(head(typed).contains("Content-Type: application/json\r\n"));
+    }
+
+    #[test]
+    fn looks_up_reason_phrases() {
+        assert_eq!(Status::from_code(418).map(|s| s.reason), Some(""));
+        assert_eq!(Status::from_code(451).map(|s| s.reason), Some("Unavailable For Legal Reasons"));
+        assert_eq!(Status::from_code(502).map(|s| s.reason), Some("Bad Gateway"));
+        assert!(Status::from_code(99).is_none());
+        assert!(Status::from_code(600).is_none());
+    }
+
+    #[test]
+    fn refuses_header_injection() {
+        let resp = Response::new(OK).set_header("Location", "/a\r\nSet-Cookie: evil=1");
+        assert!(resp.check_headers().is_err());
+        assert!(Response::new(OK).set_header("Bad Name", "x").check_headers().is_err());
+
+        let text = String::from_utf8(resp.to_bytes(false)).unwrap();
+        assert!(!text.contains("evil"));
+        assert!(Response::new(OK).set_header("Location", "/a").check_headers().is_ok());
+    }
 }
@@ -0,0 +1,231 @@
+//! Path templates for the dispatcher.
+//!
+//! A template is a `/`-separated list of segments. Each segment is either
+//! static text, a named capture (`{id}`) matching exactly one segment, or a
+//! trailing wildcard (`*` or `{rest*}`) matching one or more segments.
+//!
+//! Host patterns select a virtual host: an exact name (`jobs.internal`), a
+//! wildcard subdomain (`*.internal`) or `*` for any host.
+
+use std::cmp::Reverse;
+
+use crate::http::urlencoded;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Segment {
+    /// Lower ranks win when several templates match the same path.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPattern {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl PathPattern {
+    /// Parses a template such as `/jobs/{id}/result` or `/files/*`.
+    /// Panics on a wildcard that is not the last segment, since routes are
+    /// fixed at startup and such a template is a programming error.
+    pub fn parse(template: &str) -> Self {
+        let raw: Vec<&str> = split(template).collect();
+        let segments = raw
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let segment = if *s == "*" {
+                    Segment::Wildcard("*".into())
+                } else if let Some(name) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    match name.strip_suffix('*') {
+                        Some(name) => Segment::Wildcard(name.into()),
+                        None => Segment::Param(name.into()),
+                    }
+                } else {
+                    Segment::Static(s.to_string())
+                };
+
+                assert!(
+                    !matches!(segment, Segment::Wildcard(_)) || i + 1 == raw.len(),
+                    "wildcard must be the last segment in route '{}'",
+                    template
+                );
+                segment
+            })
+            .collect();
+
+        Self { template: template.to_string(), segments }
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// Matches `path` and returns the percent-decoded captures.
+    pub fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let parts: Vec<&str> = split(path).collect();
+        let mut params = Vec::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    if i >= parts.len() {
+                        return None;
+                    }
+                    params.push((name.clone(), urlencoded::decode_path(&parts[i..].join("/"))));
+                    return Some(params);
+                }
+                Segment::Static(text) => {
+                    if parts.get(i) != Some(&text.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => match parts.get(i) {
+                    Some(value) if !value.is_empty() => params.push((name.clone(), urlencoded::decode_path(value))),
+                    _ => return None,
+                },
+            }
+        }
+
+        (parts.len() == self.segments.len()).then_some(params)
+    }
+
+    /// Orders patterns that match the same path: at the first segment where
+    /// they differ, static beats a capture and a capture beats a wildcard.
+    pub fn priority(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Exact(String),
+    /// `*.internal`, stored as `.internal`; matches any name ending in it
+    /// but not `internal` itself.
+    Subdomain(String),
+    Any,
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Self {
+        let pattern = host_name(pattern);
+        if pattern == "*" {
+            HostPattern::Any
+        } else if let Some(parent) = pattern.strip_prefix("*.") {
+            HostPattern::Subdomain(format!(".{}", parent))
+        } else {
+            HostPattern::Exact(pattern)
+        }
+    }
+
+    /// `host` must already be normalized with `host_name`.
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Subdomain(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+            HostPattern::Any => true,
+        }
+    }
+
+    /// Orders patterns matching the same host: exact names first, then
+    /// subdomain wildcards from the most specific, then `*`.
+    pub fn priority(&self) -> (u8, Reverse<usize>) {
+        match self {
+            HostPattern::Exact(_) => (0, Reverse(0)),
+            HostPattern::Subdomain(suffix) => (1, Reverse(suffix.len())),
+            HostPattern::Any => (2, Reverse(0)),
+        }
+    }
+}
+
+/// The name in a `Host` header value, lower-cased and without the port or
+/// a trailing dot. IPv6 literals keep their brackets.
+pub fn host_name(value: &str) -> String {
+    let value = value.trim();
+    let name = match value.find(']') {
+        Some(end) if value.starts_with('[') => &value[..=end],
+        _ => value.rsplit_once(':').map(|(name, _)| name).unwrap_or(value),
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+    }
+
+    #[test]
+    fn matches_static_and_captures() {
+        let p = PathPattern::parse("/jobs/{id}/result");
+        assert_eq!(p.matches("/jobs/42/result"), params(&[("id", "42")]));
+        assert_eq!(p.matches("/jobs/a%20b/result"), params(&[("id", "a b")]));
+        assert_eq!(p.matches("/jobs//result"), None);
+        assert_eq!(p.matches("/jobs/42"), None);
+        assert_eq!(p.matches("/jobs/42/result/x"), None);
+
+        assert_eq!(PathPattern::parse("/").matches("/"), params(&[]));
+        assert_eq!(PathPattern::parse("/").matches("/x"), None);
+    }
+
+    #[test]
+    fn matches_trailing_wildcards() {
+        let p = PathPattern::parse("/files/*");
+        assert_eq!(p.matches("/files/a/b.txt"), params(&[("*", "a/b.txt")]));
+        assert_eq!(p.matches("/files/"), params(&[("*", "")]));
+        assert_eq!(p.matches("/files"), None);
+
+        let named = PathPattern::parse("/static/{rest*}");
+        assert_eq!(named.matches("/static/css/x.css"), params(&[("rest", "css/x.css")]));
+    }
+
+    #[test]
+    fn static_segments_take_priority() {
+        let fixed = PathPattern::parse("/jobs/metrics");
+        let param = PathPattern::parse("/jobs/{id}");
+        let wild = PathPattern::parse("/jobs/*");
+        assert!(fixed.priority() < param.priority());
+        assert!(param.priority() < wild.priority());
+    }
+
+    #[test]
+    fn matches_host_patterns() {
+        assert_eq!(host_name("Jobs.Internal:8080"), "jobs.internal");
+        assert_eq!(host_name("example.com."), "example.com");
+        assert_eq!(host_name("[::1]:8080"), "[::1]");
+
+        let exact = HostPattern::parse("jobs.internal");
+        let sub = HostPattern::parse("*.internal");
+        assert!(exact.matches("jobs.internal"));
+        assert!(sub.matches("jobs.internal") && sub.matches("a.b.internal"));
+        assert!(!sub.matches("internal") && !sub.matches("xinternal"));
+        assert!(HostPattern::parse("*").matches("anything"));
+
+        assert!(exact.priority() < sub.priority());
+        assert!(HostPattern::parse("*.a.internal").priority() < sub.priority());
+        assert!(sub.priority() < HostPattern::Any.priority());
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_inner_wildcards() {
+        PathPattern::parse("/a/*/b");
+    }
+}
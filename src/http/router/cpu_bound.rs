@@ -6,7 +6,7 @@ use crate::http::{
     handler::{RequestHandlerStrategy, DispatcherBuilder},
     router::router::QueryParam,
     request::HttpRequest,
-    response::{Response, OK, SERVICE_UNAVAILABLE},
+    response::{Response, IterReader, OK, SERVICE_UNAVAILABLE},
     errors::ServerError,
 };
 
@@ -298,18 +298,22 @@ impl RequestHandlerStrategy for MandelbrotHandler {
         if let Some(result) = run_with_timeout(timeout_ms, move || mandelbrot(width, height, max_iter, None)) {
             let ((map, mandelbrot_elapsed), _) = result;
 
-            let rows_json = map.iter()
-                .map(|row| format!("[{}]", row.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")))
-                .collect::<Vec<_>>()
-                .join(",");
-
-            let json = format!(
-                "{{\"width\": {}, \"height\": {}, \"max_iter\": {}, \"elapsed_ms\": {}, \"map\": [{}]}}",
-                width, height, max_iter, mandelbrot_elapsed, rows_json
+            // The map can be large: serialize it row by row while sending
+            let head = format!(
+                "{{\"width\": {}, \"height\": {}, \"max_iter\": {}, \"elapsed_ms\": {}, \"map\": [",
+                width, height, max_iter, mandelbrot_elapsed
             );
+            let rows = map.into_iter().enumerate().map(|(i, row)| {
+                let sep = if i > 0 { "," } else { "" };
+                format!("{}[{}]", sep, row.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")).into_bytes()
+            });
+            let body = std::iter::once(head.into_bytes())
+                .chain(rows)
+                .chain(std::iter::once(b"]}".to_vec()));
+
             return Ok(Response::new(OK)
                 .set_header("Content-Type", "application/json")
-                .with_body(json));
+                .with_stream(IterReader::new(body)));
         }
 
         let mut params = HashMap::new();
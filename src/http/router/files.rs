@@ -0,0 +1,435 @@
+use std::env;
+use std::fs::Metadata;
+use std::io::{self, ErrorKind, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::http::{
+    handler::{RequestHandlerStrategy, DispatcherBuilder},
+    router::router::{PathParam, QueryParam},
+    request::HttpRequest,
+    response::{Response, OK, CREATED, NO_CONTENT, PARTIAL_CONTENT, NOT_MODIFIED, RANGE_NOT_SATISFIABLE},
+    errors::ServerError,
+    multipart,
+};
+
+use crate::utils::{
+    file::{delete_file_in, is_plain_name, open_file, stage_file, storage_dir, store_file, StoredFile},
+    time::{http_date, parse_http_date},
+};
+
+/// POST /files, PUT /files/<name>
+///
+/// POST accepts either a `multipart/form-data` body, storing every part
+/// that carries a filename, or a raw body (e.g. `application/octet-stream`)
+/// stored under `?name=FILE`. PUT stores the raw body under the name in the
+/// path, answering 201 for a new file and 200 for a replaced one.
+///
+/// Bodies of up to `max_bytes` are accepted whatever the server's
+/// `max_body_bytes`; the part past that is spooled to disk. Files are
+/// stored in `dir`.
+pub struct FileUploadHandler {
+    pub max_bytes: u64,
+    pub dir: PathBuf,
+}
+
+impl FileUploadHandler {
+    pub fn from_env() -> Self {
+        let max_bytes = env::var("UPLOAD_MAX_BYTES").ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(10 * 1024 * 1024);
+        Self { max_bytes, dir: storage_dir() }
+    }
+
+    fn store<R: io::Read>(&self, name: &str, data: R) -> Result<StoredFile, ServerError> {
+        let stored = store_file(&self.dir, name, data).map_err(upload_error)?;
+        println!("File uploaded: '{}' ({} bytes)", stored.name, stored.size);
+        Ok(stored)
+    }
+
+    /// Stores every upload or none: all are written before any takes its
+    /// name. Should moving one into place fail, the files already created
+    /// are removed again; a file that was replaced cannot be brought back.
+    fn store_all<'a>(&self, uploads: impl Iterator<Item = (&'a str, &'a [u8])>) -> Result<Vec<StoredFile>, ServerError> {
+        let staged = uploads
+            .map(|(name, data)| stage_file(&self.dir, name, data))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(upload_error)?;
+
+        let mut files: Vec<StoredFile> = Vec::new();
+        for upload in staged {
+            match upload.commit() {
+                Ok(file) => files.push(file),
+                Err(e) => {
+                    for file in files.iter().filter(|f| f.created) {
+                        let _ = delete_file_in(&self.dir, &file.name);
+                    }
+                    return Err(upload_error(e));
+                }
+            }
+        }
+        for file in &files {
+            println!("File uploaded: '{}' ({} bytes)", file.name, file.size);
+        }
+        Ok(files)
+    }
+}
+
+fn describe(stored: &StoredFile) -> Value {
+    json!({ "file": stored.name, "size": stored.size, "sha256": stored.sha256 })
+}
+
+/// Names given explicitly (the PUT path, `?name=`) are used as they are,
+/// so anything that is not a plain file name is refused rather than cut
+/// down to its last component.
+fn plain_name(name: &str) -> Result<&str, ServerError> {
+    if is_plain_name(name) {
+        Ok(name)
+    } else {
+        Err(ServerError::BadRequest(format!("Invalid file name '{}'", name)))
+    }
+}
+
+impl RequestHandlerStrategy for FileUploadHandler {
+    fn max_body_bytes(&self) -> Option<u64> {
+        Some(self.max_bytes)
+    }
+
+    fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
+        if let Some(name) = req.path_param("name") {
+            let stored = self.store(plain_name(name)?, req.body_reader())?;
+            return Ok(Response::new(if stored.created { CREATED } else { OK })
+                .set_header("Content-Type", "application/json")
+                .with_body(describe(&stored).to_string()));
+        }
+
+        let content_type = req.header("Content-Type").unwrap_or("application/octet-stream");
+
+        let body = match multipart::boundary(content_type) {
+            Some(boundary) => {
+                let mapped;
+                let data: &[u8] = match &req.spooled_body {
+                    Some(spooled) => {
+                        mapped = spooled.map()?;
+                        &mapped
+                    }
+                    None => &req.body,
+                };
+                let parts = multipart::parse(data, &boundary)?;
+
+                let uploads = parts
+                    .iter()
+                    .filter_map(|p| p.filename.as_deref().filter(|f| !f.is_empty()).map(|f| (f, p.data)));
+                let files = self.store_all(uploads)?;
+
+                if files.is_empty() {
+                    return Err(ServerError::BadRequest("No file part in multipart body".into()));
+                }
+                json!({ "files": files.iter().map(describe).collect::<Vec<_>>() })
+            }
+            None => {
+                let name = req.query_param("name")
+                    .ok_or_else(|| ServerError::BadRequest("Missing query parameter 'name'".into()))?;
+                describe(&self.store(plain_name(name)?, req.body_reader())?)
+            }
+        };
+
+        Ok(Response::new(CREATED)
+            .set_header("Content-Type", "application/json")
+            .with_body(body.to_string()))
+    }
+}
+
+/// GET|HEAD /files/<name>
+///
+/// Streams a file stored in `dir` from disk, with validators for
+/// conditional requests and support for a single byte range.
+pub struct FileDownloadHandler {
+    pub dir: PathBuf,
+}
+
+impl RequestHandlerStrategy for FileDownloadHandler {
+    fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
+        let name = req.path_param("name").unwrap_or_default().to_string();
+
+        let (mut file, meta) = open_file(&self.dir, &name).map_err(|e| match e.kind() {
+            ErrorKind::NotFound | ErrorKind::PermissionDenied => ServerError::NotFound,
+            _ => ServerError::Internal(format!("Failed to open '{}': {}", name, e)),
+        })?;
+
+        let size = meta.len();
+        let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+        let etag = etag(&meta);
+        let last_modified = http_date(modified);
+
+        let base = |status| {
+            Response::new(status)
+                .set_header("ETag", &etag)
+                .set_header("Last-Modified", &last_modified)
+                .set_header("Accept-Ranges", "bytes")
+        };
+
+        if not_modified(req, &etag, modified) {
+            return Ok(base(NOT_MODIFIED));
+        }
+
+        // A stale If-Range validator means "send the whole thing"
+        let range_header = match req.header("If-Range") {
+            Some(v) if v.trim() != etag && v.trim() != last_modified => None,
+            _ => req.header("Range"),
+        };
+
+        let (status, start, len) = match byte_range(range_header, size) {
+            ByteRange::Full => (OK, 0, size),
+            ByteRange::Partial(start, end) => (PARTIAL_CONTENT, start, end - start + 1),
+            ByteRange::Unsatisfiable => {
+                return Ok(base(RANGE_NOT_SATISFIABLE)
+                    .set_header("Content-Range", &format!("bytes */{}", size)));
+            }
+        };
+
+        file.seek(SeekFrom::Start(start))?;
+        let mut resp = base(status)
+            .set_header("Content-Type", content_type(&name))
+            .with_stream_len(file, len);
+        if status.code == PARTIAL_CONTENT.code {
+            resp = resp.set_header("Content-Range", &format!("bytes {}-{}/{}", start, start + len - 1, size));
+        }
+        Ok(resp)
+    }
+}
+
+/// DELETE /files/<name>
+pub struct FileDeleteHandler {
+    pub dir: PathBuf,
+}
+
+impl RequestHandlerStrategy for FileDeleteHandler {
+    fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
+        let name = req.path_param("name").unwrap_or_default();
+
+        // Same visibility rules as downloads: hidden and missing files are 404
+        open_file(&self.dir, name).map_err(|e| match e.kind() {
+            ErrorKind::NotFound | ErrorKind::PermissionDenied => ServerError::NotFound,
+            _ => ServerError::Internal(format!("Failed to open '{}': {}", name, e)),
+        })?;
+        delete_file_in(&self.dir, name).map_err(|e| ServerError::Internal(format!("Failed to delete '{}': {}", name, e)))?;
+
+        println!("File deleted: '{}'", name);
+        Ok(Response::new(NO_CONTENT))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// Inclusive first and last byte positions.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Interprets a `Range` header against a file of `size` bytes. Anything
+/// other than a single well-formed `bytes=` range (including multiple
+/// ranges) is ignored and the full file is sent.
+fn byte_range(header: Option<&str>, size: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else { return ByteRange::Full };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else { return ByteRange::Full };
+
+    let range = match (first.trim(), last.trim()) {
+        ("", "") => return ByteRange::Full,
+        // Suffix range: the last N bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (size.saturating_sub(n), size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (first, last) => {
+            let Ok(start) = first.parse::<u64>() else { return ByteRange::Full };
+            let end = match last {
+                "" => size.saturating_sub(1),
+                last => match last.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(size.saturating_sub(1)),
+                    _ => return ByteRange::Full,
+                },
+            };
+            (start, end)
+        }
+    };
+
+    if size == 0 || range.0 >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range.0, range.1)
+    }
+}
+
+/// `If-None-Match` takes precedence; `If-Modified-Since` is only
+/// consulted when it is absent.
+fn not_modified(req: &HttpRequest, etag: &str, modified: SystemTime) -> bool {
+    if let Some(tags) = req.header("If-None-Match") {
+        let bare = etag.trim_start_matches("W/");
+        return tags
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == bare);
+    }
+
+    match req.header("If-Modified-Since").and_then(parse_http_date) {
+        // Header dates have one-second resolution
+        Some(since) => secs(modified) <= secs(since),
+        None => false,
+    }
+}
+
+fn etag(meta: &Metadata) -> String {
+    let mtime = meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", meta.len(), mtime)
+}
+
+fn secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn content_type(name: &str) -> &'static str {
+    let ext = name.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase()).unwrap_or_default();
+    match ext.as_str() {
+        "txt" | "log" | "csv" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" | "jsonl" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "gz" => "application/gzip",
+        "xz" => "application/x-xz",
+        "zip" => "application/zip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+fn upload_error(e: io::Error) -> ServerError {
+    match e.kind() {
+        ErrorKind::InvalidInput => ServerError::BadRequest(e.to_string()),
+        _ => ServerError::Internal(format!("Failed to store upload: {}", e)),
+    }
+}
+
+pub fn register(builder: DispatcherBuilder) -> DispatcherBuilder {
+    register_with(builder, FileUploadHandler::from_env())
+}
+
+/// The file routes, all serving `upload.dir`.
+fn register_with(builder: DispatcherBuilder, upload: FileUploadHandler) -> DispatcherBuilder {
+    let dir = upload.dir.clone();
+    let upload = Arc::new(upload);
+    builder
+        .post("/files", upload.clone())
+        .get("/files/{name}", Arc::new(FileDownloadHandler { dir: dir.clone() }))
+        .put("/files/{name}", upload)
+        .delete("/files/{name}", Arc::new(FileDeleteHandler { dir }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("files-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(byte_range(None, 100), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=0-9"), 100), ByteRange::Partial(0, 9));
+        assert_eq!(byte_range(Some("bytes=90-"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(byte_range(Some("bytes=-10"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(byte_range(Some("bytes=-500"), 100), ByteRange::Partial(0, 99));
+        assert_eq!(byte_range(Some("bytes=50-500"), 100), ByteRange::Partial(50, 99));
+    }
+
+    #[test]
+    fn ignores_or_rejects_other_ranges() {
+        assert_eq!(byte_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(byte_range(Some("items=0-1"), 100), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=9-1"), 100), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=100-"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn guesses_content_types() {
+        assert_eq!(content_type("report.JSON"), "application/json");
+        assert_eq!(content_type("data.txt.gz"), "application/gzip");
+        assert_eq!(content_type("numbers_sorted_merge"), "application/octet-stream");
+    }
+
+    #[test]
+    fn multipart_uploads_are_all_or_nothing() {
+        let dir = test_dir("multipart");
+        let body = "--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"first-part.txt\"\r\n\r\none\r\n\
+--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"..\"\r\n\r\ntwo\r\n--b--\r\n";
+        let raw = format!(
+            "POST /files HTTP/1.1\r\nHost: x\r\nContent-Type: multipart/form-data; boundary=b\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let req = HttpRequest::parse(&mut io::Cursor::new(raw.into_bytes())).unwrap();
+
+        let handler = FileUploadHandler { max_bytes: 1024, dir: dir.clone() };
+        assert!(matches!(handler.handle(&req), Err(ServerError::BadRequest(_))));
+        assert!(open_file(&dir, "first-part.txt").is_err());
+        // Nor any staged part left behind
+        assert_eq!(std::fs::read_dir(&dir).map(|d| d.count()).unwrap_or(0), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn put_get_and_delete_files_by_name() {
+        let dir = test_dir("routes");
+        let upload = FileUploadHandler { max_bytes: 1024, dir: dir.clone() };
+        let dispatcher = register_with(crate::http::handler::Dispatcher::builder(), upload).build();
+        let send = |raw: &str| {
+            let mut req = HttpRequest::parse(&mut io::Cursor::new(raw.as_bytes().to_vec())).unwrap();
+            dispatcher.dispatch(&mut req).unwrap()
+        };
+
+        let put = "PUT /files/routes-test.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(send(put).status.code, 201);
+        assert_eq!(send(put).status.code, 200);
+        let got = send("GET /files/routes-test.txt HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(got.status.code, 200);
+        let mut content = String::new();
+        got.stream.unwrap().reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+
+        assert_eq!(send("DELETE /files/routes-test.txt HTTP/1.1\r\nHost: x\r\n\r\n").status.code, 204);
+        assert_eq!(send("DELETE /files/routes-test.txt HTTP/1.1\r\nHost: x\r\n\r\n").status.code, 404);
+
+        // Not cut down to `b`
+        assert_eq!(send("PUT /files/a%2Fb HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\n\r\nx").status.code, 400);
+        assert!(open_file(&dir, "b").is_err());
+        assert_eq!(send("POST /files?name=..%2Fup HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\n\r\nx").status.code, 400);
+
+        assert_eq!(send("POST /files?name=routes-raw.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\n\r\nx").status.code, 201);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
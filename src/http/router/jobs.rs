@@ -1,15 +1,63 @@
+    use std::env;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
 
     use crate::http::{
+        connection_pool,
         handler::{RequestHandlerStrategy, DispatcherBuilder},
-        request::HttpRequest,
-        response::{Response, OK, SERVICE_UNAVAILABLE},
+        request::{HttpRequest, HttpMethod},
+        response::{Response, IterReader, OK, CREATED, CONFLICT, NOT_FOUND, SERVICE_UNAVAILABLE},
         errors::ServerError,
-        router::router::QueryParam,
+        router::router::{PathParam, QueryParam},
     };
     use crate::jobs::manager::JobManager;
-    use crate::jobs::job::{JobStatus, Priority};
+    use crate::jobs::job::{Job, JobStatus, Priority};
+    use crate::utils::signal;
+
+    /// The job id comes from the path (`/jobs/{id}/...`) or `?id=`.
+    fn job_id(req: &HttpRequest) -> Result<&str, ServerError> {
+        req.path_param("id")
+            .or_else(|| req.query_param("id"))
+            .ok_or_else(|| ServerError::BadRequest("Missing query parameter 'id'".into()))
+    }
+
+    pub(crate) fn status_name(status: &JobStatus) -> &'static str {
+        match status {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Error(_) => "error",
+            JobStatus::Canceled => "canceled",
+            JobStatus::Timeout => "timeout",
+        }
+    }
+
+    /// Unknown names fall back to normal priority.
+    pub(crate) fn parse_priority(name: &str) -> Priority {
+        match name.to_lowercase().as_str() {
+            "low" => Priority::Low,
+            "high" => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+
+    /// Progress as a whole percentage.
+    fn percent(progress: f32) -> u32 {
+        (progress * 100.0).round() as u32
+    }
+
+    /// The JSON pushed to event-stream and WebSocket clients.
+    pub(crate) fn job_state(id: &str, status: &JobStatus, progress: f32) -> Value {
+        let mut data = json!({ "id": id, "status": status_name(status), "progress": percent(progress) });
+        if let JobStatus::Error(msg) = status {
+            data["error"] = json!(msg);
+        }
+        data
+    }
 
     pub struct JobResultHandler {
         pub job_manager: Arc<JobManager>,
@@ -17,8 +65,7 @@
 
     impl RequestHandlerStrategy for JobResultHandler {
         fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
-            let id = req.query_param("id")
-                .ok_or_else(|| ServerError::BadRequest("Missing query parameter 'id'".into()))?;
+            let id = job_id(req)?;
 
             if id.trim().is_empty() {
                 return Err(ServerError::BadRequest("Parameter 'id' cannot be empty".into()));
@@ -55,7 +102,7 @@
                 },
                 None => {
                     let json = format!("{{\"id\":\"{}\",\"error\":\"Job not found\"}}", id);
-                    Ok(Response::new(crate::http::response::NOT_FOUND)
+                    Ok(Response::new(NOT_FOUND)
                         .set_header("Content-Type", "application/json")
                         .with_body(json))
                 }
@@ -69,32 +116,25 @@
 
     impl RequestHandlerStrategy for JobStatusHandler {
         fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
-            let id = req.query_param("id")
-                .ok_or_else(|| ServerError::BadRequest("Missing query parameter 'id'".into()))?;
+            let id = job_id(req)?;
 
             if id.trim().is_empty() {
                 return Err(ServerError::BadRequest("Parameter 'id' cannot be empty".into()));
             }
 
-            match self.job_manager.status(id) {
-                Some(status) => {
+            match self.job_manager.job(id) {
+                Some(job) => {
+                    let (status, progress) = job.snapshot();
                     let (progress, eta) = match status {
-                        JobStatus::Queued => (0, "unknown"),
-                        JobStatus::Running => (50, "estimating"),
-                        JobStatus::Done => (100, "0s"),
-                        JobStatus::Error(_) => (100, "n/a"),
-                        JobStatus::Canceled => (0, "n/a"),
-                        JobStatus::Timeout => (100, "n/a"),
+                        JobStatus::Queued => (0, "unknown".to_string()),
+                        JobStatus::Running => (percent(progress), eta(&job, progress)),
+                        JobStatus::Done => (100, "0s".to_string()),
+                        JobStatus::Error(_) => (100, "n/a".to_string()),
+                        JobStatus::Canceled => (0, "n/a".to_string()),
+                        JobStatus::Timeout => (100, "n/a".to_string()),
                     };
 
-                    let status_str = match &status {
-                        JobStatus::Queued => "queued",
-                        JobStatus::Running => "running",
-                        JobStatus::Done => "done",
-                        JobStatus::Error(_) => "error",
-                        JobStatus::Canceled => "canceled",
-                        JobStatus::Timeout => "timeout",
-                    };
+                    let status_str = status_name(&status);
 
                     let json = format!(
                         "{{\"id\":\"{}\",\"status\":\"{}\",\"progress\":{},\"eta\":\"{}\"}}",
@@ -107,7 +147,7 @@
                 }
                 None => {
                     let json = format!("{{\"id\":\"{}\",\"error\":\"Job not found\"}}", id);
-                    Ok(Response::new(crate::http::response::NOT_FOUND)
+                    Ok(Response::new(NOT_FOUND)
                         .set_header("Content-Type", "application/json")
                         .with_body(json))
                 }
@@ -115,6 +155,17 @@
         }
     }
 
+    /// Time left for a running job at its rate so far.
+    fn eta(job: &Job, progress: f32) -> String {
+        match *job.started_at.lock().unwrap() {
+            Some(started) if progress > 0.0 => {
+                let elapsed = started.elapsed().as_secs_f32();
+                format!("{}s", (elapsed * (1.0 - progress) / progress).ceil() as u64)
+            }
+            _ => "estimating".to_string(),
+        }
+    }
+
     pub struct JobSubmitHandler {
         pub job_manager: Arc<JobManager>,
     }
@@ -130,18 +181,17 @@
             }
 
             let priority_str = req.query_param("priority").unwrap_or("normal");
-            let priority = match priority_str.to_lowercase().as_str() {
-                "low" => Priority::Low,
-                "high" => Priority::High,
-                "normal" | _ => Priority::Normal,
-            };
-
-            let mut params = HashMap::new();
-            for pair in req.query.split('&') {
-                if let Some((k, v)) = pair.split_once('=') {
-                    if k != "task" && k != "priority" {
-                        params.insert(k.to_string(), v.to_string());
-                    }
+            let priority = parse_priority(priority_str);
+
+            // Jobs take one value per parameter; joining repeated keys would
+            // make `a=1&a=2` indistinguishable from `a=1,2`
+            let mut params: HashMap<String, String> = HashMap::new();
+            for (k, v) in &req.params {
+                if req.query_params(k).len() > 1 {
+                    return Err(ServerError::BadRequest(format!("Parameter '{}' is repeated", k)));
+                }
+                if k != "task" && k != "priority" {
+                    params.insert(k.clone(), v.clone());
                 }
             }
 
@@ -154,8 +204,11 @@
 
                     println!("Job submitted: id='{}', task='{}'", job_id, task);
 
-                    Ok(Response::new(OK)
+                    // The legacy GET alias keeps answering 200
+                    let status = if req.method == HttpMethod::GET { OK } else { CREATED };
+                    Ok(Response::new(status)
                         .set_header("Content-Type", "application/json")
+                        .set_header("Location", &format!("/jobs/{}", job_id))
                         .with_body(json))
                 }
 
@@ -182,13 +235,13 @@
 
     impl RequestHandlerStrategy for JobCancelHandler {
         fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
-            let id = req.query_param("id")
-                .ok_or_else(|| ServerError::BadRequest("Missing query parameter 'id'".into()))?;
+            let id = job_id(req)?;
 
             if id.trim().is_empty() {
                 return Err(ServerError::BadRequest("Parameter 'id' cannot be empty".into()));
             }
 
+            let known = self.job_manager.status(id).is_some();
             let canceled = self.job_manager.cancel(id);
 
             let status_str = if canceled { "canceled".to_string() } else { "not_cancelable".to_string() };
@@ -198,76 +251,374 @@
                 id, status_str
             );
 
-            Ok(Response::new(OK)
+            // The legacy GET alias answers 200 either way
+            let status = match (canceled, known) {
+                _ if req.method == HttpMethod::GET => OK,
+                (true, _) => OK,
+                (false, true) => CONFLICT,
+                (false, false) => {
+                    let json = format!("{{\"id\":\"{}\",\"error\":\"Job not found\"}}", id);
+                    return Ok(Response::new(NOT_FOUND)
+                        .set_header("Content-Type", "application/json")
+                        .with_body(json));
+                }
+            };
+
+            Ok(Response::new(status)
                 .set_header("Content-Type", "application/json")
                 .with_body(json))
         }
     }
 
-    pub struct JobMetricsHandler {
+    /// GET /jobs/{id}/events
+    ///
+    /// A `text/event-stream` of the job's state: a `status` event now and on
+    /// every status change, and a `progress` event whenever a running task
+    /// reports another percent. The stream ends after the terminal status.
+    ///
+    /// At most `max_streams` are open at once; further subscribers get a 503.
+    pub const DEFAULT_EVENT_STREAMS: usize = 256;
+
+    pub struct JobEventsHandler {
         pub job_manager: Arc<JobManager>,
+        pub max_streams: usize,
+        streams: Arc<AtomicUsize>,
     }
 
-    impl RequestHandlerStrategy for JobMetricsHandler {
-        fn handle(&self, _req: &HttpRequest) -> Result<Response, ServerError> {
-            let pools = self.job_manager.get_metrics();
-            let mut pools_json = Vec::new();
-
-            let ordered_names = ["cpu", "io"];
-
-            for &name in &ordered_names {
-                if let Some(metrics) = pools.get(name) {
-                    let queue_lengths = metrics.queue_lengths;
-                    let wm = &metrics.worker_metrics;
-
-                    let active = *wm.active_workers.lock().unwrap();
-                    let total = wm.total_workers;
-                    let total_jobs = *wm.total_jobs.lock().unwrap();
-
-                    let avg_wait = wm.avg_wait.lock().unwrap().as_millis();
-                    let avg_exec = wm.avg_exec.lock().unwrap().as_millis();
-                    let avg_total = wm.avg_total.lock().unwrap().as_millis();
-
-                    let std_wait = wm.std_wait_ms();
-                    let std_exec = wm.std_exec_ms();
-
-                    pools_json.push(format!(
-                        r#""{}":{{
-                            "queue_size": {{"high": {}, "normal": {}, "low": {}}},
-                            "workers": {{"active": {}, "total": {}}},
-                            "jobs": {{"total": {}}},
-                            "timings": {{
-                                "avg_wait_ms": {},
-                                "avg_exec_ms": {},
-                                "avg_total_ms": {},
-                                "std_dev_wait_ms": {:.2},
-                                "std_dev_exec_ms": {:.2}
-                            }}
-                        }}"#,
-                        name,
-                        queue_lengths.0, queue_lengths.1, queue_lengths.2,
-                        active, total,
-                        total_jobs,
-                        avg_wait, avg_exec, avg_total,
-                        std_wait, std_exec
-                    ));
+    impl JobEventsHandler {
+        pub fn new(job_manager: Arc<JobManager>, max_streams: usize) -> Self {
+            Self { job_manager, max_streams, streams: Arc::new(AtomicUsize::new(0)) }
+        }
+
+        pub fn from_env(job_manager: Arc<JobManager>) -> Self {
+            let max_streams = match env::var("JOB_EVENT_STREAMS").ok().map(|v| v.trim().parse::<usize>()) {
+                None => DEFAULT_EVENT_STREAMS,
+                Some(Ok(n)) if n > 0 => n,
+                Some(_) => {
+                    eprintln!("JOB_EVENT_STREAMS must be a positive number; using {}", DEFAULT_EVENT_STREAMS);
+                    DEFAULT_EVENT_STREAMS
+                }
+            };
+            Self::new(job_manager, max_streams)
+        }
+    }
+
+    impl RequestHandlerStrategy for JobEventsHandler {
+        fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
+            let id = job_id(req)?;
+            let job = self.job_manager.job(id).ok_or(ServerError::NotFound)?;
+
+            let Some(slot) = StreamSlot::take(&self.streams, self.max_streams) else {
+                return Ok(Response::new(SERVICE_UNAVAILABLE)
+                    .set_header("Content-Type", "application/json")
+                    .set_header("Retry-After", "1")
+                    .with_body("{\"error\":\"Too many event streams\"}"));
+            };
+
+            let events = JobEvents { job, last: None, last_sent: Instant::now(), seq: 0, done: false, _slot: slot };
+            Ok(Response::new(OK)
+                .set_header("Content-Type", "text/event-stream")
+                .set_header("Cache-Control", "no-cache")
+                .with_long_lived_stream(IterReader::new(events)))
+        }
+    }
+
+    /// One of the open event streams; given back when the stream is dropped.
+    struct StreamSlot(Arc<AtomicUsize>);
+
+    impl StreamSlot {
+        fn take(streams: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+            streams
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| (open < max).then_some(open + 1))
+                .ok()
+                .map(|_| Self(Arc::clone(streams)))
+        }
+    }
+
+    impl Drop for StreamSlot {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// How often the stream checks for shutdown while the job is quiet.
+    const EVENT_POLL: Duration = Duration::from_secs(1);
+    /// Comment lines keep proxies from timing out an idle stream.
+    const EVENT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+    struct JobEvents {
+        job: Arc<Job>,
+        last: Option<(JobStatus, f32)>,
+        last_sent: Instant,
+        seq: u64,
+        done: bool,
+        _slot: StreamSlot,
+    }
+
+    impl Iterator for JobEvents {
+        type Item = Vec<u8>;
+
+        fn next(&mut self) -> Option<Vec<u8>> {
+            if self.done {
+                return None;
+            }
+
+            loop {
+                let current = match &self.last {
+                    None => self.job.snapshot(),
+                    Some(seen) => self.job.wait_for_change(seen, EVENT_POLL),
+                };
+                if signal::shutdown_requested() {
+                    return None;
+                }
+
+                if self.last.as_ref() == Some(&current) {
+                    if self.last_sent.elapsed() < EVENT_KEEP_ALIVE {
+                        continue;
+                    }
+                    self.last_sent = Instant::now();
+                    return Some(b": keep-alive\n\n".to_vec());
                 }
+
+                let (status, progress) = &current;
+                let (event, data) = match &self.last {
+                    Some((seen, _)) if seen == status => ("progress", json!({ "id": self.job.id, "progress": percent(*progress) })),
+                    _ => ("status", job_state(&self.job.id, status, *progress)),
+                };
+
+                self.seq += 1;
+                self.done = status.is_terminal();
+                self.last_sent = Instant::now();
+                self.last = Some(current);
+                return Some(format!("id: {}\nevent: {}\ndata: {}\n\n", self.seq, event, data).into_bytes());
             }
+        }
+    }
 
-            let json = format!("{{\"pools\":{{{}}}}}", pools_json.join(","));
+    pub struct JobMetricsHandler {
+        pub job_manager: Arc<JobManager>,
+    }
 
+    impl RequestHandlerStrategy for JobMetricsHandler {
+        fn handle(&self, _req: &HttpRequest) -> Result<Response, ServerError> {
             Ok(Response::new(OK)
                 .set_header("Content-Type", "application/json")
-                .with_body(json))
+                .with_body(metrics_json(&self.job_manager)))
+        }
+    }
+
+    /// Queue, worker and timing figures for every job pool and for the
+    /// connection pool.
+    pub(crate) fn metrics_json(job_manager: &JobManager) -> String {
+        let pools = job_manager.get_metrics();
+        let mut pools_json = Vec::new();
+
+        let ordered_names = ["cpu", "io"];
+
+        for &name in &ordered_names {
+            if let Some(metrics) = pools.get(name) {
+                let queue_lengths = metrics.queue_lengths;
+                let wm = &metrics.worker_metrics;
+
+                let active = *wm.active_workers.lock().unwrap();
+                let total = wm.total_workers;
+                let total_jobs = *wm.total_jobs.lock().unwrap();
+
+                let avg_wait = wm.avg_wait.lock().unwrap().as_millis();
+                let avg_exec = wm.avg_exec.lock().unwrap().as_millis();
+                let avg_total = wm.avg_total.lock().unwrap().as_millis();
+
+                let std_wait = wm.std_wait_ms();
+                let std_exec = wm.std_exec_ms();
+
+                pools_json.push(format!(
+                    r#""{}":{{
+                        "queue_size": {{"high": {}, "normal": {}, "low": {}}},
+                        "workers": {{"active": {}, "total": {}}},
+                        "jobs": {{"total": {}}},
+                        "timings": {{
+                            "avg_wait_ms": {},
+                            "avg_exec_ms": {},
+                            "avg_total_ms": {},
+                            "std_dev_wait_ms": {:.2},
+                            "std_dev_exec_ms": {:.2}
+                        }}
+                    }}"#,
+                    name,
+                    queue_lengths.0, queue_lengths.1, queue_lengths.2,
+                    active, total,
+                    total_jobs,
+                    avg_wait, avg_exec, avg_total,
+                    std_wait, std_exec
+                ));
+            }
         }
+
+        let conns = connection_pool::stats();
+        let connections_json = format!(
+            r#"{{
+                "threads": {{"busy": {}, "total": {}}},
+                "queue": {{"depth": {}, "capacity": {}}},
+                "accepted": {},
+                "rejected": {},
+                "shed": {},
+                "timings": {{"avg_queue_wait_ms": {:.2}, "max_queue_wait_ms": {:.2}}}
+            }}"#,
+            conns.busy, conns.threads,
+            conns.queued, conns.queue_capacity,
+            conns.accepted,
+            conns.rejected,
+            conns.shed,
+            conns.avg_wait.as_secs_f64() * 1000.0, conns.max_wait.as_secs_f64() * 1000.0
+        );
+
+        format!("{{\"pools\":{{{}}},\"connections\":{}}}", pools_json.join(","), connections_json)
     }
 
 
+    /// `POST /jobs`, `GET /jobs/{id}` and `DELETE /jobs/{id}` are the
+    /// resource-style routes; the query-string ones remain as aliases.
     pub fn register(builder: DispatcherBuilder, job_manager: Arc<JobManager>) -> DispatcherBuilder {
         builder
+            .post("/jobs", Arc::new(JobSubmitHandler { job_manager: job_manager.clone() }))
+            .delete("/jobs/{id}", Arc::new(JobCancelHandler { job_manager: job_manager.clone() }))
             .get("/jobs/result", Arc::new(JobResultHandler { job_manager: job_manager.clone() }))
             .get("/jobs/status", Arc::new(JobStatusHandler { job_manager: job_manager.clone() }))
             .get("/jobs/submit", Arc::new(JobSubmitHandler { job_manager: job_manager.clone() }))
+            .post("/jobs/submit", Arc::new(JobSubmitHandler { job_manager: job_manager.clone() }))
             .get("/jobs/cancel", Arc::new(JobCancelHandler { job_manager: job_manager.clone() }))
+            .get("/jobs/{id}", Arc::new(JobStatusHandler { job_manager: job_manager.clone() }))
+            .get("/jobs/{id}/result", Arc::new(JobResultHandler { job_manager: job_manager.clone() }))
+            .get("/jobs/{id}/events", Arc::new(JobEventsHandler::from_env(job_manager.clone())))
+            .post("/jobs/{id}/cancel", Arc::new(JobCancelHandler { job_manager: job_manager.clone() }))
             .get("/metrics", Arc::new(JobMetricsHandler { job_manager }))
-    }
\ No newline at end of file
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::{Cursor, Read};
+        use crate::http::handler::Dispatcher;
+
+        fn request(raw: &str) -> HttpRequest {
+            HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap()
+        }
+
+        #[test]
+        fn submit_rejects_repeated_parameters() {
+            let path = std::env::temp_dir().join(format!("jobs-routes-{}.jsonl", std::process::id()));
+            let dispatcher = register(Dispatcher::builder(), JobManager::with_persist_path(0, 0, path.clone())).build();
+
+            let repeated = dispatcher.dispatch(&mut request("POST /jobs?task=isprime&n=7&n=11 HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+            assert_eq!(repeated.status.code, 400);
+            let listed = dispatcher.dispatch(&mut request("POST /jobs?task=isprime&n=7,11 HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+            assert_eq!(listed.status.code, 201);
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn submits_looks_up_and_cancels_jobs() {
+            let path = std::env::temp_dir().join(format!("jobs-lifecycle-{}.jsonl", std::process::id()));
+            let dispatcher = register(Dispatcher::builder(), JobManager::with_persist_path(0, 0, path.clone())).build();
+
+            // No workers, so the job stays queued until it is canceled
+            let submitted = dispatcher.dispatch(&mut request("POST /jobs?task=isprime&n=7 HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+            assert_eq!(submitted.status.code, 201);
+            let body: serde_json::Value = serde_json::from_slice(&submitted.body).unwrap();
+            let id = body["job_id"].as_str().unwrap().to_string();
+
+            let status = dispatcher.dispatch(&mut request(&format!("GET /jobs/{} HTTP/1.1\r\nHost: x\r\n\r\n", id))).unwrap();
+            assert_eq!(status.status.code, 200);
+
+            let canceled = dispatcher.dispatch(&mut request(&format!("DELETE /jobs/{} HTTP/1.1\r\nHost: x\r\n\r\n", id))).unwrap();
+            assert_eq!(canceled.status.code, 200);
+            assert!(String::from_utf8_lossy(&canceled.body).contains("\"status\":\"canceled\""));
+            let again = dispatcher.dispatch(&mut request(&format!("DELETE /jobs/{} HTTP/1.1\r\nHost: x\r\n\r\n", id))).unwrap();
+            assert_eq!(again.status.code, 409);
+            assert!(String::from_utf8_lossy(&again.body).contains("\"status\":\"not_cancelable\""));
+            let legacy = dispatcher.dispatch(&mut request(&format!("GET /jobs/cancel?id={} HTTP/1.1\r\nHost: x\r\n\r\n", id))).unwrap();
+            assert_eq!(legacy.status.code, 200);
+            let missing = dispatcher.dispatch(&mut request("DELETE /jobs/no-such-job HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+            assert_eq!(missing.status.code, 404);
+            let alias = dispatcher.dispatch(&mut request("GET /jobs/submit?task=isprime&n=7 HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+            assert_eq!(alias.status.code, 200);
+
+            let unknown = dispatcher.dispatch(&mut request("GET /jobs/no-such-job HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+            assert_eq!(unknown.status.code, 404);
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn event_stream_follows_the_job_until_it_ends() {
+            let path = std::env::temp_dir().join(format!("jobs-events-{}.jsonl", std::process::id()));
+            let manager = JobManager::with_persist_path(0, 0, path.clone());
+            let id = manager.submit("isprime", HashMap::from([("n".to_string(), "7".to_string())]), Priority::Normal).unwrap();
+            let handler = JobEventsHandler::new(manager.clone(), 1);
+            let events = request(&format!("GET /jobs/events?id={} HTTP/1.1\r\nHost: x\r\n\r\n", id));
+
+            let resp = handler.handle(&events).unwrap();
+            assert_eq!(resp.headers.get("Content-Type"), Some("text/event-stream"));
+            // Only one stream may be open
+            assert_eq!(handler.handle(&events).unwrap().status.code, 503);
+
+            let canceler = {
+                let (manager, id) = (manager.clone(), id.clone());
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    manager.cancel(&id)
+                })
+            };
+            let mut text = String::new();
+            resp.stream.unwrap().reader.read_to_string(&mut text).unwrap();
+            assert!(canceler.join().unwrap());
+
+            let kinds: Vec<&str> = text.lines().filter_map(|l| l.strip_prefix("event: ")).collect();
+            assert_eq!(kinds, ["status", "status"]);
+            assert!(text.contains("\"status\":\"queued\""));
+            assert!(text.ends_with("\"status\":\"canceled\"}\n\n"));
+
+            // The finished stream gave its slot back
+            assert_eq!(handler.handle(&events).unwrap().status.code, 200);
+            let _ = std::fs::remove_file(&path);
+        }
+
+        #[test]
+        fn event_stream_and_status_report_progress() {
+            let path = std::env::temp_dir().join(format!("jobs-progress-{}.jsonl", std::process::id()));
+            let manager = JobManager::with_persist_path(0, 0, path.clone());
+            let id = manager.submit("mandelbrot", HashMap::new(), Priority::Normal).unwrap();
+            let job = manager.job(&id).unwrap();
+            let handler = JobEventsHandler::new(manager.clone(), 1);
+            let resp = handler.handle(&request(&format!("GET /jobs/events?id={} HTTP/1.1\r\nHost: x\r\n\r\n", id))).unwrap();
+
+            // Stands in for a worker
+            let worker = {
+                let job = job.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    job.set_status(JobStatus::Running);
+                    std::thread::sleep(Duration::from_millis(50));
+                    job.set_progress(0.4);
+                })
+            };
+            let mut stream = resp.stream.unwrap().reader;
+            let mut text = String::new();
+            while text.matches("event: ").count() < 3 {
+                let mut buf = [0u8; 512];
+                let n = stream.read(&mut buf).unwrap();
+                text.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+            worker.join().unwrap();
+
+            let kinds: Vec<&str> = text.lines().filter_map(|l| l.strip_prefix("event: ")).collect();
+            assert_eq!(kinds, ["status", "status", "progress"]);
+            assert!(text.ends_with("\"progress\":40}\n\n"));
+
+            let status = JobStatusHandler { job_manager: manager.clone() }
+                .handle(&request(&format!("GET /jobs/status?id={} HTTP/1.1\r\nHost: x\r\n\r\n", id)))
+                .unwrap();
+            assert!(String::from_utf8_lossy(&status.body).contains("\"progress\":40"));
+            job.set_status(JobStatus::Done);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
@@ -5,10 +5,14 @@ use std::time::SystemTime;
 use crate::{
     http::{
         errors::ServerError,
-        handler::{RequestHandlerStrategy, Dispatcher},
+        handler::{RequestHandlerStrategy, Dispatcher, DispatcherBuilder},
+        rate_limit::RateLimiter,
+        cors::Cors,
+        compression::Compression,
+        proxy,
         request::HttpRequest,
         response::{Response, OK},
-        router::{command, jobs, cpu_bound, io_bound}
+        router::{command, jobs, cpu_bound, io_bound, files, ws}
     },
     jobs::{
         manager::JobManager,
@@ -28,35 +32,140 @@ where
 }
 
 
-pub fn build_routes(job_manager: Arc<JobManager>) -> Dispatcher {
+/// Route groups, by the names `VIRTUAL_HOSTS` uses for them.
+const ROUTE_GROUPS: [&str; 7] = ["command", "jobs", "cpu", "io", "files", "ws", "proxy"];
+
+/// Groups that only work within one process: a job is known to the prefork
+/// worker that accepted it alone, and the next request may reach another.
+pub const SINGLE_PROCESS_GROUPS: [&str; 1] = ["jobs"];
+
+/// The route groups listed in `ROUTE_GROUPS` (comma-separated). When it is
+/// unset or empty, all of them, or with `prefork` all but
+/// `SINGLE_PROCESS_GROUPS`. Disabled groups are not served on any host.
+pub fn enabled_groups(prefork: bool) -> Vec<&'static str> {
+    groups_from(&env::var("ROUTE_GROUPS").unwrap_or_default(), prefork)
+}
+
+fn groups_from(spec: &str, prefork: bool) -> Vec<&'static str> {
+    let listed: Vec<&str> = spec.split(',').map(str::trim).filter(|g| !g.is_empty()).collect();
+    if listed.is_empty() {
+        return ROUTE_GROUPS.into_iter().filter(|g| !prefork || !SINGLE_PROCESS_GROUPS.contains(g)).collect();
+    }
+    for group in listed.iter().filter(|g| !ROUTE_GROUPS.contains(g)) {
+        eprintln!("[routes] unknown route group '{}' in ROUTE_GROUPS (known: {})", group, ROUTE_GROUPS.join(", "));
+    }
+    ROUTE_GROUPS.into_iter().filter(|g| listed.contains(g)).collect()
+}
+
+fn register_group(builder: DispatcherBuilder, group: &str, job_manager: &Arc<JobManager>) -> Option<DispatcherBuilder> {
+    Some(match group {
+        "command" => command::register(builder),
+        "jobs" => jobs::register(builder, job_manager.clone()),
+        "cpu" => cpu_bound::register(builder, job_manager.clone()),
+        "io" => io_bound::register(builder, job_manager.clone()),
+        "files" => files::register(builder),
+        "ws" => ws::register(builder, job_manager.clone()),
+        "proxy" => proxy::register(builder),
+        _ => return None,
+    })
+}
+
+/// Adds a virtual host for every `VIRTUAL_HOSTS` entry, e.g.
+/// `jobs.internal=jobs;*.compute.internal=cpu,io`, serving only the named
+/// route groups.
+fn register_hosts(mut builder: DispatcherBuilder, job_manager: &Arc<JobManager>, enabled: &[&str]) -> DispatcherBuilder {
+    let spec = env::var("VIRTUAL_HOSTS").unwrap_or_default();
+    for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((pattern, groups)) = entry.split_once('=') else {
+            eprintln!("[vhost] ignoring '{}': expected HOST=GROUP[,GROUP...]", entry);
+            continue;
+        };
+
+        let mut routes = Dispatcher::builder();
+        for group in groups.split(',').map(str::trim).filter(|g| !g.is_empty()) {
+            if !ROUTE_GROUPS.contains(&group) {
+                eprintln!("[vhost] unknown route group '{}' for {} (known: {})", group, pattern.trim(), ROUTE_GROUPS.join(", "));
+                continue;
+            }
+            if !enabled.contains(&group) {
+                eprintln!("[vhost] route group '{}' for {} is not in ROUTE_GROUPS", group, pattern.trim());
+                continue;
+            }
+            routes = register_group(routes, group, job_manager).expect("known route group");
+        }
+        builder = builder.host(pattern.trim(), routes);
+    }
+    builder
+}
+
+/// The routes of the `enabled` groups (see `enabled_groups`).
+pub fn build_routes(job_manager: Arc<JobManager>, enabled: &[&str]) -> Dispatcher {
     let mut builder = Dispatcher::builder();
 
+    // Routes from other modules; hosts not listed in VIRTUAL_HOSTS get all of them
+    for group in enabled {
+        builder = register_group(builder, group, &job_manager).expect("known route group");
+    }
+    builder = register_hosts(builder, &job_manager, enabled);
+    // Compression outermost so it sees the final headers; CORS before the
+    // rate limiter, so preflights skip it and 429s stay readable
+    builder
+        .wrap(Arc::new(Compression::from_env()))
+        .wrap(Arc::new(Cors::from_env()))
+        .wrap(Arc::new(RateLimiter::from_env()))
+        .build()
+}
+
 
-    // Routes from other modules
-    builder = command::register(builder);
-    builder = jobs::register(builder, job_manager.clone());
-    builder = cpu_bound::register(builder, job_manager.clone());
-    builder = io_bound::register(builder, job_manager.clone());
-    builder.build()
+/// Access to the captures of the matched route template, e.g. `id` in
+/// `/jobs/{id}/result`. A trailing `*` wildcard is captured as `"*"`.
+pub trait PathParam {
+    fn path_param(&self, key: &str) -> Option<&str>;
 }
 
+impl PathParam for HttpRequest {
+    fn path_param(&self, key: &str) -> Option<&str> {
+        self.path_params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
 
+/// Access to decoded query-string and form-body parameters.
 pub trait QueryParam {
+    /// First value of `key`.
     fn query_param(&self, key: &str) -> Option<&str>;
+    /// Every value of `key`, in request order.
+    fn query_params(&self, key: &str) -> Vec<&str>;
 }
 
 impl QueryParam for HttpRequest {
     fn query_param(&self, key: &str) -> Option<&str> {
-        if self.query.is_empty() {
-            return None;
-        }
-        for pair in self.query.split('&') {
-            if let Some((k, v)) = pair.split_once('=') {
-                if k == key {
-                    return Some(v);
-                }
-            }
-        }
-        None
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn query_params(&self, key: &str) -> Vec<&str> {
+        self.params
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefork_defaults_to_groups_that_work_across_processes() {
+        assert_eq!(groups_from("", false), ROUTE_GROUPS);
+        assert_eq!(groups_from(" ", true), ["command", "cpu", "io", "files", "ws", "proxy"]);
+        // An explicit list is taken as is
+        assert_eq!(groups_from("jobs, cpu", true), ["jobs", "cpu"]);
     }
 }
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::http::{
+    handler::{RequestHandlerStrategy, DispatcherBuilder},
+    request::HttpRequest,
+    response::Response,
+    errors::ServerError,
+    router::jobs::{job_state, metrics_json, parse_priority},
+    websocket::{self, Message, WebSocket, CLOSE_GOING_AWAY},
+};
+use crate::jobs::manager::JobManager;
+use crate::jobs::job::{Job, JobStatus};
+use crate::utils::signal;
+
+/// How often subscribed jobs are checked, and shutdown with them.
+const POLL: Duration = Duration::from_millis(200);
+const MIN_METRICS_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_SUBSCRIPTIONS: usize = 256;
+
+/// GET /ws
+///
+/// A WebSocket speaking JSON that mirrors the `/jobs` routes. Client
+/// messages name an `op`:
+///
+/// - `subscribe` / `unsubscribe` with `jobs: [id, ...]`: a `job` message is
+///   pushed now and on every change, until the job finishes
+/// - `metrics` with `interval_ms`: a `metrics` snapshot now and then every
+///   interval; 0 stops them
+/// - `submit` with `task`, `params` and `priority`: answers `submitted` and
+///   subscribes to the new job
+/// - `cancel` with `id`: answers `cancel`
+/// - `status` with `id`: answers `job`
+///
+/// Failures are reported as `error` messages; the socket stays open.
+pub struct WsHandler {
+    pub job_manager: Arc<JobManager>,
+}
+
+impl RequestHandlerStrategy for WsHandler {
+    fn handle(&self, req: &HttpRequest) -> Result<Response, ServerError> {
+        let job_manager = Arc::clone(&self.job_manager);
+        websocket::handshake(req, move |ws| Session::new(ws, job_manager).run())
+    }
+}
+
+struct Subscription {
+    job: Arc<Job>,
+    last: Option<(JobStatus, f32)>,
+}
+
+struct Session {
+    ws: WebSocket,
+    job_manager: Arc<JobManager>,
+    jobs: HashMap<String, Subscription>,
+    metrics_every: Option<Duration>,
+    metrics_sent: Instant,
+}
+
+impl Session {
+    fn new(ws: WebSocket, job_manager: Arc<JobManager>) -> Self {
+        Self { ws, job_manager, jobs: HashMap::new(), metrics_every: None, metrics_sent: Instant::now() }
+    }
+
+    /// Alternates between client commands and pushed updates until either
+    /// side closes or the server shuts down.
+    fn run(mut self) {
+        loop {
+            if signal::shutdown_requested() {
+                let _ = self.ws.close(CLOSE_GOING_AWAY, "Server shutting down");
+                return;
+            }
+
+            let handled = match self.ws.wait_readable(POLL) {
+                Ok(false) => Ok(()),
+                Ok(true) => match self.ws.recv() {
+                    Ok(Some(Message::Text(text))) => self.handle(&text),
+                    Ok(Some(Message::Binary(_))) => self.send_error(None, "Binary messages are not supported"),
+                    Ok(None) | Err(_) => return,
+                },
+                Err(_) => return,
+            };
+
+            if handled.and_then(|_| self.push_updates()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, text: &str) -> std::io::Result<()> {
+        let Ok(cmd) = serde_json::from_str::<Value>(text) else {
+            return self.send_error(None, "Invalid JSON");
+        };
+        let op = cmd["op"].as_str().unwrap_or("");
+
+        match op {
+            "subscribe" => {
+                for id in job_ids(&cmd) {
+                    self.subscribe(&id)?;
+                }
+                Ok(())
+            }
+            "unsubscribe" => {
+                for id in job_ids(&cmd) {
+                    self.jobs.remove(&id);
+                }
+                Ok(())
+            }
+            "metrics" => {
+                match cmd["interval_ms"].as_u64() {
+                    Some(0) => self.metrics_every = None,
+                    Some(ms) => self.metrics_every = Some(Duration::from_millis(ms).max(MIN_METRICS_INTERVAL)),
+                    None => {}
+                }
+                self.send_metrics()
+            }
+            "submit" => self.submit(&cmd),
+            "cancel" => {
+                let Some(id) = cmd["id"].as_str() else { return self.send_error(Some(op), "Missing 'id'") };
+                let status = if self.job_manager.cancel(id) { "canceled" } else { "not_cancelable" };
+                self.send(json!({ "type": "cancel", "id": id, "status": status }))
+            }
+            "status" => {
+                let Some(id) = cmd["id"].as_str() else { return self.send_error(Some(op), "Missing 'id'") };
+                match self.job_manager.job(id) {
+                    Some(job) => self.send(job_message(id, &job.snapshot())),
+                    None => self.send_error(Some(op), &format!("Job not found: {}", id)),
+                }
+            }
+            _ => self.send_error(Some(op), "Unknown op"),
+        }
+    }
+
+    fn subscribe(&mut self, id: &str) -> std::io::Result<()> {
+        if self.jobs.contains_key(id) {
+            return Ok(());
+        }
+        if self.jobs.len() >= MAX_SUBSCRIPTIONS {
+            return self.send_error(Some("subscribe"), "Too many subscriptions");
+        }
+        match self.job_manager.job(id) {
+            Some(job) => {
+                self.jobs.insert(id.to_string(), Subscription { job, last: None });
+                Ok(())
+            }
+            None => self.send_error(Some("subscribe"), &format!("Job not found: {}", id)),
+        }
+    }
+
+    fn submit(&mut self, cmd: &Value) -> std::io::Result<()> {
+        let task = cmd["task"].as_str().unwrap_or("").trim();
+        if task.is_empty() {
+            return self.send_error(Some("submit"), "Missing 'task'");
+        }
+        let priority_str = cmd["priority"].as_str().unwrap_or("normal");
+
+        let params: HashMap<String, String> = cmd["params"]
+            .as_object()
+            .map(|obj| {
+                obj.iter()
+                    .map(|(k, v)| (k.clone(), v.as_str().map(String::from).unwrap_or_else(|| v.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        match self.job_manager.submit(task, params, parse_priority(priority_str)) {
+            Ok(id) => {
+                println!("Job submitted: id='{}', task='{}'", id, task);
+                self.send(json!({ "type": "submitted", "id": id, "task": task, "priority": priority_str }))?;
+                self.subscribe(&id)
+            }
+            Err(e) => {
+                let reason = e.strip_prefix("SERVICE_UNAVAILABLE:").unwrap_or(&e).to_string();
+                self.send_error(Some("submit"), &reason)
+            }
+        }
+    }
+
+    /// Sends a `job` message for every subscribed job that changed, dropping
+    /// finished ones, and a metrics snapshot when one is due.
+    fn push_updates(&mut self) -> std::io::Result<()> {
+        let mut finished = Vec::new();
+        for (id, sub) in self.jobs.iter_mut() {
+            let current = sub.job.snapshot();
+            if sub.last.as_ref() == Some(&current) {
+                continue;
+            }
+            self.ws.send_text(&job_message(id, &current).to_string())?;
+            if current.0.is_terminal() {
+                finished.push(id.clone());
+            }
+            sub.last = Some(current);
+        }
+        for id in finished {
+            self.jobs.remove(&id);
+        }
+
+        match self.metrics_every {
+            Some(every) if self.metrics_sent.elapsed() >= every => self.send_metrics(),
+            _ => Ok(()),
+        }
+    }
+
+    fn send_metrics(&mut self) -> std::io::Result<()> {
+        let metrics = serde_json::from_str::<Value>(&metrics_json(&self.job_manager)).unwrap_or(Value::Null);
+        self.metrics_sent = Instant::now();
+        self.send(json!({ "type": "metrics", "pools": metrics["pools"] }))
+    }
+
+    fn send_error(&self, op: Option<&str>, message: &str) -> std::io::Result<()> {
+        self.send(json!({ "type": "error", "op": op, "error": message }))
+    }
+
+    fn send(&self, msg: Value) -> std::io::Result<()> {
+        self.ws.send_text(&msg.to_string())
+    }
+}
+
+fn job_message(id: &str, (status, progress): &(JobStatus, f32)) -> Value {
+    let mut msg = job_state(id, status, *progress);
+    msg["type"] = json!("job");
+    msg
+}
+
+/// Ids from `jobs: [...]`, or a single `id`.
+fn job_ids(cmd: &Value) -> Vec<String> {
+    match (cmd["jobs"].as_array(), cmd["id"].as_str()) {
+        (Some(ids), _) => ids.iter().filter_map(|v| v.as_str()).map(String::from).collect(),
+        (None, Some(id)) => vec![id.to_string()],
+        (None, None) => Vec::new(),
+    }
+}
+
+pub fn register(builder: DispatcherBuilder, job_manager: Arc<JobManager>) -> DispatcherBuilder {
+    builder.get("/ws", Arc::new(WsHandler { job_manager }))
+}
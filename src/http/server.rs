@@ -1,45 +1,154 @@
 
 use std::{
-    collections::VecDeque,
     fs::File,
-    io::{self, Read, Write, ErrorKind},
+    io::{self, BufRead, BufReader, Write, ErrorKind},
+    net::SocketAddr,
     os::fd::{FromRawFd, RawFd},
+    path::PathBuf,
     sync::{
         Arc,
-        Mutex,
         atomic::{AtomicUsize, Ordering},
     },
     thread,
     time::{Duration, Instant},
 };
 
-use libc::{
-    self, c_int, sockaddr, sockaddr_in, socklen_t,
-    AF_INET, SOCK_STREAM, SOL_SOCKET, SO_REUSEADDR,
-};
+use libc::{self, c_int, sockaddr, sockaddr_storage, socklen_t};
 
 use crate::{
+    utils::signal,
     http::{
+        access_log::{AccessLog, LogEntry, LogFormat},
+        connection_pool::ConnectionPool,
         errors::ServerError,
         handler::Dispatcher,
-        request::{HttpRequest, HttpMethod},
-        response::{
-            Status, Response,
-            BAD_REQUEST, NOT_FOUND, CONFLICT,
-            TOO_MANY_REQUESTS, INTERNAL_SERVER_ERROR, SERVICE_UNAVAILABLE,
-        },
+        listener::{self, Listener, SystemdListener, TcpListener, UnixListener},
+        reactor,
+        request::{self, HttpRequest, HttpMethod, RequestLimits},
+        middleware::error_response,
+        response::{Status, Response},
+        upgrade::{Upgrade, Upgraded},
     },
 };
 
 
-pub struct ServerConfig { pub bind_addr: String, pub max_connections: usize, pub rate_limit_per_sec: usize }
-impl Default for ServerConfig { fn default() -> Self { Self{ bind_addr: "127.0.0.1:8080".into(), max_connections: 64, rate_limit_per_sec: 200 } } }
+/// How client connections are driven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoModel {
+    /// A fixed pool of threads, each serving one connection at a time.
+    Threaded,
+    /// A few epoll I/O threads feeding a fixed pool of handler threads.
+    Epoll,
+}
+
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// One or more comma-separated listen addresses; empty for no TCP.
+    pub bind_addr: String,
+    /// Whether IPv6 listeners refuse IPv4-mapped connections.
+    pub ipv6_only: bool,
+    /// Bind TCP addresses with `SO_REUSEPORT`, sharing them with other
+    /// processes doing the same.
+    pub reuse_port: bool,
+    /// Also listen on a Unix domain socket at this path.
+    pub unix_socket: Option<PathBuf>,
+    /// Permission bits for the Unix socket file.
+    pub unix_socket_mode: Option<u32>,
+    /// Serve sockets passed in through `LISTEN_FDS`/`LISTEN_PID`.
+    pub socket_activation: bool,
+    /// Connections served at once; in the threaded model, the number of
+    /// connection threads.
+    pub max_connections: usize,
+    /// Threaded model only: accepted connections that may wait for a free
+    /// thread before new ones are turned away.
+    pub accept_queue_size: usize,
+    /// Threaded model only: how long a connection may wait in the queue
+    /// before it gets a 503. Zero waits indefinitely.
+    pub accept_queue_wait: Duration,
+    /// How long a persistent connection may sit idle between requests.
+    pub keep_alive_timeout: Duration,
+    /// Requests served on one connection before it is closed.
+    pub max_requests_per_connection: usize,
+    pub io_model: IoModel,
+    /// Epoll mode only: threads running the event loops.
+    pub io_threads: usize,
+    /// Epoll mode only: threads running request handlers.
+    pub handler_threads: usize,
+    /// How long active connections may keep going after a shutdown signal.
+    pub shutdown_timeout: Duration,
+    /// Size limits on the request line, headers and body.
+    pub request_limits: RequestLimits,
+    /// How long a client may take to send the rest of a started request.
+    pub read_timeout: Duration,
+    /// How long a client may stall while a response is being written.
+    pub write_timeout: Duration,
+    /// Access log file, `-` for stdout, or `None` to disable.
+    pub access_log: Option<String>,
+    pub access_log_format: LogFormat,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "127.0.0.1:8080".into(),
+            ipv6_only: false,
+            reuse_port: false,
+            unix_socket: None,
+            unix_socket_mode: None,
+            socket_activation: true,
+            max_connections: 64,
+            accept_queue_size: 128,
+            accept_queue_wait: Duration::from_secs(1),
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests_per_connection: 100,
+            io_model: IoModel::Threaded,
+            io_threads: 2,
+            handler_threads: 8,
+            shutdown_timeout: Duration::from_secs(30),
+            request_limits: RequestLimits::default(),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            access_log: None,
+            access_log_format: LogFormat::Combined,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Everything this configuration listens on: the TCP addresses, the
+    /// Unix socket and inherited sockets.
+    pub fn listeners(&self) -> io::Result<Vec<Arc<dyn Listener>>> {
+        let mut listeners: Vec<Arc<dyn Listener>> = Vec::new();
+        if !self.bind_addr.trim().is_empty() {
+            for addr in listener::parse_bind_addrs(&self.bind_addr)? {
+                listeners.push(Arc::new(TcpListener { addr, v6_only: self.ipv6_only, reuse_port: self.reuse_port }));
+            }
+        }
+        if let Some(path) = &self.unix_socket {
+            listeners.push(Arc::new(UnixListener { path: path.clone(), mode: self.unix_socket_mode }));
+        }
+        if self.socket_activation && listener::inherited_fd_count() > 0 {
+            listeners.push(Arc::new(SystemdListener));
+        }
+        Ok(listeners)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct ConnectionLimits {
+    pub(crate) keep_alive_timeout: Duration,
+    pub(crate) max_requests: usize,
+    pub(crate) request: RequestLimits,
+    pub(crate) read_timeout: Duration,
+    pub(crate) write_timeout: Duration,
+}
 
 pub struct HttpServer {
     pub cfg: ServerConfig,
     pub dispatcher: Arc<Dispatcher>,
-    active: Arc<AtomicUsize>,
-    window: Arc<Mutex<VecDeque<Instant>>>,
+    pub(crate) active: Arc<AtomicUsize>,
+    /// Served in addition to the listeners `cfg` describes.
+    extra_listeners: Vec<Arc<dyn Listener>>,
 }
 
 impl HttpServer {
@@ -50,245 +159,477 @@ impl HttpServer {
             cfg,
             dispatcher: Arc::new(dispatcher),
             active: Arc::new(AtomicUsize::new(0)),
-            window: Arc::new(Mutex::new(VecDeque::new())),
+            extra_listeners: Vec::new(),
         }
     }
 
+    /// Serves connections from `listener` as well.
+    pub fn with_listener(mut self, listener: impl Listener + 'static) -> Self {
+        self.extra_listeners.push(Arc::new(listener));
+        self
+    }
+
+    /// Serves until a shutdown is requested, then stops accepting and lets
+    /// active connections finish until `shutdown_timeout` runs out.
     pub fn run(&self) -> io::Result<()> {
-        let (ip, port) = parse_ipv4_addr(&self.cfg.bind_addr)?;
-        let listen_fd = create_listen_socket(ip, port)?;
-        println!("🚀 Listening on {}", self.cfg.bind_addr);
+        let access_log = self.open_access_log()?;
+        let listeners = self.listeners()?;
+        let listen_fds = open_listeners(&listeners)?;
+
+        if self.cfg.io_model == IoModel::Epoll {
+            let result = reactor::run(self, &listen_fds, access_log);
+            close_listeners(&listen_fds, &listeners);
+            return result;
+        }
 
-        loop {
-            let client_fd = match Self::accept_client(listen_fd) {
-                Ok(fd) => fd,
+        let pool = self.connection_pool(access_log);
+        while !signal::shutdown_requested() {
+            let ready = match listener::poll_listeners(&listen_fds, 250) {
+                Ok(ready) => ready,
                 Err(e) => {
                     eprintln!("Accept error: {e}");
                     continue;
                 }
             };
 
-            if self.active.load(Ordering::SeqCst) >= self.cfg.max_connections {
-                Self::reject_client(client_fd, SERVICE_UNAVAILABLE, "Service Unavailable: too many connections");
-                continue;
+            for listen_fd in ready {
+                match Self::accept_client(listen_fd) {
+                    Ok((client_fd, peer)) => pool.submit(client_fd, peer),
+                    // Another process sharing the listener took the connection
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => eprintln!("Accept error: {e}"),
+                }
             }
+            pool.shed_expired();
+        }
 
-            if self.is_rate_limited() {
-                Self::reject_client(client_fd, TOO_MANY_REQUESTS, "Too Many Requests");
-                continue;
-            }
+        close_listeners(&listen_fds, &listeners);
+        println!("🛑 Shutdown requested: no longer accepting connections");
 
-            self.active.fetch_add(1, Ordering::SeqCst);
-            let dispatcher = Arc::clone(&self.dispatcher);
-            let active = Arc::clone(&self.active);
+        // Connections already queued still get their request served
+        let deadline = Instant::now() + self.cfg.shutdown_timeout;
+        while pool.open_connections() > 0 && Instant::now() < deadline {
+            pool.shed_expired();
+            thread::sleep(Duration::from_millis(50));
+        }
+        pool.close();
+        Ok(())
+    }
 
-            thread::spawn(move || {
-                if let Err(e) = Self::serve_client(client_fd, dispatcher) {
-                    eprintln!("Error handling connection: {e}");
-                }
-                active.fetch_sub(1, Ordering::SeqCst);
-            });
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    fn open_access_log(&self) -> io::Result<Option<Arc<AccessLog>>> {
+        let Some(path) = &self.cfg.access_log else { return Ok(None) };
+        let log = AccessLog::open(path, self.cfg.access_log_format)
+            .map_err(|e| io::Error::new(e.kind(), format!("Cannot open access log {}: {}", path, e)))?;
+        Ok(Some(Arc::new(log)))
+    }
+
+    /// The listeners from `cfg` followed by the extra ones.
+    fn listeners(&self) -> io::Result<Vec<Arc<dyn Listener>>> {
+        let mut listeners = self.cfg.listeners()?;
+        listeners.extend(self.extra_listeners.iter().cloned());
+        Ok(listeners)
+    }
+
+    /// `max_connections` threads serving accepted connections, which wait
+    /// in a bounded queue while they are all busy.
+    fn connection_pool(&self, access_log: Option<Arc<AccessLog>>) -> ConnectionPool {
+        let dispatcher = Arc::clone(&self.dispatcher);
+        let active = Arc::clone(&self.active);
+        let limits = self.limits();
+
+        let rejections = access_log.clone();
+        ConnectionPool::new(self.cfg.max_connections, self.cfg.accept_queue_size, self.cfg.accept_queue_wait, rejections, move |fd, peer| {
+            let _active = Active::count(&active);
+            if let Err(e) = Self::serve_client(fd, Arc::clone(&dispatcher), limits, peer, access_log.clone()) {
+                eprintln!("Error handling connection: {e}");
+            }
+        })
+    }
+
+    pub(crate) fn limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            keep_alive_timeout: self.cfg.keep_alive_timeout,
+            max_requests: self.cfg.max_requests_per_connection.max(1),
+            request: self.cfg.request_limits,
+            read_timeout: self.cfg.read_timeout,
+            write_timeout: self.cfg.write_timeout,
         }
     }
 
-    fn accept_client(listen_fd: i32) -> io::Result<i32> {
-        let mut addr: sockaddr_in = unsafe { std::mem::zeroed() };
-        let mut addr_len = std::mem::size_of::<sockaddr_in>() as socklen_t;
+    fn accept_client(listen_fd: i32) -> io::Result<(i32, Option<SocketAddr>)> {
+        let mut addr: sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut addr_len = std::mem::size_of::<sockaddr_storage>() as socklen_t;
 
         let fd = unsafe {
-            libc::accept(
+            libc::accept4(
                 listen_fd,
-                (&mut addr as *mut sockaddr_in).cast::<sockaddr>(),
+                (&mut addr as *mut sockaddr_storage).cast::<sockaddr>(),
                 &mut addr_len,
+                libc::SOCK_CLOEXEC,
             )
         };
 
         if fd < 0 {
             Err(io::Error::last_os_error())
         } else {
-            Ok(fd)
+            Ok((fd, listener::socket_addr(&addr)))
         }
     }
 
-    fn serve_client(fd: i32, dispatcher: Arc<Dispatcher>) -> Result<(), ServerError> {
-        let mut stream = unsafe { File::from_raw_fd(fd) };
-        handle_connection(&mut stream, &dispatcher)
-    }
+    fn serve_client(
+        fd: i32,
+        dispatcher: Arc<Dispatcher>,
+        limits: ConnectionLimits,
+        peer: Option<SocketAddr>,
+        access_log: Option<Arc<AccessLog>>,
+    ) -> Result<(), ServerError> {
+        let mut writer = unsafe { File::from_raw_fd(fd) };
+        set_timeout(fd, libc::SO_RCVTIMEO, limits.read_timeout)?;
+        set_timeout(fd, libc::SO_SNDTIMEO, limits.write_timeout)?;
+        let mut reader = BufReader::new(writer.try_clone()?);
+
+        let mut served = 0;
+        loop {
+            if served > 0 && signal::shutdown_requested() {
+                break;
+            }
 
-    fn reject_client(fd: i32, status: Status, message: &str) {
-        unsafe {
-            let mut stream = std::fs::File::from_raw_fd(fd);
-            let response = Response::new(status).with_body(message);
-            let _ = stream.write_all(&response.to_bytes(false));
-            let _ = stream.flush();
+            // Between requests, wait for the next one for at most the idle timeout
+            if served > 0 && reader.buffer().is_empty() && !wait_for_request(fd, limits.keep_alive_timeout)? {
+                break;
+            }
+
+            served += 1;
+            let allow_keep_alive = served < limits.max_requests && !signal::shutdown_requested();
+            let conn = Connection { dispatcher: &dispatcher, limits: &limits.request, peer, access_log: access_log.as_deref() };
+            match handle_connection(&mut reader, &mut writer, &conn, allow_keep_alive)? {
+                AfterResponse::KeepAlive => {}
+                AfterResponse::Close => break,
+                AfterResponse::Upgrade(upgrade) => {
+                    let buffered = reader.buffer().to_vec();
+                    upgrade.run(Upgraded::new(writer, buffered));
+                    break;
+                }
+            }
         }
+
+        Ok(())
     }
 
-    fn is_rate_limited(&self) -> bool {
-        let now = Instant::now();
-        let mut window = self.window.lock().expect("rate limiter mutex");
+    /// Answers a connection that will not be served with `status` and
+    /// closes it.
+    pub(crate) fn reject_client(fd: i32, status: Status, message: &str, peer: Option<SocketAddr>, access_log: Option<&AccessLog>) {
+        let mut stream = unsafe { std::fs::File::from_raw_fd(fd) };
+        let response = Response::new(status).with_body(message);
+        let sent = stream.write_all(&response.to_bytes(false)).and_then(|_| stream.flush());
 
-        while let Some(&front) = window.front() {
-            if now.duration_since(front) > Duration::from_secs(1) {
-                window.pop_front();
-            } else {
-                break;
-            }
+        if let Some(log) = access_log {
+            let bytes = if sent.is_ok() { message.len() as u64 } else { 0 };
+            log.record(&LogEntry { peer, request: None, request_line: None, status: status.code, bytes, latency: Duration::ZERO });
         }
+    }
+}
 
-        if window.len() >= self.cfg.rate_limit_per_sec {
-            true
-        } else {
-            window.push_back(now);
-            false
-        }
+/// Counts a connection in `HttpServer::active` until dropped, even when
+/// serving it panics.
+struct Active<'a>(&'a AtomicUsize);
+
+impl<'a> Active<'a> {
+    fn count(active: &'a AtomicUsize) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(active)
     }
 }
 
-fn handle_connection<RW: Read + Write>(
-    rw: &mut RW,
-    dispatcher: &Dispatcher
-) -> Result<(), ServerError> {
-    match HttpRequest::parse(rw) {
-        Ok(req) => {
-            let is_head = matches!(req.method, HttpMethod::HEAD);
-
-            let resp = match dispatcher.dispatch(&req) {
-                Ok(r) => r,
-                Err(err) => {
-                    let status = match err {
-                        ServerError::BadRequest(_) => BAD_REQUEST,
-                        ServerError::NotFound => NOT_FOUND,
-                        ServerError::Conflict(_) => CONFLICT,
-                        ServerError::TooManyRequests => TOO_MANY_REQUESTS,
-                        ServerError::ServiceUnavailable => SERVICE_UNAVAILABLE,
-                        ServerError::Internal(_) | ServerError::Io(_) => INTERNAL_SERVER_ERROR,
-                    };
-
-                    let json_body = format!("{{\"error\": \"{}\"}}", err.to_string());
-                    Response::new(status)
-                        .set_header("Content-Type", "application/json")
-                        .with_body(json_body)
-                }
-            };
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-            let bytes = resp.to_bytes(is_head);
-            let _ = rw.write_all(&bytes);
-            let _ = rw.flush();
-            Ok(())
-        }
+/// What a threaded connection needs to serve its requests.
+pub(crate) struct Connection<'a> {
+    pub(crate) dispatcher: &'a Dispatcher,
+    pub(crate) limits: &'a RequestLimits,
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) access_log: Option<&'a AccessLog>,
+}
 
-        Err(e) => {
-            let status = match e {
-                ServerError::BadRequest(_) => BAD_REQUEST,
-                ServerError::NotFound => NOT_FOUND,
-                ServerError::Conflict(_) => CONFLICT,
-                ServerError::TooManyRequests => TOO_MANY_REQUESTS,
-                ServerError::ServiceUnavailable => SERVICE_UNAVAILABLE,
-                ServerError::Internal(_) | ServerError::Io(_) => INTERNAL_SERVER_ERROR,
-            };
+/// What becomes of a connection once a response has been sent.
+pub(crate) enum AfterResponse {
+    Close,
+    KeepAlive,
+    /// The response was a 101; the connection now belongs to this upgrade.
+    Upgrade(Upgrade),
+}
 
-            let json_body = format!("{{\"error\": \"{}\"}}", e.to_string());
-            let resp = Response::new(status)
-                .set_header("Content-Type", "application/json")
-                .with_body(json_body);
+/// Serves a single request from the connection and reports what to do with
+/// the connection next.
+pub(crate) fn handle_connection<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    conn: &Connection<'_>,
+    allow_keep_alive: bool,
+) -> Result<AfterResponse, ServerError> {
+    let started = Instant::now();
+    let line = match request::read_request_line(reader, conn.limits) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Ok(AfterResponse::Close),
+        Err(e) => return Ok(refuse(writer, conn, None, &e, started)),
+    };
 
-            let _ = rw.write_all(&resp.to_bytes(false));
-            let _ = rw.flush();
-            Ok(())
+    match HttpRequest::read_rest(&line, reader, conn.limits, |req| conn.dispatcher.body_limit(req)) {
+        Ok(mut req) => {
+            req.peer_addr = conn.peer;
+            Ok(serve_request(conn.dispatcher, &mut req, allow_keep_alive, writer, conn.access_log))
         }
+        Err(e) => Ok(refuse(writer, conn, Some(&line), &e, started)),
     }
 }
 
-pub fn create_listen_socket(ip_host: u32, port_host: u16) -> io::Result<RawFd> {
-    let fd = unsafe { libc::socket(AF_INET, SOCK_STREAM, 0) };
-    if fd < 0 {
-        return Err(io::Error::last_os_error());
+/// Answers a request that could not be read with the error, logs it and
+/// closes the connection. A connection that failed outright just closes.
+fn refuse<W: Write>(writer: &mut W, conn: &Connection<'_>, request_line: Option<&str>, err: &ServerError, started: Instant) -> AfterResponse {
+    if let ServerError::Io(_) = err {
+        return AfterResponse::Close;
     }
 
-    // Allow immediate reuse of port
-    let opt: c_int = 1;
-    unsafe {
-        libc::setsockopt(
-            fd,
-            SOL_SOCKET,
-            SO_REUSEADDR,
-            (&opt as *const c_int).cast(),
-            std::mem::size_of_val(&opt) as socklen_t,
-        );
+    let resp = error_response(err);
+    let sent = writer.write_all(&resp.to_bytes(false)).and_then(|_| writer.flush());
+    if let Some(log) = conn.access_log {
+        let bytes = if sent.is_ok() { resp.body.len() as u64 } else { 0 };
+        log.record(&LogEntry { peer: conn.peer, request: None, request_line, status: resp.status.code, bytes, latency: started.elapsed() });
     }
+    AfterResponse::Close
+}
+
+/// Dispatches `req`, writes the response to `w` and logs it. Returns what
+/// the connection is used for next.
+pub(crate) fn serve_request<W: Write>(
+    dispatcher: &Dispatcher,
+    req: &mut HttpRequest,
+    allow_keep_alive: bool,
+    w: &mut W,
+    access_log: Option<&AccessLog>,
+) -> AfterResponse {
+    let started = Instant::now();
+    let (resp, keep_alive) = respond(dispatcher, req, allow_keep_alive);
+    send_response(resp, keep_alive, req, w, access_log, started)
+}
 
-    let mut addr: sockaddr_in = unsafe { std::mem::zeroed() };
-    addr.sin_family = AF_INET as u16;
-    addr.sin_port = port_host.to_be();     // convert port to network byte order
-    addr.sin_addr.s_addr = ip_host;        // use ip as-is (already parsed)
+/// Writes a response from `respond` to `w` and logs it, timed from
+/// `started`. Returns what the connection is used for next.
+pub(crate) fn send_response<W: Write>(
+    mut resp: Response,
+    keep_alive: bool,
+    req: &HttpRequest,
+    w: &mut W,
+    access_log: Option<&AccessLog>,
+    started: Instant,
+) -> AfterResponse {
+    let is_head = matches!(req.method, HttpMethod::HEAD);
+    let status = resp.status.code;
+    let upgrade = resp.upgrade.take();
+
+    let sent = resp.write_to(w, is_head);
+    if let Some(log) = access_log {
+        let bytes = *sent.as_ref().unwrap_or(&0);
+        log.record(&LogEntry { peer: req.peer_addr, request: Some(req), request_line: None, status, bytes, latency: started.elapsed() });
+    }
+
+    match (sent, upgrade) {
+        (Ok(_), Some(upgrade)) => AfterResponse::Upgrade(upgrade),
+        (Ok(_), None) if keep_alive => AfterResponse::KeepAlive,
+        _ => AfterResponse::Close,
+    }
+}
+
+/// Dispatches `req` and prepares the response for the wire. Also returns
+/// whether the connection may be reused once the response has been sent.
+pub(crate) fn respond(dispatcher: &Dispatcher, req: &mut HttpRequest, allow_keep_alive: bool) -> (Response, bool) {
+    let keep_alive = allow_keep_alive && req.keep_alive();
+
+    let resp = match dispatcher.dispatch(req) {
+        Ok(r) => r,
+        Err(err) => error_response(&err),
+    };
+
+    // A handler echoing client input into a header must not be able to
+    // split the response
+    let resp = match resp.check_headers() {
+        Ok(()) => resp,
+        Err(err) => {
+            eprintln!("Refusing response to {}: {}", req.path, err);
+            error_response(&err)
+        }
+    };
+
+    let mut resp = resp.with_keep_alive(keep_alive);
+    resp.version = req.version.clone();
+    let keep_alive = keep_alive && !resp.is_close_delimited();
+
+    (resp, keep_alive)
+}
+
+/// Waits for the next request on an idle connection, giving up after
+/// `timeout` or as soon as a shutdown is requested.
+fn wait_for_request(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    let slice = Duration::from_millis(250);
+
+    while !signal::shutdown_requested() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(false);
+        }
+        if wait_readable(fd, left.min(slice))? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
 
+/// Sets `SO_RCVTIMEO` or `SO_SNDTIMEO`; blocking reads and writes then fail
+/// with `WouldBlock` once the client has stalled for `timeout`.
+pub(crate) fn set_timeout(fd: RawFd, option: c_int, timeout: Duration) -> io::Result<()> {
+    let tv = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
     let rc = unsafe {
-        libc::bind(
+        libc::setsockopt(
             fd,
-            (&addr as *const sockaddr_in).cast::<sockaddr>(),
-            std::mem::size_of::<sockaddr_in>() as socklen_t,
+            libc::SOL_SOCKET,
+            option,
+            (&tv as *const libc::timeval).cast(),
+            std::mem::size_of::<libc::timeval>() as socklen_t,
         )
     };
-    if rc < 0 {
-        let e = io::Error::last_os_error();
-        unsafe { libc::close(fd) };
-        return Err(e);
-    }
+    if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
 
-    let rc = unsafe { libc::listen(fd, 128) };
-    if rc < 0 {
-        let e = io::Error::last_os_error();
-        unsafe { libc::close(fd) };
-        return Err(e);
+/// Opens every listener, closing the ones already open if one fails.
+pub(crate) fn open_listeners(listeners: &[Arc<dyn Listener>]) -> io::Result<Vec<RawFd>> {
+    let mut fds = Vec::new();
+    for (i, listener) in listeners.iter().enumerate() {
+        match listener.open() {
+            Ok(opened) => {
+                println!("🚀 Listening on {}", listener);
+                fds.extend(opened);
+            }
+            Err(e) => {
+                close_listeners(&fds, &listeners[..i]);
+                return Err(io::Error::new(e.kind(), format!("Cannot listen on {}: {}", listener, e)));
+            }
+        }
     }
 
-    Ok(fd)
+    if fds.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "No listen address configured"));
+    }
+    Ok(fds)
 }
 
-fn create_parse_error(msg: &str) -> io::Error {
-    io::Error::new(ErrorKind::InvalidInput, msg)
+pub(crate) fn close_listeners(fds: &[RawFd], listeners: &[Arc<dyn Listener>]) {
+    for &fd in fds {
+        unsafe { libc::close(fd) };
+    }
+    for listener in listeners {
+        listener.cleanup();
+    }
 }
 
-fn parse_ipv4_addr(addr: &str) -> io::Result<(u32, u16)> {
-    let split = addr.trim();
+/// Blocks until `fd` has data to read or `timeout` elapses.
+pub(crate) fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let timeout_ms = timeout.as_millis().min(c_int::MAX as u128) as c_int;
 
-    let (host_str, port_str) = split.rsplit_once(':')
-        .ok_or_else(|| create_parse_error("Address format must be 'HOST:PORT'"))?;
-    
-    let host_str = host_str.trim();
-    let port_str = port_str.trim();
-
-    let port: u16 = port_str.parse()
-        .map_err(|_| create_parse_error(&format!("Invalid port value: '{}'", port_str)))?;
-
-    let final_host_str = match host_str {
-        "*" | "0.0.0.0" => {
-            return Ok((0u32, port.to_be()));
+    loop {
+        let rc = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
         }
-        host if host.eq_ignore_ascii_case("localhost") => "127.0.0.1",
-        host => host,
-    };
+        return Ok(rc > 0);
+    }
+}
 
-    let mut octets: [u8; 4] = [0; 4];
-    
-    for (i, part) in final_host_str.split('.').enumerate() {
-        if i >= 4 { 
-            return Err(create_parse_error(&format!("Invalid IPv4 format: '{}' has too many octets", final_host_str)));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use crate::http::response::OK;
+    use crate::http::router::router::SimpleHandler;
+
+    fn serve_over_socket(requests: &[u8]) -> String {
+        serve_logged(requests, None)
+    }
 
-        let octet_val = part.parse::<u8>()
-            .map_err(|_| create_parse_error(&format!("Invalid octet value: '{}'", part)))?;
-        
-        octets[i] = octet_val;
+    fn serve_logged(requests: &[u8], access_log: Option<Arc<AccessLog>>) -> String {
+        let dispatcher = Dispatcher::builder()
+            .get("/echo", Arc::new(SimpleHandler(|req: &HttpRequest| Ok(Response::new(OK).with_body(req.query.clone())))))
+            .build();
+        let server = HttpServer::with_dispatcher(ServerConfig::default(), dispatcher);
+        let (mut client, conn) = UnixStream::pair().unwrap();
+        client.write_all(requests).unwrap();
+
+        let fd = conn.into_raw_fd();
+        let (dispatcher, limits) = (Arc::clone(&server.dispatcher), server.limits());
+        let handle = thread::spawn(move || HttpServer::serve_client(fd, dispatcher, limits, None, access_log));
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        handle.join().unwrap().unwrap();
+        out
     }
 
-    if final_host_str.split('.').count() != 4 {
-        return Err(create_parse_error(&format!("Invalid IPv4 format: '{}' must have 4 octets", final_host_str)));
+    #[test]
+    fn serves_pipelined_requests_on_one_connection() {
+        let out = serve_over_socket(
+            b"GET /echo?a HTTP/1.1\r\nHost: x\r\n\r\n\r\nGET /echo?b HTTP/1.1\r\nHost: x\r\n\r\nGET /echo?c HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        let responses: Vec<&str> = out.split("HTTP/1.1 200 OK").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].contains("Connection: keep-alive") && responses[0].ends_with("\r\n\r\na"));
+        assert!(responses[1].ends_with("\r\n\r\nb"));
+        assert!(responses[2].contains("Connection: close") && responses[2].ends_with("\r\n\r\nc"));
     }
 
-    let ip_host: u32 = u32::from_ne_bytes(octets);
-    let port_host: u16 = port;
+    #[test]
+    fn closes_http_1_0_connections_unless_kept_alive() {
+        let out = serve_over_socket(b"GET /echo?a HTTP/1.0\r\n\r\nGET /echo?b HTTP/1.0\r\n\r\n");
+        assert_eq!(out.matches("200 OK").count(), 1);
 
-    Ok((ip_host, port_host))
-}   
\ No newline at end of file
+        let out = serve_over_socket(b"GET /echo?a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /echo?b HTTP/1.0\r\n\r\n");
+        assert_eq!(out.matches("200 OK").count(), 2);
+    }
+
+    #[test]
+    fn logs_requests_refused_before_they_are_parsed() {
+        let path = std::env::temp_dir().join(format!("refused-{}.log", std::process::id()));
+        let log = Arc::new(AccessLog::open(path.to_str().unwrap(), LogFormat::Common).unwrap());
+
+        assert!(serve_logged(b"GET /echo?a HTTP/1.1\r\n\r\n", Some(Arc::clone(&log))).contains(" 400 Bad Request"));
+        let long = format!("GET /{} HTTP/1.1\r\nHost: x\r\n\r\n", "a".repeat(9000));
+        assert!(serve_logged(long.as_bytes(), Some(Arc::clone(&log))).contains(" 414 URI Too Long"));
+        let (client, conn) = UnixStream::pair().unwrap();
+        HttpServer::reject_client(conn.into_raw_fd(), crate::http::response::SERVICE_UNAVAILABLE, "busy", None, Some(&log));
+        drop(client);
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("\"GET /echo?a HTTP/1.1\" 400 "), "{}", lines[0]);
+        assert!(lines[1].contains("\"-\" 414 "), "{}", lines[1]);
+        assert!(lines[2].ends_with("\"-\" 503 4"), "{}", lines[2]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,132 @@
+//! Request bodies kept on disk instead of in memory.
+//!
+//! A route whose handler accepts more than `MAX_BODY_BYTES` (see
+//! `RequestHandlerStrategy::max_body_bytes`) gets the part of a body past
+//! that cap streamed to a temporary file in `TMPDIR`. The file is unlinked
+//! as soon as it is created, so it goes away with the last handle to it.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    ops::Deref,
+    os::unix::fs::FileExt,
+    os::fd::AsRawFd,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+};
+
+static NEXT_SPOOL: AtomicU64 = AtomicU64::new(0);
+
+/// A request body that was written to disk as it arrived.
+#[derive(Debug, Clone)]
+pub struct SpooledBody {
+    file: Arc<File>,
+    len: u64,
+}
+
+impl SpooledBody {
+    /// Writes `head` followed by everything `rest` yields to a new
+    /// temporary file.
+    pub(crate) fn create<R: Read>(head: &[u8], rest: &mut R) -> io::Result<Self> {
+        let path = env::temp_dir().join(format!(
+            ".body-{}-{}.spool",
+            std::process::id(),
+            NEXT_SPOOL.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        fs::remove_file(&path)?;
+
+        file.write_all(head)?;
+        let len = head.len() as u64 + io::copy(rest, &mut file)?;
+        Ok(Self { file: Arc::new(file), len })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the body from its first byte. Readers are independent of
+    /// each other.
+    pub fn reader(&self) -> impl Read + '_ {
+        SpoolReader { file: &self.file, pos: 0 }
+    }
+
+    /// Maps the body into memory read-only, for parsers that need it as
+    /// one slice.
+    pub fn map(&self) -> io::Result<MappedBody<'_>> {
+        if self.len == 0 {
+            return Ok(MappedBody { ptr: std::ptr::null_mut(), len: 0, _body: self });
+        }
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), self.len as usize, libc::PROT_READ, libc::MAP_PRIVATE, self.file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(MappedBody { ptr, len: self.len as usize, _body: self })
+    }
+}
+
+struct SpoolReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for SpoolReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// A spooled body mapped into memory; unmapped on drop.
+pub struct MappedBody<'a> {
+    ptr: *mut libc::c_void,
+    len: usize,
+    _body: &'a SpooledBody,
+}
+
+impl Deref for MappedBody<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr.cast::<u8>(), self.len) }
+    }
+}
+
+impl Drop for MappedBody<'_> {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_maps_what_was_spooled() {
+        let body = SpooledBody::create(b"head ", &mut &b"and the rest"[..]).unwrap();
+        assert_eq!(body.len(), 17);
+
+        let mut text = String::new();
+        body.reader().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "head and the rest");
+        // Each reader starts over
+        let mut again = Vec::new();
+        body.reader().read_to_end(&mut again).unwrap();
+        assert_eq!(again, text.as_bytes());
+
+        assert_eq!(&*body.map().unwrap(), text.as_bytes());
+    }
+}
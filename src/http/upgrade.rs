@@ -0,0 +1,91 @@
+//! Handing a connection over to another protocol after
+//! `101 Switching Protocols`.
+//!
+//! A handler attaches an `Upgrade` to its 101 response. Once the head has
+//! been written, the connection layer stops treating the socket as HTTP and
+//! runs the upgrade on it with blocking I/O: on the connection's own thread
+//! in threaded mode, on a thread of its own in epoll mode.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::AsRawFd,
+    time::Duration,
+};
+
+use crate::http::server;
+
+/// Runs the new protocol; the connection is closed when it returns.
+pub struct Upgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+impl Upgrade {
+    pub fn new(f: impl FnOnce(Upgraded) + Send + 'static) -> Self {
+        Self(Box::new(f))
+    }
+
+    pub(crate) fn run(self, conn: Upgraded) {
+        (self.0)(conn)
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// The raw client connection, including any bytes the client sent after
+/// the upgrade request that were already buffered.
+pub struct Upgraded {
+    socket: File,
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl Upgraded {
+    pub(crate) fn new(socket: File, buffered: Vec<u8>) -> Self {
+        Self { socket, buffered, pos: 0 }
+    }
+
+    /// A second handle on the same socket, e.g. for writing from another
+    /// thread. Bytes buffered in this handle are not shared.
+    pub fn try_clone(&self) -> io::Result<Upgraded> {
+        Ok(Self::new(self.socket.try_clone()?, Vec::new()))
+    }
+
+    /// Waits up to `timeout` for something to read.
+    pub fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        if self.pos < self.buffered.len() {
+            return Ok(true);
+        }
+        server::wait_readable(self.socket.as_raw_fd(), timeout)
+    }
+
+    /// Shuts both directions down, waking up a thread blocked in `read`.
+    pub fn shutdown(&self) {
+        unsafe { libc::shutdown(self.socket.as_raw_fd(), libc::SHUT_RDWR) };
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let n = buf.len().min(self.buffered.len() - self.pos);
+            buf[..n].copy_from_slice(&self.buffered[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.socket.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
@@ -0,0 +1,91 @@
+//! `application/x-www-form-urlencoded` decoding, shared by query strings
+//! and form POST bodies.
+
+/// Splits `input` on `&` and decodes every `key=value` pair. Keys without
+/// `=` get an empty value; empty segments are skipped. Order and repeated
+/// keys are preserved.
+pub fn parse(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(k), decode(v))
+        })
+        .collect()
+}
+
+/// Decodes `+` as a space and `%XX` escapes as raw bytes, then reads the
+/// result as UTF-8 (invalid sequences become U+FFFD). A `%` that is not
+/// followed by two hex digits is kept literally.
+pub fn decode(input: &str) -> String {
+    decode_with(input, true)
+}
+
+/// Like `decode`, but for path segments, where `+` is an ordinary character.
+pub fn decode_path(input: &str) -> String {
+    decode_with(input, false)
+}
+
+fn decode_with(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => out.push(b' '),
+            b'%' => match (bytes.get(i + 1).and_then(|b| hex(*b)), bytes.get(i + 2).and_then(|b| hex(*b))) {
+                (Some(hi), Some(lo)) => {
+                    out.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes_and_plus() {
+        assert_eq!(decode("hello%20world"), "hello world");
+        assert_eq!(decode("a+b%2Bc"), "a b+c");
+        assert_eq!(decode("%5Ba-z%5D%2A"), "[a-z]*");
+        assert_eq!(decode("caf%C3%A9"), "café");
+        assert_eq!(decode_path("a+b%20c"), "a+b c");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn parses_pairs_in_order() {
+        assert_eq!(
+            parse("a=1&b=x+y&a=2&flag&&c=%3D"),
+            vec![
+                ("a".into(), "1".into()),
+                ("b".into(), "x y".into()),
+                ("a".into(), "2".into()),
+                ("flag".into(), "".into()),
+                ("c".into(), "=".into()),
+            ]
+        );
+        assert!(parse("").is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+//! WebSocket connections (RFC 6455).
+//!
+//! `handshake` answers a client's opening request with `101 Switching
+//! Protocols` and hands the upgraded connection to a callback as a
+//! `WebSocket`. Reading reassembles fragmented messages and deals with
+//! control frames on its own: pings get a pong, and a close frame is echoed
+//! before `recv` reports the end of the conversation. A client that breaks
+//! the protocol is sent a close frame with the matching status code.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use sha1::{Digest, Sha1};
+
+use crate::http::{
+    errors::ServerError,
+    request::{HttpMethod, HttpRequest},
+    response::{Response, SWITCHING_PROTOCOLS, UPGRADE_REQUIRED},
+    upgrade::{Upgrade, Upgraded},
+};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest reassembled message accepted unless configured otherwise.
+pub const DEFAULT_MAX_MESSAGE: usize = 1 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.trim().as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(sha.finalize())
+}
+
+/// Checks the opening handshake in `req` and returns the 101 response;
+/// `on_open` then runs with the connection. Requests that are not a
+/// WebSocket upgrade, or ask for a version other than 13, get 426.
+pub fn handshake(req: &HttpRequest, on_open: impl FnOnce(WebSocket) + Send + 'static) -> Result<Response, ServerError> {
+    if req.method != HttpMethod::GET || req.version != "HTTP/1.1" {
+        return Err(ServerError::BadRequest("WebSocket handshake requires GET over HTTP/1.1".into()));
+    }
+    if !has_token(req.header("Upgrade"), "websocket") || !has_token(req.header("Connection"), "upgrade") {
+        return Ok(upgrade_required("Expected a WebSocket upgrade request"));
+    }
+    if req.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Ok(upgrade_required("Unsupported WebSocket version"));
+    }
+
+    let key = req.header("Sec-WebSocket-Key").unwrap_or("").trim();
+    if STANDARD.decode(key).map(|k| k.len()).unwrap_or(0) != 16 {
+        return Err(ServerError::BadRequest("Invalid Sec-WebSocket-Key".into()));
+    }
+
+    let upgrade = Upgrade::new(move |conn| match WebSocket::new(conn) {
+        Ok(ws) => on_open(ws),
+        Err(e) => eprintln!("WebSocket setup failed: {}", e),
+    });
+    Ok(Response::new(SWITCHING_PROTOCOLS)
+        .with_upgrade("websocket", upgrade)
+        .set_header("Sec-WebSocket-Accept", &accept_key(key)))
+}
+
+fn upgrade_required(message: &str) -> Response {
+    Response::new(UPGRADE_REQUIRED)
+        .set_header("Content-Type", "application/json")
+        .set_header("Upgrade", "websocket")
+        .set_header("Sec-WebSocket-Version", "13")
+        .with_body(format!("{{\"error\": \"UpgradeRequired: {}\"}}", message))
+}
+
+/// Whether the comma-separated header `value` lists `token`.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))).unwrap_or(false)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Why reading stopped: the socket failed, or the client broke the
+/// protocol and must be sent a close frame with this code.
+#[derive(Debug)]
+enum Fault {
+    Io(io::Error),
+    Close(u16, &'static str),
+}
+
+impl From<io::Error> for Fault {
+    fn from(e: io::Error) -> Self {
+        Fault::Io(e)
+    }
+}
+
+/// Reads one client frame and unmasks its payload.
+fn read_frame<R: Read>(r: &mut R, max_payload: usize) -> Result<Frame, Fault> {
+    let mut head = [0u8; 2];
+    r.read_exact(&mut head)?;
+
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+    }
+    if !matches!(opcode, OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG) {
+        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Unknown opcode"));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Client frames must be masked"));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut b = [0u8; 2];
+            r.read_exact(&mut b)?;
+            u16::from_be_bytes(b) as u64
+        }
+        127 => {
+            let mut b = [0u8; 8];
+            r.read_exact(&mut b)?;
+            u64::from_be_bytes(b)
+        }
+        n => n as u64,
+    };
+
+    // Control frames may be interleaved with fragments, so they must fit in one
+    if opcode & 0x8 != 0 && (!fin || len > 125) {
+        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Invalid control frame"));
+    }
+    if len > max_payload as u64 {
+        return Err(Fault::Close(CLOSE_TOO_BIG, "Message too big"));
+    }
+
+    let mut mask = [0u8; 4];
+    r.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    Ok(Frame { fin, opcode, payload })
+}
+
+/// Writes a single unmasked, unfragmented frame.
+fn write_frame<W: Write>(w: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+/// The status code of a close frame, if it carries one.
+fn parse_close(payload: &[u8]) -> Result<Option<u16>, Fault> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    if payload.len() < 2 {
+        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Truncated close frame"));
+    }
+
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    // 1004-1006 and 1015 are reserved for reporting, never sent
+    if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Invalid close code"));
+    }
+    if std::str::from_utf8(&payload[2..]).is_err() {
+        return Err(Fault::Close(CLOSE_INVALID_DATA, "Close reason is not valid UTF-8"));
+    }
+    Ok(Some(code))
+}
+
+fn message(opcode: u8, data: Vec<u8>) -> Result<Message, Fault> {
+    if opcode == OP_BINARY {
+        return Ok(Message::Binary(data));
+    }
+    String::from_utf8(data)
+        .map(Message::Text)
+        .map_err(|_| Fault::Close(CLOSE_INVALID_DATA, "Text message is not valid UTF-8"))
+}
+
+struct SenderState {
+    conn: Upgraded,
+    /// A close frame went out; nothing may follow it.
+    closed: bool,
+}
+
+/// The sending half of a `WebSocket`. Clones can be used from other
+/// threads; frames sent through them never interleave.
+#[derive(Clone)]
+pub struct WsSender {
+    state: Arc<Mutex<SenderState>>,
+}
+
+impl WsSender {
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.send(OP_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> io::Result<()> {
+        self.send(OP_BINARY, data)
+    }
+
+    /// Starts the closing handshake. `reason` is cut to fit a control frame.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.send(OP_CLOSE, &payload)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().expect("websocket sender mutex").closed
+    }
+
+    fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().expect("websocket sender mutex");
+        if state.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "WebSocket closed"));
+        }
+        state.closed = opcode == OP_CLOSE;
+        write_frame(&mut state.conn, opcode, payload)
+    }
+}
+
+pub struct WebSocket {
+    reader: Upgraded,
+    sender: WsSender,
+    max_message: usize,
+    /// Opcode and data of a fragmented message still being received.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl WebSocket {
+    pub fn new(conn: Upgraded) -> io::Result<Self> {
+        let writer = conn.try_clone()?;
+        Ok(Self {
+            reader: conn,
+            sender: WsSender { state: Arc::new(Mutex::new(SenderState { conn: writer, closed: false })) },
+            max_message: DEFAULT_MAX_MESSAGE,
+            partial: None,
+        })
+    }
+
+    pub fn with_max_message(mut self, max_message: usize) -> Self {
+        self.max_message = max_message;
+        self
+    }
+
+    pub fn sender(&self) -> WsSender {
+        self.sender.clone()
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.sender.send_text(text)
+    }
+
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.sender.close(code, reason)
+    }
+
+    /// Waits up to `timeout` for the client to send something.
+    pub fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        self.reader.wait_readable(timeout)
+    }
+
+    /// The next message, or `None` once the client closed the connection.
+    /// Protocol errors close it from this side and are returned as
+    /// `InvalidData`.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        match self.read_message() {
+            Ok(msg) => Ok(msg),
+            Err(Fault::Io(e)) => Err(e),
+            Err(Fault::Close(code, reason)) => {
+                let _ = self.sender.close(code, reason);
+                Err(io::Error::new(ErrorKind::InvalidData, reason))
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Option<Message>, Fault> {
+        loop {
+            let frame = read_frame(&mut self.reader, self.max_message)?;
+            match frame.opcode {
+                OP_PING => {
+                    if !self.sender.is_closed() {
+                        self.sender.send(OP_PONG, &frame.payload)?;
+                    }
+                }
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let code = parse_close(&frame.payload)?;
+                    // Echo the code back to complete the closing handshake
+                    if !self.sender.is_closed() {
+                        let _ = match code {
+                            Some(code) => self.sender.close(code, ""),
+                            None => self.sender.send(OP_CLOSE, &[]),
+                        };
+                    }
+                    return Ok(None);
+                }
+                OP_CONTINUATION => {
+                    let Some((_, data)) = self.partial.as_mut() else {
+                        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Unexpected continuation frame"));
+                    };
+                    if data.len() + frame.payload.len() > self.max_message {
+                        return Err(Fault::Close(CLOSE_TOO_BIG, "Message too big"));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, data) = self.partial.take().unwrap_or_default();
+                        return message(opcode, data).map(Some);
+                    }
+                }
+                opcode => {
+                    if self.partial.is_some() {
+                        return Err(Fault::Close(CLOSE_PROTOCOL_ERROR, "Expected a continuation frame"));
+                    }
+                    if frame.fin {
+                        return message(opcode, frame.payload).map(Some);
+                    }
+                    self.partial = Some((opcode, frame.payload));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixStream;
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// A `WebSocket` on one end of a socket pair, and the client's end.
+    fn pair() -> (WebSocket, UnixStream) {
+        let (server, client) = UnixStream::pair().unwrap();
+        let conn = Upgraded::new(File::from(OwnedFd::from(server)), Vec::new());
+        (WebSocket::new(conn).unwrap(), client)
+    }
+
+    #[test]
+    fn computes_accept_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn validates_handshake() {
+        let request = |raw: &str| HttpRequest::parse(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap();
+        let raw = "GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+        let resp = handshake(&request(raw), |_| {}).unwrap();
+        assert_eq!(resp.status.code, 101);
+        assert!(resp.upgrade.is_some());
+        assert_eq!(resp.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let resp = handshake(&request(&raw.replace("Version: 13", "Version: 8")), |_| {}).unwrap();
+        assert_eq!(resp.status.code, 426);
+        assert_eq!(resp.headers.get("Sec-WebSocket-Version"), Some("13"));
+
+        let plain = handshake(&request("GET /ws HTTP/1.1\r\nHost: x\r\n\r\n"), |_| {}).unwrap();
+        assert_eq!(plain.status.code, 426);
+        assert!(handshake(&request(&raw.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=")), |_| {}).is_err());
+    }
+
+    #[test]
+    fn parses_and_rejects_frames() {
+        // The masked "Hello" example from RFC 6455 section 5.7
+        let hello = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let frame = read_frame(&mut Cursor::new(hello), 1024).unwrap();
+        assert_eq!(frame, Frame { fin: true, opcode: OP_TEXT, payload: b"Hello".to_vec() });
+
+        let unmasked = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(matches!(read_frame(&mut Cursor::new(unmasked), 1024), Err(Fault::Close(CLOSE_PROTOCOL_ERROR, _))));
+        let fragmented_ping = client_frame(false, OP_PING, b"");
+        assert!(matches!(read_frame(&mut Cursor::new(fragmented_ping), 1024), Err(Fault::Close(CLOSE_PROTOCOL_ERROR, _))));
+        assert!(matches!(read_frame(&mut Cursor::new(hello), 4), Err(Fault::Close(CLOSE_TOO_BIG, _))));
+
+        let mut out = Vec::new();
+        write_frame(&mut out, OP_BINARY, &[7u8; 300]).unwrap();
+        assert_eq!(&out[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(out.len(), 304);
+    }
+
+    #[test]
+    fn reassembles_fragments_and_answers_control_frames() {
+        let (mut ws, mut client) = pair();
+        let mut wire = client_frame(false, OP_TEXT, b"hel");
+        wire.extend(client_frame(true, OP_PING, b"p"));
+        wire.extend(client_frame(true, OP_CONTINUATION, b"lo"));
+        wire.extend(client_frame(true, OP_CLOSE, &1000u16.to_be_bytes()));
+        client.write_all(&wire).unwrap();
+
+        assert_eq!(ws.recv().unwrap(), Some(Message::Text("hello".into())));
+        assert_eq!(ws.recv().unwrap(), None);
+        assert!(ws.send_text("late").is_err());
+
+        let mut reply = [0u8; 7];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [0x8A, 1, b'p', 0x88, 2, 0x03, 0xE8]);
+    }
+}
@@ -1,11 +1,12 @@
-use crate::utils::io::compress::compress_file;
+use crate::utils::io::compress::compress_file_with_progress;
+use crate::utils::progress::Progress;
 use std::collections::HashMap;
 
-pub fn run(params: &HashMap<String, String>) -> Result<String, String> {
+pub fn run(params: &HashMap<String, String>, progress: Progress) -> Result<String, String> {
     let name = params.get("name").cloned().unwrap_or_default();
     let codec = params.get("codec").cloned().unwrap_or("gzip".into());
 
-    match compress_file(&name, &codec) {
+    match compress_file_with_progress(&name, &codec, progress) {
         Ok(result) => {
             let output = result.output_file.file_name().and_then(|n| n.to_str()).unwrap_or("unknown");
             Ok(format!(
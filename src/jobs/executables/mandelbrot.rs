@@ -1,7 +1,8 @@
-use crate::utils::cpu::mandelbrot::mandelbrot;
+use crate::utils::cpu::mandelbrot::mandelbrot_with_progress;
+use crate::utils::progress::Progress;
 use std::collections::HashMap;
 
-pub fn run(params: &HashMap<String, String>) -> Result<String, String> {
+pub fn run(params: &HashMap<String, String>, progress: Progress) -> Result<String, String> {
     let width = params
         .get("width")
         .and_then(|v| v.parse::<usize>().ok())
@@ -22,7 +23,7 @@ pub fn run(params: &HashMap<String, String>) -> Result<String, String> {
     }
 
     // ✅ Correct destructuring: mandelbrot returns (map, elapsed)
-    let (map, elapsed_calc) = mandelbrot(width, height, max_iter, None);
+    let (map, elapsed_calc) = mandelbrot_with_progress(width, height, max_iter, None, progress);
 
     let rows_json = map
         .iter()
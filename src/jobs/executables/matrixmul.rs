@@ -1,7 +1,8 @@
-use crate::utils::cpu::matrixmul::matrixmul;
+use crate::utils::cpu::matrixmul::matrixmul_with_progress;
+use crate::utils::progress::Progress;
 use std::collections::HashMap;
 
-pub fn run(params: &HashMap<String, String>) -> Result<String, String> {
+pub fn run(params: &HashMap<String, String>, progress: Progress) -> Result<String, String> {
     let size = params
         .get("size")
         .and_then(|v| v.parse::<usize>().ok())
@@ -16,7 +17,7 @@ pub fn run(params: &HashMap<String, String>) -> Result<String, String> {
         return Err("Matrix size must be between 1 and 1000".into());
     }
 
-    let (hash, elapsed_calc) = matrixmul(size, seed);
+    let (hash, elapsed_calc) = matrixmul_with_progress(size, seed, progress);
 
     Ok(format!(
         "{{\"size\": {}, \"seed\": {}, \"result_sha256\": \"{}\", \"elapsed_ms\": {}}}",
@@ -1,11 +1,12 @@
-use crate::utils::io::sort_file::sort_file;
+use crate::utils::io::sort_file::sort_file_with_progress;
+use crate::utils::progress::Progress;
 use std::path::PathBuf;
 
-pub fn run(params: &std::collections::HashMap<String, String>) -> Result<String, String> {
+pub fn run(params: &std::collections::HashMap<String, String>, progress: Progress) -> Result<String, String> {
     let name = params.get("name").cloned().unwrap_or_default();
     let algo = params.get("algo").cloned().unwrap_or("merge".into());
 
-    match sort_file(&name, &algo) {
+    match sort_file_with_progress(&name, &algo, progress) {
         Ok((out_path, count, sort_elapsed)) => {
             let sorted_name = out_path
                 .file_name()
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
@@ -13,6 +13,13 @@ pub enum JobStatus {
     Timeout,
 }
 
+impl JobStatus {
+    /// Done, Error, Canceled and Timeout never change again.
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Priority {
     Low,
@@ -34,6 +41,8 @@ pub struct Job {
     pub finished_at: Arc<Mutex<Option<Instant>>>,
     pub timeout: Duration,
     pub cancel_flag: Arc<Mutex<bool>>,
+    /// Signalled, under the `status` lock, whenever status or progress change.
+    pub changed: Arc<Condvar>,
 }
 
 impl Job {
@@ -51,6 +60,7 @@ impl Job {
             finished_at: Arc::new(Mutex::new(None)),
             timeout,
             cancel_flag: Arc::new(Mutex::new(false)),
+            changed: Arc::new(Condvar::new()),
         }
     }
 
@@ -60,6 +70,38 @@ impl Job {
         job
     }
 
+    pub fn set_status(&self, status: JobStatus) {
+        *self.status.lock().unwrap() = status;
+        self.changed.notify_all();
+    }
+
+    /// Records progress in `0.0..=1.0`, in whole percents so watchers are
+    /// not woken for every row or buffer a task gets through.
+    pub fn set_progress(&self, progress: f32) {
+        let progress = (progress.clamp(0.0, 1.0) * 100.0).floor() / 100.0;
+        let _status = self.status.lock().unwrap();
+        let mut current = self.progress.lock().unwrap();
+        if *current != progress {
+            *current = progress;
+            self.changed.notify_all();
+        }
+    }
+
+    pub fn snapshot(&self) -> (JobStatus, f32) {
+        let status = self.status.lock().unwrap();
+        (status.clone(), *self.progress.lock().unwrap())
+    }
+
+    /// Blocks until status or progress differ from `seen`, or `timeout`
+    /// passes, and returns the current values.
+    pub fn wait_for_change(&self, seen: &(JobStatus, f32), timeout: Duration) -> (JobStatus, f32) {
+        let status = self.status.lock().unwrap();
+        let (status, _) = self.changed
+            .wait_timeout_while(status, timeout, |s| *s == seen.0 && *self.progress.lock().unwrap() == seen.1)
+            .unwrap();
+        (status.clone(), *self.progress.lock().unwrap())
+    }
+
     pub fn is_expired(&self) -> bool {
         self.created_at.elapsed() > self.timeout
     }
@@ -81,3 +123,48 @@ impl Job {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn wait_for_change_returns_on_a_new_status_or_the_timeout() {
+        let job = Arc::new(Job::new("isprime", HashMap::new(), Duration::from_secs(60)));
+        let queued = (JobStatus::Queued, 0.0);
+
+        let started = Instant::now();
+        assert_eq!(job.wait_for_change(&queued, Duration::from_millis(30)), queued);
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        let setter = {
+            let job = Arc::clone(&job);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                job.set_status(JobStatus::Running);
+            })
+        };
+        let started = Instant::now();
+        assert_eq!(job.wait_for_change(&queued, Duration::from_secs(10)), (JobStatus::Running, 0.0));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        setter.join().unwrap();
+
+        // Already different: no wait at all
+        assert_eq!(job.wait_for_change(&queued, Duration::from_secs(10)), (JobStatus::Running, 0.0));
+    }
+
+    #[test]
+    fn progress_wakes_watchers_once_per_percent() {
+        let job = Job::new("mandelbrot", HashMap::new(), Duration::from_secs(60));
+        let running = (JobStatus::Running, 0.0);
+        job.set_status(JobStatus::Running);
+
+        job.set_progress(0.004);
+        assert_eq!(job.snapshot(), running);
+        job.set_progress(0.257);
+        assert_eq!(job.wait_for_change(&running, Duration::from_secs(10)), (JobStatus::Running, 0.25));
+        job.set_progress(7.0);
+        assert_eq!(job.snapshot().1, 1.0);
+    }
+}
@@ -1,7 +1,7 @@
 use std::{collections::HashMap, env, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant}};
 use crate::jobs::{
     job::{Job, JobStatus, Priority},
-    persistence::{save_job_state, load_job_states, remove_job_state},
+    persistence::{save_job_state, save_job_states, load_job_states, remove_job_state},
     workers::{cpu_pool::CpuPool, io_pool::IoPool, worker::WorkerMetrics},
 };
 
@@ -18,6 +18,9 @@ pub struct PoolMetrics {
     pub worker_metrics: Arc<WorkerMetrics>,
 }
 
+/// Where job state is kept unless `JOB_PERSIST_PATH` says otherwise.
+pub const DEFAULT_PERSIST_PATH: &str = "./data/persistent/state.jsonl";
+
 pub struct JobManager {
     pub cpu_pool: Arc<CpuPool>,
     pub io_pool: Arc<IoPool>,
@@ -27,11 +30,15 @@ pub struct JobManager {
 
 impl JobManager {
     pub fn new(cpu_workers: usize, io_workers: usize) -> Arc<Self> {
-        let jobs = Arc::new(Mutex::new(HashMap::new()));
         let persist_path = std::env::var("JOB_PERSIST_PATH")
             .map(PathBuf::from)
-            .unwrap_or_else(|_| PathBuf::from("./data/persistent/state.jsonl"));
-            
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_PERSIST_PATH));
+        Self::with_persist_path(cpu_workers, io_workers, persist_path)
+    }
+
+    /// Like `new`, with job state kept at `persist_path`.
+    pub fn with_persist_path(cpu_workers: usize, io_workers: usize, persist_path: PathBuf) -> Arc<Self> {
+        let jobs = Arc::new(Mutex::new(HashMap::new()));
         let manager = Arc::new_cyclic(|weak_self| JobManager {
             cpu_pool: Arc::new(CpuPool::empty()),
             io_pool: Arc::new(IoPool::empty()),
@@ -93,16 +100,15 @@ impl JobManager {
 
         if let Err(_) = queue.try_enqueue(job.clone(), queue_max) {
             {
-                let mut status = job.status.lock().unwrap();
-                *status = JobStatus::Error(format!(
-                    "QueueFull: {} pool is at capacity (max={})",
-                    queue_type, queue_max
-                ));
                 *job.finished_at.lock().unwrap() = Some(std::time::Instant::now());
                 *job.result.lock().unwrap() = Some(format!(
                     r#"{{"error":"queue_full","pool":"{}","max":{}}}"#,
                     queue_type, queue_max
                 ));
+                job.set_status(JobStatus::Error(format!(
+                    "QueueFull: {} pool is at capacity (max={})",
+                    queue_type, queue_max
+                )));
             }
 
             save_job_state(&job, &self.persist_path);
@@ -140,22 +146,23 @@ impl JobManager {
 
     pub fn execute_job(&self, job: Arc<Job>) {
         {
-            *job.status.lock().unwrap() = JobStatus::Running;
             *job.started_at.lock().unwrap() = Some(Instant::now());
+            job.set_status(JobStatus::Running);
         }
 
+        let progress = |p: f32| job.set_progress(p);
         let out: Result<String, String> = match job.task.as_str() {
             "isprime" => executables::is_prime::run(&job.params),
             "factor" => executables::factor::run(&job.params),
             "pi" => executables::pi::run(&job.params),
-            "matrixmul" => executables::matrixmul::run(&job.params),
-            "mandelbrot" => executables::mandelbrot::run(&job.params),
+            "matrixmul" => executables::matrixmul::run(&job.params, &progress),
+            "mandelbrot" => executables::mandelbrot::run(&job.params, &progress),
 
-            "sortfile" => executables::sort_file::run(&job.params),
+            "sortfile" => executables::sort_file::run(&job.params, &progress),
             "wordcount" => executables::word_count::run(&job.params),
             "grep" => executables::grep::run(&job.params),
-            "compress" => executables::compress::run(&job.params),
-            "hashfile" => executables::hash_file::run(&job.params),
+            "compress" => executables::compress::run(&job.params, &progress),
+            "hashfile" => executables::hash_file::run(&job.params, &progress),
 
             _ => Err(format!("Unknown task '{}'", job.task)),
         };
@@ -163,8 +170,11 @@ impl JobManager {
         {
             *job.result.lock().unwrap() = out.clone().ok();
             *job.finished_at.lock().unwrap() = Some(Instant::now());
+            if out.is_ok() {
+                job.set_progress(1.0);
+            }
 
-            *job.status.lock().unwrap() = match out {
+            job.set_status(match out {
                 Ok(_) => {
                     if job.is_expired() {
                         JobStatus::Timeout
@@ -173,7 +183,7 @@ impl JobManager {
                     }
                 }
                 Err(e) => JobStatus::Error(e),
-            };
+            });
         }
 
         save_job_state(&job, &self.persist_path);
@@ -183,6 +193,10 @@ impl JobManager {
         self.jobs.lock().unwrap().get(id).map(|j| j.status.lock().unwrap().clone())
     }
 
+    pub fn job(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
     pub fn result(&self, id: &str) -> Option<String> {
         self.jobs.lock().unwrap().get(id).and_then(|j| j.result.lock().unwrap().clone())
     }
@@ -193,6 +207,7 @@ impl JobManager {
             let mut s = job.status.lock().unwrap();
             if *s == JobStatus::Queued {
                 *s = JobStatus::Canceled;
+                job.changed.notify_all();
                 true
             } else {
                 false
@@ -221,6 +236,32 @@ impl JobManager {
         m
     }
 
+    /// Graceful stop: workers finish their current job and stop dequeuing,
+    /// then the final state of every job is flushed to the journal.
+    /// Returns false if workers were still busy at the deadline or the
+    /// journal could not be written.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        // Close both queues first so neither pool picks up new work while
+        // the other is draining
+        self.cpu_pool.queue.close();
+        self.io_pool.queue.close();
+        let cpu_done = self.cpu_pool.shutdown(deadline);
+        let io_done = self.io_pool.shutdown(deadline);
+
+        let jobs: Vec<Arc<Job>> = self.jobs.lock().unwrap().values().cloned().collect();
+        let saved = save_job_states(jobs.iter().map(|j| j.as_ref()), &self.persist_path);
+
+        let pending = self.cpu_pool.queue.total_len() + self.io_pool.queue.total_len();
+        println!(
+            "[shutdown] workers drained: cpu={} io={}, {} job(s) persisted, {} left queued",
+            cpu_done, io_done, jobs.len(), pending
+        );
+
+        cpu_done && io_done && saved
+    }
+
     fn load_persistent_jobs(manager: &Arc<JobManager>) {
         let persist_path = &manager.persist_path;
         let previous_jobs = load_job_states(persist_path);
@@ -280,3 +321,52 @@ impl JobManager {
         println!("[restore] Completed loading job persistence from {:?}", persist_path);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn journal(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("jobs-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn shutdown_reports_workers_still_busy_at_the_deadline() {
+        let path = journal("busy");
+        let manager = JobManager::with_persist_path(1, 1, path.clone());
+        let id = manager
+            .submit("mandelbrot", params(&[("width", "800"), ("height", "800"), ("max_iter", "50000")]), Priority::Normal)
+            .unwrap();
+
+        let started = Instant::now();
+        while manager.status(&id) != Some(JobStatus::Running) && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!manager.shutdown(Duration::from_millis(50)));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn shutdown_persists_the_final_state_of_every_job() {
+        let path = journal("drain");
+        // No workers, so submitted jobs stay queued
+        let manager = JobManager::with_persist_path(0, 0, path.clone());
+        let kept = manager.submit("isprime", params(&[("n", "7")]), Priority::Normal).unwrap();
+        let canceled = manager.submit("isprime", params(&[("n", "11")]), Priority::High).unwrap();
+        assert!(manager.cancel(&canceled));
+
+        assert!(manager.shutdown(Duration::from_secs(1)));
+        let saved = load_job_states(&path);
+        let status_of = |id: &str| saved.iter().find(|j| j.id == id).map(|j| j.status.clone());
+        assert_eq!(status_of(&kept), Some(JobStatus::Queued));
+        assert_eq!(status_of(&canceled), Some(JobStatus::Canceled));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -12,10 +12,8 @@ lazy_static! {
     static ref FILE_LOCK: Mutex<()> = Mutex::new(());
 }
 
-pub fn save_job_state(job: &Job, path: &Path) {
-    let _guard = FILE_LOCK.lock().unwrap();
-
-    let snapshot = json!({
+fn snapshot(job: &Job) -> Value {
+    json!({
         "id": job.id,
         "task": job.task,
         "priority": format!("{:?}", job.priority),
@@ -28,7 +26,13 @@ pub fn save_job_state(job: &Job, path: &Path) {
         "finished_at": job.finished_at.lock().unwrap().map(|t| t.elapsed().as_millis()),
         "timeout_secs": job.timeout.as_secs(),
         "cancel_flag": *job.cancel_flag.lock().unwrap(),
-    });
+    })
+}
+
+pub fn save_job_state(job: &Job, path: &Path) {
+    let _guard = FILE_LOCK.lock().unwrap();
+
+    let snapshot = snapshot(job);
 
     if let Some(parent) = path.parent() {
         let _ = fs::create_dir_all(parent);
@@ -64,6 +68,38 @@ pub fn save_job_state(job: &Job, path: &Path) {
     }
 }
 
+/// Rewrites the state file with a snapshot of every job in one pass.
+/// Used on shutdown so the journal reflects the final state of each job.
+pub fn save_job_states<'a>(jobs: impl IntoIterator<Item = &'a Job>, path: &Path) -> bool {
+    let _guard = FILE_LOCK.lock().unwrap();
+
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut file = match OpenOptions::new().create(true).write(true).truncate(true).open(&tmp_path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("[persistence] failed to open state file: {}", e);
+            return false;
+        }
+    };
+
+    for job in jobs {
+        if let Err(e) = writeln!(file, "{}", snapshot(job)) {
+            eprintln!("[persistence] failed to write job {}: {}", job.id, e);
+            return false;
+        }
+    }
+
+    if let Err(e) = file.sync_all().and_then(|_| fs::rename(&tmp_path, path)) {
+        eprintln!("[persistence] failed to replace state file: {}", e);
+        return false;
+    }
+    true
+}
+
 #[derive(Debug, Clone)]
 pub struct SavedJob {
     pub id: String,
@@ -13,6 +13,7 @@ struct JobQueueInner {
     high: VecDeque<Arc<Job>>,
     normal: VecDeque<Arc<Job>>,
     low: VecDeque<Arc<Job>>,
+    closed: bool,
 }
 
 impl JobQueue {
@@ -22,6 +23,7 @@ impl JobQueue {
                 high: VecDeque::new(),
                 normal: VecDeque::new(),
                 low: VecDeque::new(),
+                closed: false,
             }),
             cv: Condvar::new(),
         }
@@ -55,24 +57,35 @@ impl JobQueue {
         self.cv.notify_one();
     }
 
-    pub fn dequeue(&self) -> Arc<Job> {
+    /// Blocks until a job is available. Returns `None` once the queue has
+    /// been closed; jobs still waiting stay queued for the next start.
+    pub fn dequeue(&self) -> Option<Arc<Job>> {
         let mut q = self.inner.lock().unwrap();
 
         loop {
+            if q.closed {
+                return None;
+            }
             if let Some(job) = q.high.pop_front() {
-                return job;
+                return Some(job);
             }
             if let Some(job) = q.normal.pop_front() {
-                return job;
+                return Some(job);
             }
             if let Some(job) = q.low.pop_front() {
-                return job;
+                return Some(job);
             }
 
             q = self.cv.wait_timeout(q, Duration::from_millis(500)).unwrap().0;
         }
     }
 
+    /// Stops handing out jobs and wakes every blocked worker.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.cv.notify_all();
+    }
+
     pub fn len_by_priority(&self) -> (usize, usize, usize) {
         let q = self.inner.lock().unwrap();
         (q.high.len(), q.normal.len(), q.low.len())
@@ -87,3 +100,48 @@ impl JobQueue {
         self.len_by_priority()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::Instant;
+
+    fn job(priority: Priority) -> Arc<Job> {
+        Arc::new(Job::with_priority("isprime", HashMap::new(), priority, Duration::from_secs(60)))
+    }
+
+    #[test]
+    fn dequeues_by_priority() {
+        let q = JobQueue::new();
+        q.enqueue(job(Priority::Low));
+        q.enqueue(job(Priority::High));
+        q.enqueue(job(Priority::Normal));
+        assert_eq!(q.len_by_priority(), (1, 1, 1));
+        assert!(q.try_enqueue(job(Priority::Normal), 3).is_err());
+
+        let order: Vec<Priority> = (0..3).map(|_| q.dequeue().unwrap().priority).collect();
+        assert_eq!(order, [Priority::High, Priority::Normal, Priority::Low]);
+    }
+
+    #[test]
+    fn close_wakes_blocked_workers_and_keeps_queued_jobs() {
+        let q = Arc::new(JobQueue::new());
+        let waiter = {
+            let q = Arc::clone(&q);
+            thread::spawn(move || q.dequeue().is_none())
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        q.close();
+        assert!(waiter.join().unwrap());
+        // Woken by the close, not by the periodic timeout
+        assert!(started.elapsed() < Duration::from_millis(400));
+
+        q.enqueue(job(Priority::Normal));
+        assert!(q.dequeue().is_none());
+        assert_eq!(q.total_len(), 1);
+    }
+}
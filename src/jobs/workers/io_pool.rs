@@ -1,27 +1,49 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use crate::jobs::{queue::JobQueue, manager::JobManager};
 use super::worker::{spawn_workers, WorkerMetrics};
 
 pub struct IoPool {
     pub queue: Arc<JobQueue>,
     pub metrics: Arc<WorkerMetrics>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
 }
 impl IoPool {
     pub fn new(size: usize, manager: Arc<JobManager>) -> Self {
         let queue = Arc::new(JobQueue::new());
-        let metrics = spawn_workers("IO", size, queue.clone(), manager);
-        Self { queue, metrics }
+        let (metrics, workers) = spawn_workers("IO", size, queue.clone(), manager);
+        Self { queue, metrics, workers: Mutex::new(workers) }
     }
 
     pub fn queue_lengths(&self) -> (usize, usize, usize) {
         self.queue.len_by_priority()
     }
+
+    /// Stops dequeuing and waits for workers to finish their current job.
+    /// Returns false if some were still busy at `deadline`.
+    pub fn shutdown(&self, deadline: Instant) -> bool {
+        self.queue.close();
+
+        let mut workers = self.workers.lock().unwrap();
+        while workers.iter().any(|w| !w.is_finished()) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        for w in workers.drain(..) {
+            let _ = w.join();
+        }
+        true
+    }
 }
 
 impl IoPool {
     pub fn empty() -> Self {
         let queue = Arc::new(JobQueue::new());
         let dummy_metrics = Arc::new(WorkerMetrics::new(0));
-        Self { queue, metrics: dummy_metrics }
+        Self { queue, metrics: dummy_metrics, workers: Mutex::new(Vec::new()) }
     }
 }
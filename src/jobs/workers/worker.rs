@@ -1,7 +1,7 @@
 use std::{
     collections::VecDeque,
     sync::{Arc, Mutex},
-    thread,
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
@@ -70,8 +70,9 @@ pub fn spawn_workers(
     pool_size: usize,
     queue: Arc<JobQueue>,
     manager: Arc<JobManager>,
-) -> Arc<WorkerMetrics> {
+) -> (Arc<WorkerMetrics>, Vec<JoinHandle<()>>) {
     let metrics = Arc::new(WorkerMetrics::new(pool_size));
+    let mut handles = Vec::with_capacity(pool_size);
 
     for idx in 0..pool_size {
         let queue = queue.clone();
@@ -79,8 +80,8 @@ pub fn spawn_workers(
         let metrics = metrics.clone();
         let tag = tag.to_string();
 
-        thread::spawn(move || loop {
-            let job = queue.dequeue();
+        // A closed queue means shutdown: the loop ends and nothing new starts
+        handles.push(thread::spawn(move || while let Some(job) = queue.dequeue() {
 
             if matches!(*job.status.lock().unwrap(), JobStatus::Canceled) {
                 continue;
@@ -107,7 +108,7 @@ pub fn spawn_workers(
 
             {
                 *job.started_at.lock().unwrap() = Some(Instant::now());
-                *job.status.lock().unwrap() = JobStatus::Running;
+                job.set_status(JobStatus::Running);
             }
 
             let exec_start = Instant::now();
@@ -145,7 +146,7 @@ pub fn spawn_workers(
             }
 
             if result.is_err() {
-                *job.status.lock().unwrap() = JobStatus::Error("panic".into());
+                job.set_status(JobStatus::Error("panic".into()));
             } else {
                 *job.finished_at.lock().unwrap() = Some(Instant::now());
             }
@@ -156,8 +157,8 @@ pub fn spawn_workers(
                     *active -= 1;
                 }
             }
-        });
+        }));
     }
 
-    metrics
+    (metrics, handles)
 }
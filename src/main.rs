@@ -1,15 +1,27 @@
 use std::env;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
 use dotenv::dotenv;
-use std::sync::Arc;
 
 use HTTP_Server::{
     http::{
-        router::router::build_routes,
-        server::{HttpServer, ServerConfig},
+        access_log::LogFormat,
+        listener::{self, SharedListener},
+        prefork::{self, PreforkConfig},
+        router::router::{build_routes, enabled_groups, SINGLE_PROCESS_GROUPS},
+        request::RequestLimits,
+        server::{HttpServer, IoModel, ServerConfig},
     },
-    jobs::manager::JobManager,
+    jobs::manager::{JobManager, DEFAULT_PERSIST_PATH},
+    utils::signal,
 };
 
+/// Process exit codes.
+const EXIT_OK: i32 = 0;
+const EXIT_FATAL: i32 = 1;
+const EXIT_DRAIN_TIMEOUT: i32 = 2;
+
 fn main() {
     dotenv().ok();
 
@@ -20,24 +32,162 @@ fn main() {
             .unwrap_or(default)
     };
 
-    let bind_addr = env::var("BIND_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    // Socket-activated services listen where the service manager says
+    let bind_addr = env::var("BIND_ADDRESS").unwrap_or_else(|_| match listener::inherited_fd_count() {
+        0 => "127.0.0.1:8080".to_string(),
+        _ => String::new(),
+    });
     let max_conns = parse_env_var("MAX_CONNECTIONS", 64);
-    let rate_limit = parse_env_var("RATE_LIMIT_PER_SEC", 200);
+    let accept_queue_size = parse_env_var("ACCEPT_QUEUE_SIZE", 128);
+    let accept_queue_wait = Duration::from_millis(parse_env_var("ACCEPT_QUEUE_WAIT_MS", 1000) as u64);
     let cpu_workers = parse_env_var("CPU_WORKERS", 4);
     let io_workers = parse_env_var("IO_WORKERS", 2);
+    let keep_alive_timeout = parse_env_var("KEEP_ALIVE_TIMEOUT", 5);
+    let max_keep_alive_requests = parse_env_var("MAX_KEEP_ALIVE_REQUESTS", 100);
+    let io_model = match env::var("IO_MODEL").unwrap_or_default().trim().to_lowercase().as_str() {
+        "epoll" => IoModel::Epoll,
+        _ => IoModel::Threaded,
+    };
+    let io_threads = parse_env_var("EPOLL_IO_THREADS", 2);
+    let handler_threads = parse_env_var("EPOLL_HANDLER_THREADS", 8);
+    let shutdown_timeout = Duration::from_secs(parse_env_var("SHUTDOWN_TIMEOUT", 30) as u64);
+
+    let request_limits = RequestLimits {
+        max_request_line: parse_env_var("MAX_REQUEST_LINE", 8 * 1024),
+        max_headers: parse_env_var("MAX_HEADER_COUNT", 100),
+        max_header_bytes: parse_env_var("MAX_HEADER_BYTES", 64 * 1024),
+        max_body_bytes: parse_env_var("MAX_BODY_BYTES", 10 * 1024 * 1024),
+    };
+    let read_timeout = Duration::from_secs(parse_env_var("READ_TIMEOUT", 10) as u64);
+    let write_timeout = Duration::from_secs(parse_env_var("WRITE_TIMEOUT", 10) as u64);
+
+    let access_log = env::var("ACCESS_LOG").ok().filter(|v| !v.trim().is_empty());
+    let access_log_format = match env::var("ACCESS_LOG_FORMAT") {
+        Ok(v) => v.parse().unwrap_or_else(|e| {
+            eprintln!("{}, using combined", e);
+            LogFormat::Combined
+        }),
+        Err(_) => LogFormat::Combined,
+    };
+
+    let ipv6_only = env::var("IPV6_ONLY")
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+
+    let unix_socket = env::var("UNIX_SOCKET").ok().filter(|v| !v.trim().is_empty()).map(|v| v.trim().into());
+    let unix_socket_mode = env::var("UNIX_SOCKET_MODE").ok().filter(|v| !v.trim().is_empty()).and_then(|v| {
+        u32::from_str_radix(v.trim(), 8)
+            .map_err(|_| eprintln!("Invalid UNIX_SOCKET_MODE '{}', expected octal like 660", v))
+            .ok()
+    });
+    let socket_activation = env::var("SOCKET_ACTIVATION")
+        .map(|v| !matches!(v.trim().to_lowercase().as_str(), "0" | "false" | "no"))
+        .unwrap_or(true);
 
     let cfg = ServerConfig {
         bind_addr,
+        ipv6_only,
+        reuse_port: false,
+        unix_socket,
+        unix_socket_mode,
+        socket_activation,
         max_connections: max_conns,
-        rate_limit_per_sec: rate_limit,
+        accept_queue_size,
+        accept_queue_wait,
+        keep_alive_timeout: Duration::from_secs(keep_alive_timeout as u64),
+        max_requests_per_connection: max_keep_alive_requests,
+        io_model,
+        io_threads,
+        handler_threads,
+        shutdown_timeout,
+        request_limits,
+        read_timeout,
+        write_timeout,
+        access_log,
+        access_log_format,
+    };
+
+    let prefork_workers = parse_env_var("PREFORK_WORKERS", 0);
+    let prefork_reuse_port = env::var("PREFORK_REUSE_PORT")
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+
+    signal::install_shutdown_handlers();
+    signal::install_reopen_handler();
+
+    let prefork = prefork_workers > 1;
+    let groups = enabled_groups(prefork);
+    if let Some(group) = groups.iter().find(|g| prefork && SINGLE_PROCESS_GROUPS.contains(g)) {
+        eprintln!("🛑 PREFORK_WORKERS={} cannot serve the '{}' route group; leave it out of ROUTE_GROUPS", prefork_workers, group);
+        process::exit(EXIT_FATAL);
+    }
+    if prefork && env::var("ROUTE_GROUPS").unwrap_or_default().trim().is_empty() {
+        println!("⚙️  prefork mode serves without the {} route group(s)", SINGLE_PROCESS_GROUPS.join(", "));
+    }
+
+    let code = if prefork_workers > 0 {
+        // Must fork before anything starts a thread
+        let prefork = PreforkConfig { workers: prefork_workers, reuse_port: prefork_reuse_port, shutdown_timeout };
+        let result = prefork::run(&cfg, &prefork, |index, cfg, shared| {
+            env::set_var("JOB_PERSIST_PATH", worker_persist_path(index));
+            serve(cfg, shared, cpu_workers, io_workers, &groups)
+        });
+        result.unwrap_or_else(|e| {
+            eprintln!("🛑 Server encountered a fatal error: {}", e);
+            EXIT_FATAL
+        })
+    } else {
+        serve(cfg, None, cpu_workers, io_workers, &groups)
     };
 
+    match signal::received_signal() {
+        Some(sig) => println!("👋 Stopped after signal {} (exit code {})", sig, code),
+        None => println!("👋 Stopped (exit code {})", code),
+    }
+    process::exit(code);
+}
+
+/// Runs a server with its own job pools until shutdown and returns the exit code.
+fn serve(cfg: ServerConfig, shared: Option<SharedListener>, cpu_workers: usize, io_workers: usize, groups: &[&str]) -> i32 {
+    let shutdown_timeout = cfg.shutdown_timeout;
     let job_manager = JobManager::new(cpu_workers, io_workers);
 
-    let dispatcher = build_routes(job_manager.clone());
-    let server = HttpServer::with_dispatcher(cfg, dispatcher);
+    let dispatcher = build_routes(job_manager.clone(), groups);
+    let mut server = HttpServer::with_dispatcher(cfg, dispatcher);
+    if let Some(shared) = shared {
+        server = server.with_listener(shared);
+    }
 
-    if let Err(e) = server.run() {
-        eprintln!("🛑 Server encountered a fatal error: {}", e);
+    let fatal = match server.run() {
+        Ok(()) => false,
+        Err(e) => {
+            eprintln!("🛑 Server encountered a fatal error: {}", e);
+            true
+        }
+    };
+
+    let connections_left = server.active_connections();
+    let jobs_drained = job_manager.shutdown(shutdown_timeout);
+
+    if fatal {
+        EXIT_FATAL
+    } else if connections_left > 0 || !jobs_drained {
+        eprintln!("🛑 Shutdown deadline exceeded ({} connection(s) still open)", connections_left);
+        EXIT_DRAIN_TIMEOUT
+    } else {
+        EXIT_OK
     }
 }
+
+/// Job pools are per process, so each prefork worker keeps its job state
+/// in a file of its own: `state.jsonl` becomes `state.worker0.jsonl`.
+fn worker_persist_path(index: usize) -> String {
+    let path = env::var("JOB_PERSIST_PATH").unwrap_or_else(|_| DEFAULT_PERSIST_PATH.to_string());
+    let path = Path::new(&path);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.worker{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}.worker{}", stem, index),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
@@ -2,11 +2,24 @@
     use std::io::Write;
     use std::time::Instant;
 
+    use crate::utils::progress::{self, Progress};
+
     pub fn mandelbrot(
         width: usize,
         height: usize,
         max_iter: u32,
         dump_filename: Option<&str>,
+    ) -> (Vec<Vec<u32>>, u128) {
+        mandelbrot_with_progress(width, height, max_iter, dump_filename, &progress::ignore)
+    }
+
+    /// Like `mandelbrot`, reporting each finished row.
+    pub fn mandelbrot_with_progress(
+        width: usize,
+        height: usize,
+        max_iter: u32,
+        dump_filename: Option<&str>,
+        progress: Progress,
     ) -> (Vec<Vec<u32>>, u128) {
         let start = Instant::now();
 
@@ -34,6 +47,7 @@
 
                 data[y][x] = iter;
             }
+            progress((y + 1) as f32 / height as f32);
         }
 
         if let Some(filename) = dump_filename {
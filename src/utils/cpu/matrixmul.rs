@@ -1,6 +1,8 @@
 use sha2::{Digest, Sha256};
 use std::time::Instant;
 
+use crate::utils::progress::{self, Progress};
+
 /// Prime modulus: keeps values bounded & fully deterministic across platforms.
 const MOD: u64 = 1_000_000_007;
 
@@ -27,7 +29,7 @@ fn gen_matrix(n: usize, seed: u64) -> Vec<u64> {
 }
 
 /// Multiply C = A × B (mod MOD), A,B row-major N×N. Cache-friendly using Bᵀ.
-fn matmul_mod(a: &[u64], b: &[u64], n: usize) -> Vec<u64> {
+fn matmul_mod(a: &[u64], b: &[u64], n: usize, progress: Progress) -> Vec<u64> {
     let mut c = vec![0u64; n * n];
     // Precompute Bᵀ to improve locality
     let mut bt = vec![0u64; n * n];
@@ -47,6 +49,7 @@ fn matmul_mod(a: &[u64], b: &[u64], n: usize) -> Vec<u64> {
             }
             c[i * n + j] = (s % (MOD as u128)) as u64;
         }
+        progress((i + 1) as f32 / n as f32);
     }
     c
 }
@@ -61,6 +64,11 @@ fn to_be_bytes_u64_row_major(m: &[u64]) -> Vec<u8> {
 }
 
 pub fn matrixmul(size: usize, seed: u64) -> (String, u128) {
+    matrixmul_with_progress(size, seed, &progress::ignore)
+}
+
+/// Like `matrixmul`, reporting each finished row of the product.
+pub fn matrixmul_with_progress(size: usize, seed: u64, progress: Progress) -> (String, u128) {
     let start = Instant::now();
 
     // 1) matrices
@@ -68,7 +76,7 @@ pub fn matrixmul(size: usize, seed: u64) -> (String, u128) {
     let b = gen_matrix(size, seed ^ 0xDEADBEEFCAFEBABE);
 
     // 2) multiply (mod MOD)
-    let c = matmul_mod(&a, &b, size);
+    let c = matmul_mod(&a, &b, size, progress);
 
     // 3) hash
     let bytes = to_be_bytes_u64_row_major(&c);
@@ -1,13 +1,20 @@
 use std::env;
 use std::fs::{self, File, remove_file, metadata};
-use std::io::{Write, Result, ErrorKind};
+use std::io::{Read, Write, Result, ErrorKind};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-/// Resolve a safe, absolute path using the FILE_STORAGE_PATH environment variable.
+use sha2::{Digest, Sha256};
+
+/// The storage directory from the FILE_STORAGE_PATH environment variable.
 /// Falls back to "./data/" if the variable is missing.
-fn resolve_path(filename: &str) -> PathBuf {
-    let base = env::var("FILE_STORAGE_PATH").unwrap_or_else(|_| "./data/".to_string());
-    let mut path = PathBuf::from(base);
+pub fn storage_dir() -> PathBuf {
+    PathBuf::from(env::var("FILE_STORAGE_PATH").unwrap_or_else(|_| "./data/".to_string()))
+}
+
+/// Resolve a safe path for `filename` inside `dir`.
+fn resolve_path_in(dir: &Path, filename: &str) -> PathBuf {
+    let mut path = dir.to_path_buf();
 
     // Prevent directory traversal (e.g., "../../etc/passwd")
     let clean_name = Path::new(filename)
@@ -20,7 +27,11 @@ fn resolve_path(filename: &str) -> PathBuf {
 }
 
 pub fn create_file(name: &str, content: &str, repeat: usize) -> Result<()> {
-    let path = resolve_path(name);
+    create_file_in(&storage_dir(), name, content, repeat)
+}
+
+pub fn create_file_in(dir: &Path, name: &str, content: &str, repeat: usize) -> Result<()> {
+    let path = resolve_path_in(dir, name);
 
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?; // ensure directory exists
@@ -35,7 +46,11 @@ pub fn create_file(name: &str, content: &str, repeat: usize) -> Result<()> {
 }
 
 pub fn delete_file(name: &str) -> Result<String> {
-    let path = resolve_path(name);
+    delete_file_in(&storage_dir(), name)
+}
+
+pub fn delete_file_in(dir: &Path, name: &str) -> Result<String> {
+    let path = resolve_path_in(dir, name);
 
     match metadata(&path) {
         Ok(_) => match remove_file(&path) {
@@ -49,37 +64,217 @@ pub fn delete_file(name: &str) -> Result<String> {
     }
 }
 
+/// Whether `name` names a visible file directly inside the storage
+/// directory: no path separators, and not hidden like in-progress uploads.
+pub fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && Path::new(name).file_name() == Some(std::ffi::OsStr::new(name))
+}
+
+/// Opens a file stored in `dir` for reading. Hidden names (including
+/// in-progress uploads) and directories are reported as `NotFound`.
+pub fn open_file(dir: &Path, name: &str) -> Result<(File, fs::Metadata)> {
+    if !is_plain_name(name) {
+        return Err(std::io::Error::new(ErrorKind::NotFound, format!("File '{}' not found", name)));
+    }
+
+    let file = File::open(resolve_path_in(dir, name))?;
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(std::io::Error::new(ErrorKind::NotFound, format!("File '{}' not found", name)));
+    }
+    Ok((file, meta))
+}
+
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+/// What `store_file` wrote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    /// The file name `store_file` kept from the name it was given.
+    pub name: String,
+    pub size: u64,
+    /// Hex SHA-256 of the content.
+    pub sha256: String,
+    /// No file of that name existed; otherwise it was replaced.
+    pub created: bool,
+}
+
+/// Streams `content` into `name` inside `dir`. Only the final path
+/// component of `name` is kept. The data goes to a temporary file first, so
+/// readers never see a half-written upload.
+pub fn store_file<R: Read>(dir: &Path, name: &str, content: R) -> Result<StoredFile> {
+    stage_file(dir, name, content)?.commit()
+}
+
+/// An upload written to a temporary file next to its destination but not
+/// stored under its name yet. Dropping it discards the data.
+pub struct StagedFile {
+    tmp_path: PathBuf,
+    path: PathBuf,
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+/// The first half of `store_file`: writes `content` without making it
+/// visible. Uploads that must succeed or fail together are all staged
+/// before any is committed.
+pub fn stage_file<R: Read>(dir: &Path, name: &str, mut content: R) -> Result<StagedFile> {
+    let stored_name = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if stored_name.is_empty() || stored_name.starts_with('.') {
+        return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("Invalid file name '{}'", name)));
+    }
+    let path = resolve_path_in(dir, &stored_name);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Unique per upload, so concurrent uploads of one name cannot share it
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}-{}.upload",
+        stored_name,
+        std::process::id(),
+        NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&tmp_path)?;
+    let mut staged = StagedFile { tmp_path, path, name: stored_name, size: 0, sha256: String::new() };
+
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = content.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+        staged.size += n as u64;
+    }
+    file.sync_all()?;
+
+    staged.sha256 = format!("{:x}", hasher.finalize());
+    Ok(staged)
+}
+
+impl StagedFile {
+    /// Stores the upload under its name, replacing any file there.
+    pub fn commit(self) -> Result<StoredFile> {
+        // Linking fails if the name is taken, which tells a new file from a
+        // replaced one without a separate (racy) existence check
+        let created = match fs::hard_link(&self.tmp_path, &self.path) {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                fs::rename(&self.tmp_path, &self.path)?;
+                false
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(StoredFile { name: self.name.clone(), size: self.size, sha256: self.sha256.clone(), created })
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        // Gone already once renamed into place
+        let _ = remove_file(&self.tmp_path);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs;
 
-    fn setup_env() {
-        env::set_var("FILE_STORAGE_PATH", "./test_data/");
-        fs::create_dir_all("./test_data/").unwrap();
+    /// A directory of the test's own, so tests running in parallel never
+    /// share files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("file-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
     }
 
     #[test]
     fn test_create_and_delete_file() {
-        setup_env();
+        let dir = test_dir("create");
 
         let name = "test_output.txt";
-        create_file(name, "Hello", 2).unwrap();
+        create_file_in(&dir, name, "Hello", 2).unwrap();
 
-        let path = resolve_path(name);
+        let path = resolve_path_in(&dir, name);
         let content = fs::read_to_string(&path).unwrap();
         assert!(content.contains("Hello"));
 
-        let res = delete_file(name).unwrap();
+        let res = delete_file_in(&dir, name).unwrap();
         assert!(res.contains("deleted successfully") || res.contains("does not exist"));
         assert!(!fs::metadata(&path).is_ok());
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
     fn test_delete_nonexistent_file() {
-        setup_env();
+        let dir = test_dir("missing");
         let name = "nonexistent.txt";
-        let result = delete_file(name).unwrap();
+        let result = delete_file_in(&dir, name).unwrap();
         assert!(result.contains("does not exist"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_store_file_sanitizes_name_and_hashes() {
+        let dir = test_dir("store");
+
+        let stored = store_file(&dir, "../../upload_test.txt", "abc".as_bytes()).unwrap();
+        assert_eq!(stored.name, "upload_test.txt");
+        assert_eq!(stored.size, 3);
+        assert_eq!(stored.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert!(stored.created);
+        assert_eq!(fs::read_to_string(resolve_path_in(&dir, &stored.name)).unwrap(), "abc");
+        assert!(!store_file(&dir, "upload_test.txt", "abcd".as_bytes()).unwrap().created);
+
+        assert!(store_file(&dir, "..", "x".as_bytes()).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn staged_files_stay_hidden_until_committed() {
+        let dir = test_dir("stage");
+
+        let staged = stage_file(&dir, "staged.txt", "abc".as_bytes()).unwrap();
+        assert!(open_file(&dir, "staged.txt").is_err());
+        assert!(staged.commit().unwrap().created);
+        assert!(open_file(&dir, "staged.txt").is_ok());
+
+        drop(stage_file(&dir, "dropped.txt", "abc".as_bytes()).unwrap());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn concurrent_stores_of_one_name_do_not_collide() {
+        let dir = test_dir("concurrent");
+
+        let writers: Vec<_> = (0..4u8)
+            .map(|i| {
+                let dir = dir.clone();
+                std::thread::spawn(move || store_file(&dir, "concurrent.txt", &[b'a' + i; 64 * 1024][..]))
+            })
+            .collect();
+        for writer in writers {
+            assert_eq!(writer.join().unwrap().unwrap().size, 64 * 1024);
+        }
+
+        // One of the uploads, whole, and no temporary files left behind
+        let content = fs::read(resolve_path_in(&dir, "concurrent.txt")).unwrap();
+        assert!(content.iter().all(|b| *b == content[0]));
+        let leftovers = fs::read_dir(&dir).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with(".concurrent.txt"))
+            .count();
+        assert_eq!(leftovers, 0);
+        let _ = fs::remove_dir_all(&dir);
     }
 }
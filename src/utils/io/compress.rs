@@ -8,6 +8,8 @@ use flate2::write::GzEncoder;
 use flate2::Compression;
 use xz2::write::XzEncoder;
 
+use crate::utils::progress::{self, Progress, ProgressReader};
+
 #[derive(Debug)]
 pub struct CompressResult {
     pub output_file: PathBuf,
@@ -16,6 +18,11 @@ pub struct CompressResult {
 }
 
 pub fn compress_file(name: &str, codec: &str) -> io::Result<CompressResult> {
+    compress_file_with_progress(name, codec, &progress::ignore)
+}
+
+/// Like `compress_file`, reporting the share of the input read.
+pub fn compress_file_with_progress(name: &str, codec: &str, progress: Progress) -> io::Result<CompressResult> {
     let base = env::var("FILE_STORAGE_PATH").unwrap_or_else(|_| "./data/files".to_string());
     let input_path = PathBuf::from(&base).join(name);
 
@@ -35,7 +42,8 @@ pub fn compress_file(name: &str, codec: &str) -> io::Result<CompressResult> {
 
     let start = Instant::now();
     let infile = File::open(&input_path)?;
-    let mut reader = BufReader::new(infile);
+    let size = infile.metadata()?.len();
+    let mut reader = BufReader::new(ProgressReader::new(infile, size, progress));
     let outfile = File::create(&out_path)?;
     let mut writer = BufWriter::new(outfile);
 
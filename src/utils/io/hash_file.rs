@@ -6,6 +6,8 @@ use std::time::Instant;
 
 use sha2::{Sha256, Digest};
 
+use crate::utils::progress::{self, Progress, ProgressReader};
+
 #[derive(Debug)]
 pub struct HashResult {
     pub hash_hex: String,
@@ -14,6 +16,11 @@ pub struct HashResult {
 }
 
 pub fn hash_file(name: &str, algo: &str) -> io::Result<HashResult> {
+    hash_file_with_progress(name, algo, &progress::ignore)
+}
+
+/// Like `hash_file`, reporting the share of the file read.
+pub fn hash_file_with_progress(name: &str, algo: &str, progress: Progress) -> io::Result<HashResult> {
     let base = env::var("FILE_STORAGE_PATH").unwrap_or_else(|_| "./data/files".to_string());
     let path = PathBuf::from(&base).join(name);
 
@@ -22,7 +29,9 @@ pub fn hash_file(name: &str, algo: &str) -> io::Result<HashResult> {
     }
 
     let start = Instant::now();
-    let mut file = BufReader::new(File::open(&path)?);
+    let file = File::open(&path)?;
+    let size = file.metadata()?.len();
+    let mut file = BufReader::new(ProgressReader::new(file, size, progress));
     let mut buffer = [0u8; 8192];
 
     match algo {
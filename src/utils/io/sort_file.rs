@@ -4,12 +4,22 @@ use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path::PathBuf;
 use std::time::Instant;
 
+use crate::utils::progress::{self, Progress, ProgressReader};
+
 pub fn sort_file(name: &str, algo: &str) -> io::Result<(PathBuf, usize, u128)> {
+    sort_file_with_progress(name, algo, &progress::ignore)
+}
+
+/// Like `sort_file`, reporting progress: reading counts for the first half,
+/// sorting up to three quarters and writing for the rest.
+pub fn sort_file_with_progress(name: &str, algo: &str, progress: Progress) -> io::Result<(PathBuf, usize, u128)> {
     let base = env::var("FILE_STORAGE_PATH").unwrap_or_else(|_| "./data/files".to_string());
     let path = PathBuf::from(base).join(name);
 
     let file = File::open(&path)?;
-    let reader = BufReader::new(file);
+    let size = file.metadata()?.len();
+    let reading = |p: f32| progress(p * 0.5);
+    let reader = BufReader::new(ProgressReader::new(file, size, &reading));
 
     let mut numbers: Vec<i64> = Vec::new();
     for line in reader.lines() {
@@ -26,6 +36,7 @@ pub fn sort_file(name: &str, algo: &str) -> io::Result<(PathBuf, usize, u128)> {
         "quick" => quick_sort(&mut numbers),
         _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unknown algorithm")),
     }
+    progress(0.75);
 
     let elapsed = start.elapsed().as_millis();
 
@@ -41,8 +52,11 @@ pub fn sort_file(name: &str, algo: &str) -> io::Result<(PathBuf, usize, u128)> {
         .write(true)
         .open(&out_path)?;
     let mut writer = BufWriter::new(out_file);
-    for n in &numbers {
+    for (i, n) in numbers.iter().enumerate() {
         writeln!(writer, "{}", n)?;
+        if i % 4096 == 0 {
+            progress(0.75 + 0.25 * i as f32 / numbers.len() as f32);
+        }
     }
     writer.flush()?;
 
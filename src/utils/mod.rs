@@ -6,6 +6,8 @@ pub mod time;
 pub mod hash;
 pub mod commands;
 pub mod timeout;
+pub mod signal;
+pub mod progress;
 
 // cpu intensive utilities
 pub mod cpu {
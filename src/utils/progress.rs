@@ -0,0 +1,51 @@
+use std::io::{self, Read};
+
+/// Receives how far a task has come, from 0.0 to 1.0.
+pub type Progress<'a> = &'a dyn Fn(f32);
+
+/// For callers that do not follow progress.
+pub fn ignore(_: f32) {}
+
+/// Reports the share of `total` bytes read so far.
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    read: u64,
+    total: u64,
+    progress: Progress<'a>,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, total: u64, progress: Progress<'a>) -> Self {
+        Self { inner, read: 0, total, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.total > 0 {
+            (self.progress)(self.read as f32 / self.total as f32);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    #[test]
+    fn reports_the_share_read() {
+        let seen = Cell::new(0.0);
+        let report = |p: f32| seen.set(p);
+        let mut reader = ProgressReader::new(Cursor::new(vec![0u8; 100]), 100, &report);
+
+        reader.read_exact(&mut [0u8; 25]).unwrap();
+        assert_eq!(seen.get(), 0.25);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(seen.get(), 1.0);
+    }
+}
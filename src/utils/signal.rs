@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use libc::c_int;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static LAST_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static REOPEN_LOGS: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown_signal(sig: c_int) {
+    // A second signal while draining means the operator wants out now
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(128 + sig) };
+    }
+    LAST_SIGNAL.store(sig, Ordering::SeqCst);
+}
+
+/// Installs SIGTERM/SIGINT handlers that request a graceful shutdown.
+/// The handler only flips an atomic flag; the accept loops, connection
+/// threads and worker pools poll it through `shutdown_requested`.
+pub fn install_shutdown_handlers() {
+    for sig in [libc::SIGTERM, libc::SIGINT] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_shutdown_signal as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(sig, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+extern "C" fn on_reopen_signal(_sig: c_int) {
+    REOPEN_LOGS.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGHUP handler asking log files to be reopened (after
+/// logrotate moved them). Writers check `take_reopen_request`.
+pub fn install_reopen_handler() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_reopen_signal as *const () as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut());
+    }
+}
+
+/// Returns true once per SIGHUP received.
+pub fn take_reopen_request() -> bool {
+    REOPEN_LOGS.swap(false, Ordering::SeqCst)
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Starts a shutdown without a signal (e.g. on a fatal error).
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// The signal that triggered the shutdown, if any.
+pub fn received_signal() -> Option<i32> {
+    match LAST_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
@@ -9,6 +9,99 @@ pub fn timestamp() -> String {
     }
 }
 
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats `time` as an IMF-fixdate (RFC 9110), e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Formats `time` for the Common Log Format, e.g.
+/// `[10/Oct/2000:13:55:36 +0000]` (always UTC).
+pub fn clf_date(time: SystemTime) -> String {
+    let (year, month, day, h, m, s) = utc_parts(time);
+    format!("[{:02}/{}/{}:{:02}:{:02}:{:02} +0000]", day, MONTHS[(month - 1) as usize], year, h, m, s)
+}
+
+/// Formats `time` as RFC 3339 in UTC, e.g. `2000-10-10T13:55:36Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, h, m, s) = utc_parts(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, h, m, s)
+}
+
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+/// Parses an IMF-fixdate. The obsolete RFC 850 and asctime forms are not
+/// accepted; callers treat an unparsable date as absent.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let (_weekday, rest) = value.trim().split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    if parts.len() != 5 || parts[4] != "GMT" {
+        return None;
+    }
+
+    let day: u32 = parts[0].parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == parts[1])? as u32 + 1;
+    let year: i64 = parts[2].parse().ok()?;
+
+    let hms: Vec<u64> = parts[3].split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    if hms.len() != 3 || day == 0 || day > 31 || hms[0] > 23 || hms[1] > 59 || hms[2] > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    let secs = days as u64 * 86_400 + hms[0] * 3600 + hms[1] * 60 + hms[2];
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Gregorian calendar conversions, after Howard Hinnant's date algorithms.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
 pub fn sleep(seconds: u64) {
     thread::sleep(Duration::from_secs(seconds));
 }
@@ -29,6 +122,23 @@ mod tests {
         assert!(ts.parse::<u64>().is_ok());
     }
 
+    #[test]
+    fn test_http_date_roundtrip() {
+        let t = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(t));
+
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(http_date(leap), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(parse_http_date(&http_date(leap)), Some(leap));
+
+        assert_eq!(clf_date(t), "[06/Nov/1994:08:49:37 +0000]");
+        assert_eq!(rfc3339(t), "1994-11-06T08:49:37Z");
+
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("garbage"), None);
+    }
+
     #[test]
     fn test_simulate() {
         let msg = simulate(1, "demo");
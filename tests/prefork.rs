@@ -0,0 +1,64 @@
+//! Forks real worker processes, so it runs in a test binary of its own
+//! instead of next to the threaded unit tests.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use HTTP_Server::{
+    http::{
+        prefork::{self, PreforkConfig},
+        server::ServerConfig,
+    },
+    utils::signal,
+};
+
+#[test]
+fn restarts_workers_and_forwards_sigterm() {
+    let dir = std::env::temp_dir().join(format!("prefork-test-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let starts = dir.join("starts");
+    let crashed = dir.join("crashed");
+
+    // Inherited by the workers, which then drain on the forwarded SIGTERM
+    signal::install_shutdown_handlers();
+
+    let stopper = {
+        let starts = starts.clone();
+        thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while fs::read_to_string(&starts).unwrap_or_default().lines().count() < 3 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(20));
+            }
+            signal::request_shutdown();
+        })
+    };
+
+    let cfg = ServerConfig { bind_addr: String::new(), socket_activation: false, ..ServerConfig::default() };
+    let prefork_cfg = PreforkConfig { workers: 2, reuse_port: false, shutdown_timeout: Duration::from_secs(5) };
+    let worst = prefork::run(&cfg, &prefork_cfg, |index, _, _| {
+        let mut log = OpenOptions::new().create(true).append(true).open(&starts).unwrap();
+        writeln!(log, "{}", index).unwrap();
+        // The first worker 0 dies at once; every later one runs until told to stop
+        if index == 0 && OpenOptions::new().write(true).create_new(true).open(&crashed).is_ok() {
+            return 3;
+        }
+        while !signal::shutdown_requested() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        0
+    })
+    .unwrap();
+    stopper.join().unwrap();
+
+    let mut started: Vec<String> = fs::read_to_string(&starts).unwrap().lines().map(String::from).collect();
+    started.sort();
+    assert_eq!(started, ["0", "0", "1"]);
+    // Both running workers got SIGTERM and exited cleanly, not by SIGKILL
+    assert_eq!(worst, 0);
+    let _ = fs::remove_dir_all(&dir);
+}